@@ -0,0 +1,862 @@
+//! Assembler for the instruction syntax documented in the README.
+//!
+//! One instruction per line, mnemonic first and operands separated by
+//! whitespace or commas. Everything after a `;` is a comment. A line may
+//! start with one or more `name:` labels, which can then be used anywhere a
+//! constant is expected and resolve to the address of the instruction that
+//! follows them.
+//!
+//! ```text
+//! ld 0x10 al      ; constant into a register
+//! ld [200] b      ; memory into a register
+//! loop:
+//!     sum a b
+//!     shr 2 b
+//!     jmp loop
+//! ```
+
+#![allow(dead_code)]
+
+use crate::cpu::*;
+use crate::encoding;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    OperandCount {
+        expected: usize,
+        found: usize,
+    },
+    InvalidOperand {
+        expected: &'static str,
+        found: String,
+    },
+    InvalidNumber(String),
+    NumberOutOfRange(String),
+    UnterminatedMemory,
+    InvalidLabel(String),
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+    LabelOutOfRange(String),
+}
+
+/// Assembly error, `line` and `col` are 1 based.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub col: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmErrorKind::UnknownMnemonic(m) => write!(f, "unknown instruction `{}`", m),
+            AsmErrorKind::OperandCount { expected, found } => {
+                write!(f, "expected {} operand(s), found {}", expected, found)
+            }
+            AsmErrorKind::InvalidOperand { expected, found } => {
+                write!(f, "expected {}, found `{}`", expected, found)
+            }
+            AsmErrorKind::InvalidNumber(n) => write!(f, "invalid number `{}`", n),
+            AsmErrorKind::NumberOutOfRange(n) => write!(f, "number `{}` doesn't fit in 16 bits", n),
+            AsmErrorKind::UnterminatedMemory => write!(f, "missing `]`"),
+            AsmErrorKind::InvalidLabel(l) => write!(f, "invalid label name `{}`", l),
+            AsmErrorKind::DuplicateLabel(l) => write!(f, "label `{}` is already defined", l),
+            AsmErrorKind::UndefinedLabel(l) => write!(f, "undefined label `{}`", l),
+            AsmErrorKind::LabelOutOfRange(l) => write!(f, "label `{}` is past address 0xffff", l),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.kind)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Copy, Clone, Debug)]
+struct Token<'a> {
+    text: &'a str,
+    col: usize,
+}
+
+/// Splits a line into tokens, dropping comments. A `[...]` memory operand is
+/// always a single token, even if it contains whitespace.
+fn tokenize(line: &str, line_no: usize) -> Result<Vec<Token<'_>>, AsmError> {
+    let line = match line.find(';') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() || c == ',' {
+            chars.next();
+            continue;
+        }
+
+        let mut end = line.len();
+        if c == '[' {
+            match line[start..].find(']') {
+                Some(i) => end = start + i + 1,
+                None => {
+                    return Err(AsmError {
+                        line: line_no,
+                        col: start + 1,
+                        kind: AsmErrorKind::UnterminatedMemory,
+                    })
+                }
+            }
+            while chars.peek().is_some_and(|&(i, _)| i < end) {
+                chars.next();
+            }
+        } else {
+            while let Some(&(i, c)) = chars.peek() {
+                if c.is_whitespace() || c == ',' {
+                    end = i;
+                    break;
+                }
+                chars.next();
+            }
+        }
+
+        tokens.push(Token {
+            text: &line[start..end],
+            col: start + 1,
+        });
+    }
+
+    Ok(tokens)
+}
+
+pub fn parse_reg(s: &str) -> Option<Reg> {
+    let reg = match s.to_ascii_lowercase().as_str() {
+        "a" => Reg::A,
+        "ah" => Reg::AH,
+        "al" => Reg::AL,
+        "b" => Reg::B,
+        "bh" => Reg::BH,
+        "bl" => Reg::BL,
+        "c" => Reg::C,
+        "ch" => Reg::CH,
+        "cl" => Reg::CL,
+        "d" => Reg::D,
+        "dh" => Reg::DH,
+        "dl" => Reg::DL,
+        _ => return None,
+    };
+
+    Some(reg)
+}
+
+pub fn parse_seg(s: &str) -> Option<SegReg> {
+    let seg = match s.to_ascii_lowercase().as_str() {
+        "cs" => SegReg::CS,
+        "ds" => SegReg::DS,
+        "ss" => SegReg::SS,
+        "es" => SegReg::ES,
+        _ => return None,
+    };
+
+    Some(seg)
+}
+
+/// Parses a decimal, `0x` hex or `0b` binary number, optionally negative.
+/// Anything from -32768 to 65535 is accepted, values above `i16::MAX` are
+/// stored as their 16 bit pattern.
+pub fn parse_number(s: &str) -> Result<i16, AsmErrorKind> {
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+
+    let lower = digits.to_ascii_lowercase();
+    let (radix, digits) = if let Some(hex) = lower.strip_prefix("0x") {
+        (16, hex)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        (2, bin)
+    } else {
+        (10, lower.as_str())
+    };
+
+    let digits = digits.replace('_', "");
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(AsmErrorKind::InvalidNumber(s.to_string()));
+    }
+
+    let val = i64::from_str_radix(&digits, radix)
+        .map_err(|_| AsmErrorKind::NumberOutOfRange(s.to_string()))?;
+    let val = if neg { -val } else { val };
+
+    if val < i16::MIN as i64 || val > u16::MAX as i64 {
+        return Err(AsmErrorKind::NumberOutOfRange(s.to_string()));
+    }
+
+    Ok(val as i16)
+}
+
+fn is_label_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && parse_reg(s).is_none()
+        && parse_seg(s).is_none()
+}
+
+struct Line<'a> {
+    no: usize,
+    mnemonic: Token<'a>,
+    operands: Vec<Token<'a>>,
+    // `None` while the labels are still being collected, every label then
+    // resolves to 0 since only the instruction length matters.
+    labels: Option<&'a HashMap<String, u16>>,
+}
+
+impl Line<'_> {
+    fn err(&self, tok: Token, kind: AsmErrorKind) -> AsmError {
+        AsmError {
+            line: self.no,
+            col: tok.col,
+            kind,
+        }
+    }
+
+    fn expect_operands(&self, n: usize) -> Result<(), AsmError> {
+        if self.operands.len() != n {
+            let tok = self.operands.get(n).copied().unwrap_or(self.mnemonic);
+            return Err(self.err(
+                tok,
+                AsmErrorKind::OperandCount {
+                    expected: n,
+                    found: self.operands.len(),
+                },
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn invalid(&self, tok: Token, expected: &'static str) -> AsmError {
+        self.err(
+            tok,
+            AsmErrorKind::InvalidOperand {
+                expected,
+                found: tok.text.to_string(),
+            },
+        )
+    }
+
+    fn seg(&self, i: usize) -> Result<SegReg, AsmError> {
+        let tok = self.operands[i];
+        parse_seg(tok.text).ok_or_else(|| self.invalid(tok, "a segment register"))
+    }
+
+    fn reg(&self, i: usize) -> Result<Reg, AsmError> {
+        let tok = self.operands[i];
+        parse_reg(tok.text).ok_or_else(|| self.invalid(tok, "a register"))
+    }
+
+    fn number(&self, tok: Token, text: &str) -> Result<i16, AsmError> {
+        if !is_label_name(text) {
+            return parse_number(text).map_err(|kind| self.err(tok, kind));
+        }
+
+        match self.labels {
+            None => Ok(0),
+            Some(labels) => labels
+                .get(text)
+                .map(|&a| a as i16)
+                .ok_or_else(|| self.err(tok, AsmErrorKind::UndefinedLabel(text.to_string()))),
+        }
+    }
+
+    fn memory(&self, tok: Token) -> Option<Result<u16, AsmError>> {
+        let inner = tok.text.strip_prefix('[')?.strip_suffix(']')?.trim();
+        Some(self.number(tok, inner).map(|a| a as u16))
+    }
+
+    fn inpt(&self, i: usize) -> Result<Inpt, AsmError> {
+        let tok = self.operands[i];
+        if let Some(r) = parse_reg(tok.text) {
+            return Ok(Inpt::Register(r));
+        }
+        if tok.text.starts_with('[') {
+            return Err(self.invalid(tok, "a register or constant"));
+        }
+
+        self.number(tok, tok.text).map(Inpt::Const)
+    }
+
+    fn generous(&self, i: usize) -> Result<GenerousInpt, AsmError> {
+        let tok = self.operands[i];
+        if let Some(addr) = self.memory(tok) {
+            return addr.map(GenerousInpt::Memory);
+        }
+
+        Ok(match self.inpt(i)? {
+            Inpt::Const(c) => GenerousInpt::Const(c),
+            Inpt::Register(r) => GenerousInpt::Register(r),
+        })
+    }
+
+    fn dest(&self, i: usize) -> Result<Dest, AsmError> {
+        let tok = self.operands[i];
+        if let Some(addr) = self.memory(tok) {
+            return addr.map(Dest::Memory);
+        }
+
+        parse_reg(tok.text)
+            .map(Dest::Register)
+            .ok_or_else(|| self.invalid(tok, "a register or memory address"))
+    }
+
+    fn two_regs(&self, f: fn(Reg, Reg) -> Instruction) -> Result<Instruction, AsmError> {
+        self.expect_operands(2)?;
+        Ok(f(self.reg(0)?, self.reg(1)?))
+    }
+
+    fn three_regs(&self, f: fn(Reg, Reg, Reg) -> Instruction) -> Result<Instruction, AsmError> {
+        self.expect_operands(3)?;
+        Ok(f(self.reg(0)?, self.reg(1)?, self.reg(2)?))
+    }
+
+    fn one_inpt(&self, f: fn(Inpt) -> Instruction) -> Result<Instruction, AsmError> {
+        self.expect_operands(1)?;
+        Ok(f(self.inpt(0)?))
+    }
+
+    fn instruction(&self) -> Result<Instruction, AsmError> {
+        let instr = match self.mnemonic.text.to_ascii_lowercase().as_str() {
+            "ld" => {
+                self.expect_operands(2)?;
+                if parse_seg(self.operands[0].text).is_some() {
+                    Instruction::StSeg(self.seg(0)?, self.reg(1)?)
+                } else if parse_seg(self.operands[1].text).is_some() {
+                    Instruction::LdSeg(self.inpt(0)?, self.seg(1)?)
+                } else {
+                    Instruction::Ld(self.generous(0)?, self.dest(1)?)
+                }
+            }
+            "sum" => self.two_regs(Instruction::Sum)?,
+            "sub" => self.two_regs(Instruction::Sub)?,
+            "mul" => self.two_regs(Instruction::Mul)?,
+            "div" => self.two_regs(Instruction::Div)?,
+            "and" => self.two_regs(Instruction::And)?,
+            "or" => self.two_regs(Instruction::Or)?,
+            "not" => {
+                self.expect_operands(1)?;
+                Instruction::Not(self.reg(0)?)
+            }
+            "xor" => self.two_regs(Instruction::Xor)?,
+            "sext" => self.two_regs(Instruction::Sext)?,
+            "zext" => self.two_regs(Instruction::Zext)?,
+            "shr" => {
+                self.expect_operands(2)?;
+                Instruction::Shr(self.inpt(0)?, self.reg(1)?)
+            }
+            "shl" => {
+                self.expect_operands(2)?;
+                Instruction::Shl(self.inpt(0)?, self.reg(1)?)
+            }
+            "cmp" => self.two_regs(Instruction::Cmp)?,
+            "jmp" => self.one_inpt(Instruction::Jmp)?,
+            "jeq" => self.one_inpt(Instruction::Jeq)?,
+            "jne" => self.one_inpt(Instruction::Jne)?,
+            "jgt" => self.one_inpt(Instruction::Jgt)?,
+            "jlt" => self.one_inpt(Instruction::Jlt)?,
+            "push" => self.one_inpt(Instruction::Push)?,
+            "pop" => {
+                self.expect_operands(1)?;
+                Instruction::Pop(self.reg(0)?)
+            }
+            "call" => self.one_inpt(Instruction::Call)?,
+            "ret" => {
+                self.expect_operands(0)?;
+                Instruction::Ret
+            }
+            "pxl" => self.two_regs(Instruction::Pxl)?,
+            "rect" => self.three_regs(Instruction::Rect)?,
+            "line" => self.three_regs(Instruction::Line)?,
+            "cls" => {
+                self.expect_operands(1)?;
+                Instruction::Cls(self.reg(0)?)
+            }
+            "hlt" => {
+                self.expect_operands(0)?;
+                Instruction::Hlt
+            }
+            "syscall" => {
+                self.expect_operands(0)?;
+                Instruction::Syscall
+            }
+            "seg" => {
+                self.expect_operands(1)?;
+                Instruction::Seg(self.seg(0)?)
+            }
+            "jmpf" => {
+                self.expect_operands(2)?;
+                Instruction::JmpFar(self.inpt(0)?, self.inpt(1)?)
+            }
+            "callf" => {
+                self.expect_operands(2)?;
+                Instruction::CallFar(self.inpt(0)?, self.inpt(1)?)
+            }
+            "retf" => {
+                self.expect_operands(0)?;
+                Instruction::RetFar
+            }
+            "cli" => {
+                self.expect_operands(0)?;
+                Instruction::Cli
+            }
+            "sti" => {
+                self.expect_operands(0)?;
+                Instruction::Sti
+            }
+            "iret" => {
+                self.expect_operands(0)?;
+                Instruction::Iret
+            }
+            "pushf" => {
+                self.expect_operands(0)?;
+                Instruction::Pushf
+            }
+            "popf" => {
+                self.expect_operands(0)?;
+                Instruction::Popf
+            }
+            _ => {
+                return Err(self.err(
+                    self.mnemonic,
+                    AsmErrorKind::UnknownMnemonic(self.mnemonic.text.to_string()),
+                ))
+            }
+        };
+
+        Ok(instr)
+    }
+}
+
+/// Parsed program along with the address of every label, assuming the
+/// program is loaded at address 0.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub labels: HashMap<String, u16>,
+}
+
+struct SourceLine<'a> {
+    no: usize,
+    labels: Vec<Token<'a>>,
+    tokens: Vec<Token<'a>>,
+}
+
+impl<'a> SourceLine<'a> {
+    fn split(text: &'a str, no: usize) -> Result<Self, AsmError> {
+        let mut tokens = tokenize(text, no)?;
+        let mut labels = Vec::new();
+
+        while let Some(name) = tokens.first().and_then(|t| t.text.strip_suffix(':')) {
+            let tok = Token {
+                text: name,
+                col: tokens[0].col,
+            };
+            if !is_label_name(name) {
+                return Err(AsmError {
+                    line: no,
+                    col: tok.col,
+                    kind: AsmErrorKind::InvalidLabel(name.to_string()),
+                });
+            }
+
+            labels.push(tok);
+            tokens.remove(0);
+        }
+
+        Ok(SourceLine { no, labels, tokens })
+    }
+
+    fn instruction(
+        &self,
+        labels: Option<&HashMap<String, u16>>,
+    ) -> Option<Result<Instruction, AsmError>> {
+        let (&mnemonic, operands) = self.tokens.split_first()?;
+        let line = Line {
+            no: self.no,
+            mnemonic,
+            operands: operands.to_vec(),
+            labels,
+        };
+
+        Some(line.instruction())
+    }
+}
+
+/// Parses `src` in two passes: the first one collects the address of every
+/// label, the second one resolves them.
+pub fn parse_program(src: &str) -> Result<Program, AsmError> {
+    let lines = src
+        .lines()
+        .enumerate()
+        .map(|(i, text)| SourceLine::split(text, i + 1))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut labels = HashMap::new();
+    let mut addr: usize = 0;
+    for line in lines.iter() {
+        for label in line.labels.iter() {
+            let Ok(label_addr) = u16::try_from(addr) else {
+                return Err(AsmError {
+                    line: line.no,
+                    col: label.col,
+                    kind: AsmErrorKind::LabelOutOfRange(label.text.to_string()),
+                });
+            };
+            if labels.insert(label.text.to_string(), label_addr).is_some() {
+                return Err(AsmError {
+                    line: line.no,
+                    col: label.col,
+                    kind: AsmErrorKind::DuplicateLabel(label.text.to_string()),
+                });
+            }
+        }
+
+        if let Some(instr) = line.instruction(None) {
+            addr += encoding::encode(&instr?).len();
+        }
+    }
+
+    let instructions = lines
+        .iter()
+        .filter_map(|line| line.instruction(Some(&labels)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Program {
+        instructions,
+        labels,
+    })
+}
+
+pub fn parse(src: &str) -> Result<Vec<Instruction>, AsmError> {
+    Ok(parse_program(src)?.instructions)
+}
+
+/// Parses `src` and encodes it into machine code.
+pub fn assemble(src: &str) -> Result<Vec<u8>, AsmError> {
+    Ok(encoding::encode_all(&parse(src)?))
+}
+
+#[cfg(test)]
+mod asm_tests {
+    use super::*;
+
+    fn err_at(src: &str) -> (usize, usize, AsmErrorKind) {
+        let e = parse(src).unwrap_err();
+        (e.line, e.col, e.kind)
+    }
+
+    #[test]
+    fn parse_every_instruction() {
+        let src = "
+            ld 5 a
+            ld al [0x10]
+            ld [16] bh
+            sum a b
+            sub b c
+            mul c d
+            div d a
+            and ah al
+            or bh bl
+            not ch
+            xor cl dh
+            shr 2 dl
+            shl a b
+            cmp a b
+            jmp 0x20
+            jeq a
+            jne 3
+            jgt b
+            jlt 4
+            push -1
+            pop d
+            call 0x30
+            ret
+            hlt
+            syscall
+            pxl a b
+            rect a b c
+            line d c b
+            cls al
+        ";
+
+        assert_eq!(
+            parse(src).unwrap(),
+            vec![
+                Instruction::Ld(GenerousInpt::Const(5), Dest::Register(Reg::A)),
+                Instruction::Ld(GenerousInpt::Register(Reg::AL), Dest::Memory(0x10)),
+                Instruction::Ld(GenerousInpt::Memory(16), Dest::Register(Reg::BH)),
+                Instruction::Sum(Reg::A, Reg::B),
+                Instruction::Sub(Reg::B, Reg::C),
+                Instruction::Mul(Reg::C, Reg::D),
+                Instruction::Div(Reg::D, Reg::A),
+                Instruction::And(Reg::AH, Reg::AL),
+                Instruction::Or(Reg::BH, Reg::BL),
+                Instruction::Not(Reg::CH),
+                Instruction::Xor(Reg::CL, Reg::DH),
+                Instruction::Shr(Inpt::Const(2), Reg::DL),
+                Instruction::Shl(Inpt::Register(Reg::A), Reg::B),
+                Instruction::Cmp(Reg::A, Reg::B),
+                Instruction::Jmp(Inpt::Const(0x20)),
+                Instruction::Jeq(Inpt::Register(Reg::A)),
+                Instruction::Jne(Inpt::Const(3)),
+                Instruction::Jgt(Inpt::Register(Reg::B)),
+                Instruction::Jlt(Inpt::Const(4)),
+                Instruction::Push(Inpt::Const(-1)),
+                Instruction::Pop(Reg::D),
+                Instruction::Call(Inpt::Const(0x30)),
+                Instruction::Ret,
+                Instruction::Hlt,
+                Instruction::Syscall,
+                Instruction::Pxl(Reg::A, Reg::B),
+                Instruction::Rect(Reg::A, Reg::B, Reg::C),
+                Instruction::Line(Reg::D, Reg::C, Reg::B),
+                Instruction::Cls(Reg::AL),
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("42"), Ok(42));
+        assert_eq!(parse_number("-42"), Ok(-42));
+        assert_eq!(parse_number("0xff"), Ok(255));
+        assert_eq!(parse_number("0XFF"), Ok(255));
+        assert_eq!(parse_number("0b1010"), Ok(10));
+        assert_eq!(parse_number("0b1111_0000"), Ok(0xf0));
+        assert_eq!(parse_number("0xffff"), Ok(-1));
+        assert_eq!(parse_number("-32768"), Ok(i16::MIN));
+        assert_eq!(
+            parse_number("65536"),
+            Err(AsmErrorKind::NumberOutOfRange("65536".into()))
+        );
+        assert_eq!(
+            parse_number("0b102"),
+            Err(AsmErrorKind::InvalidNumber("0b102".into()))
+        );
+        assert_eq!(
+            parse_number("0x"),
+            Err(AsmErrorKind::InvalidNumber("0x".into()))
+        );
+    }
+
+    #[test]
+    fn comments_commas_and_case() {
+        let src = "; a program\n\n  LD 1, A ; load\n\tsum a,b;add\n";
+
+        assert_eq!(
+            parse(src).unwrap(),
+            vec![
+                Instruction::Ld(GenerousInpt::Const(1), Dest::Register(Reg::A)),
+                Instruction::Sum(Reg::A, Reg::B),
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_operand_with_spaces() {
+        assert_eq!(
+            parse("ld [ 0x10 ] a").unwrap(),
+            vec![Instruction::Ld(
+                GenerousInpt::Memory(0x10),
+                Dest::Register(Reg::A)
+            )]
+        );
+    }
+
+    #[test]
+    fn assemble_to_bytes() {
+        let src = "ld 1 a\nhlt";
+        let expected = encoding::encode_all(&[
+            Instruction::Ld(GenerousInpt::Const(1), Dest::Register(Reg::A)),
+            Instruction::Hlt,
+        ]);
+
+        assert_eq!(assemble(src).unwrap(), expected);
+    }
+
+    #[test]
+    fn unknown_mnemonic() {
+        assert_eq!(
+            err_at("ld 1 a\n  mov a b"),
+            (2, 3, AsmErrorKind::UnknownMnemonic("mov".into()))
+        );
+    }
+
+    #[test]
+    fn wrong_operand_count() {
+        assert_eq!(
+            err_at("sum a"),
+            (
+                1,
+                1,
+                AsmErrorKind::OperandCount {
+                    expected: 2,
+                    found: 1
+                }
+            )
+        );
+        assert_eq!(
+            err_at("not a b"),
+            (
+                1,
+                7,
+                AsmErrorKind::OperandCount {
+                    expected: 1,
+                    found: 2
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_operands() {
+        assert_eq!(
+            err_at("sum a 5"),
+            (
+                1,
+                7,
+                AsmErrorKind::InvalidOperand {
+                    expected: "a register",
+                    found: "5".into()
+                }
+            )
+        );
+        assert_eq!(
+            err_at("ld a 5"),
+            (
+                1,
+                6,
+                AsmErrorKind::InvalidOperand {
+                    expected: "a register or memory address",
+                    found: "5".into()
+                }
+            )
+        );
+        assert_eq!(
+            err_at("push [4]"),
+            (
+                1,
+                6,
+                AsmErrorKind::InvalidOperand {
+                    expected: "a register or constant",
+                    found: "[4]".into()
+                }
+            )
+        );
+        assert_eq!(
+            err_at("jmp 0x1g"),
+            (1, 5, AsmErrorKind::InvalidNumber("0x1g".into()))
+        );
+    }
+
+    #[test]
+    fn labels() {
+        let src = "
+            start:  ld 3 a
+                    jmp check
+            loop:   sum b c
+            check:
+                    cmp c a
+                    jne loop
+            end: hlt
+        ";
+        let prog = parse_program(src).unwrap();
+
+        let mut addr = 0;
+        let mut addrs = Vec::new();
+        for instr in prog.instructions.iter() {
+            addrs.push(addr);
+            addr += encoding::encode(instr).len() as u16;
+        }
+
+        assert_eq!(prog.labels["start"], 0);
+        assert_eq!(prog.labels["loop"], addrs[2]);
+        assert_eq!(prog.labels["check"], addrs[3]);
+        assert_eq!(prog.labels["end"], addrs[5]);
+        assert_eq!(
+            prog.instructions[1],
+            Instruction::Jmp(Inpt::Const(addrs[3] as i16))
+        );
+        assert_eq!(
+            prog.instructions[4],
+            Instruction::Jne(Inpt::Const(addrs[2] as i16))
+        );
+    }
+
+    #[test]
+    fn label_as_constant() {
+        assert_eq!(
+            parse("ld data a\npush data\ndata: hlt").unwrap(),
+            vec![
+                Instruction::Ld(GenerousInpt::Const(10), Dest::Register(Reg::A)),
+                Instruction::Push(Inpt::Const(10)),
+                Instruction::Hlt,
+            ]
+        );
+    }
+
+    #[test]
+    fn undefined_label() {
+        assert_eq!(
+            err_at("jmp start\nhlt\njne nowhere"),
+            (1, 5, AsmErrorKind::UndefinedLabel("start".into()))
+        );
+    }
+
+    #[test]
+    fn duplicate_label() {
+        assert_eq!(
+            err_at("a1: hlt\n  a1: hlt"),
+            (2, 3, AsmErrorKind::DuplicateLabel("a1".into()))
+        );
+    }
+
+    #[test]
+    fn invalid_label() {
+        assert_eq!(
+            err_at("1abc: hlt"),
+            (1, 1, AsmErrorKind::InvalidLabel("1abc".into()))
+        );
+        assert_eq!(
+            err_at("al: hlt"),
+            (1, 1, AsmErrorKind::InvalidLabel("al".into()))
+        );
+        assert_eq!(
+            err_at("ds: hlt"),
+            (1, 1, AsmErrorKind::InvalidLabel("ds".into()))
+        );
+    }
+
+    #[test]
+    fn label_out_of_range() {
+        let src = "hlt\n".repeat(0x10000) + "end: hlt";
+        assert_eq!(
+            err_at(&src),
+            (0x10001, 1, AsmErrorKind::LabelOutOfRange("end".into()))
+        );
+    }
+
+    #[test]
+    fn unterminated_memory() {
+        assert_eq!(err_at("ld [10 a"), (1, 4, AsmErrorKind::UnterminatedMemory));
+    }
+}
@@ -0,0 +1,415 @@
+//! What the cpu talks to: plain RAM, or a memory map routing address ranges
+//! to RAM, ROM and devices.
+
+#![allow(dead_code)]
+
+use crate::cpu::*;
+
+/// Byte addressable memory or device. Words are big-endian.
+///
+/// `read` takes `&mut self` as reading a device register may change it,
+/// `peek` must not have side effects since it's used to fetch instructions
+/// and by debugging tools.
+pub trait Bus {
+    fn len(&self) -> usize;
+
+    fn peek(&self, addr: usize) -> Result<u8, MemFault>;
+
+    fn read(&mut self, addr: usize) -> Result<u8, MemFault>;
+
+    fn write(&mut self, addr: usize, val: u8) -> Result<(), MemFault>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn read_16(&mut self, addr: usize) -> Result<u16, MemFault> {
+        Ok(((self.read(addr)? as u16) << 8) | self.read(addr + 1)? as u16)
+    }
+
+    /// Faults before writing anything if either address is out of range.
+    fn write_16(&mut self, addr: usize, val: i16) -> Result<(), MemFault> {
+        self.peek(addr)?;
+        self.peek(addr + 1)?;
+
+        let hl = val.to_be_bytes();
+        self.write(addr, hl[0])?;
+        self.write(addr + 1, hl[1])
+    }
+
+    /// Start of the interrupt vector table.
+    fn ivt(&self) -> usize {
+        0
+    }
+
+    /// Handler of interrupt `vector` as (segment, offset). Each vector takes
+    /// 4 bytes, the offset first.
+    fn vector(&mut self, vector: u8) -> Result<(u16, u16), MemFault> {
+        let addr = self.ivt() + 4 * vector as usize;
+        let offset = self.read_16(addr)?;
+        Ok((self.read_16(addr + 2)?, offset))
+    }
+
+    /// Watchpoint hits since the last call, for buses that support them.
+    fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        Vec::new()
+    }
+
+    /// Checks that `addr..addr + size` may be used for `access`, for buses
+    /// with protected regions.
+    fn protect(&self, _addr: usize, _size: usize, _access: Perms) -> Result<(), MemFault> {
+        Ok(())
+    }
+
+    fn start_journal(&mut self) {}
+
+    /// Bytes written since `start_journal`, for buses that record them.
+    fn stop_journal(&mut self) -> Vec<MemWrite> {
+        Vec::new()
+    }
+
+    /// Same as `stop_journal` but keeps recording.
+    fn take_journal(&mut self) -> Vec<MemWrite> {
+        Vec::new()
+    }
+
+    /// Writes without watchpoints, protections or journal, for debugging
+    /// tools. Buses without these just write.
+    fn poke(&mut self, addr: usize, val: u8) -> Result<(), MemFault> {
+        self.write(addr, val)
+    }
+}
+
+impl Bus for Mem {
+    fn len(&self) -> usize {
+        Mem::len(self)
+    }
+
+    fn peek(&self, addr: usize) -> Result<u8, MemFault> {
+        Mem::peek(self, addr)
+    }
+
+    fn read(&mut self, addr: usize) -> Result<u8, MemFault> {
+        Mem::read(self, addr)
+    }
+
+    fn write(&mut self, addr: usize, val: u8) -> Result<(), MemFault> {
+        Mem::write(self, addr, val)
+    }
+
+    fn read_16(&mut self, addr: usize) -> Result<u16, MemFault> {
+        Mem::read_16(self, addr)
+    }
+
+    fn write_16(&mut self, addr: usize, val: i16) -> Result<(), MemFault> {
+        Mem::write_16(self, addr, val)
+    }
+
+    fn ivt(&self) -> usize {
+        Mem::ivt(self)
+    }
+
+    fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        Mem::take_watch_hits(self)
+    }
+
+    fn protect(&self, addr: usize, size: usize, access: Perms) -> Result<(), MemFault> {
+        Mem::protect(self, addr, size, access)
+    }
+
+    fn start_journal(&mut self) {
+        Mem::start_journal(self)
+    }
+
+    fn stop_journal(&mut self) -> Vec<MemWrite> {
+        Mem::stop_journal(self)
+    }
+
+    fn take_journal(&mut self) -> Vec<MemWrite> {
+        Mem::take_journal(self)
+    }
+
+    fn poke(&mut self, addr: usize, val: u8) -> Result<(), MemFault> {
+        Mem::poke(self, addr, val)
+    }
+}
+
+/// Read-only memory, writes fault.
+pub struct Rom(Vec<u8>);
+
+impl Rom {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Rom(bytes)
+    }
+}
+
+impl Bus for Rom {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn peek(&self, addr: usize) -> Result<u8, MemFault> {
+        self.0.get(addr).copied().ok_or(MemFault {
+            addr,
+            kind: MemFaultKind::OutOfBounds,
+        })
+    }
+
+    fn read(&mut self, addr: usize) -> Result<u8, MemFault> {
+        self.peek(addr)
+    }
+
+    fn write(&mut self, addr: usize, _val: u8) -> Result<(), MemFault> {
+        self.peek(addr)?;
+        Err(MemFault {
+            addr,
+            kind: MemFaultKind::ReadOnly,
+        })
+    }
+}
+
+struct Region {
+    start: usize,
+    end: usize,
+    bus: Box<dyn Bus>,
+}
+
+/// Routes address ranges to other buses. Each one sees addresses relative to
+/// the start of its range, addresses outside every range fault.
+#[derive(Default)]
+pub struct MemoryMap {
+    // sorted by address
+    regions: Vec<Region>,
+    ivt: usize,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        MemoryMap::default()
+    }
+
+    /// Maps `bus` at `start..start + bus.len()`, which must not overlap
+    /// anything already mapped.
+    pub fn map(&mut self, start: usize, bus: Box<dyn Bus>) {
+        let end = start + bus.len();
+        let i = self.regions.partition_point(|r| r.start < start);
+        assert!(i == 0 || self.regions[i - 1].end <= start);
+        assert!(i == self.regions.len() || end <= self.regions[i].start);
+
+        self.regions.insert(i, Region { start, end, bus });
+    }
+
+    pub fn set_ivt(&mut self, base: usize) {
+        self.ivt = base;
+    }
+
+    fn region(&self, addr: usize) -> Result<usize, MemFault> {
+        let i = self.regions.partition_point(|r| r.end <= addr);
+        match self.regions.get(i) {
+            Some(r) if r.start <= addr => Ok(i),
+            _ => Err(MemFault {
+                addr,
+                kind: MemFaultKind::Unmapped,
+            }),
+        }
+    }
+
+    /// Runs `f` on the region holding `addr`, with the address relative to
+    /// it. Faults are reported with the absolute address.
+    fn route<T>(
+        &mut self,
+        addr: usize,
+        f: impl FnOnce(&mut dyn Bus, usize) -> Result<T, MemFault>,
+    ) -> Result<T, MemFault> {
+        let i = self.region(addr)?;
+        let r = &mut self.regions[i];
+        f(r.bus.as_mut(), addr - r.start).map_err(|e| MemFault {
+            addr: e.addr + r.start,
+            ..e
+        })
+    }
+
+    /// Collects the journal of every region, with absolute addresses.
+    fn journals(&mut self, mut f: impl FnMut(&mut dyn Bus) -> Vec<MemWrite>) -> Vec<MemWrite> {
+        let mut writes = Vec::new();
+        for r in self.regions.iter_mut() {
+            writes.extend(f(r.bus.as_mut()).into_iter().map(|w| MemWrite {
+                addr: w.addr + r.start,
+                ..w
+            }));
+        }
+        writes
+    }
+}
+
+impl Bus for MemoryMap {
+    /// Up to the end of the last region, there may be holes.
+    fn len(&self) -> usize {
+        self.regions.last().map_or(0, |r| r.end)
+    }
+
+    fn peek(&self, addr: usize) -> Result<u8, MemFault> {
+        let r = &self.regions[self.region(addr)?];
+        r.bus.peek(addr - r.start).map_err(|e| MemFault {
+            addr: e.addr + r.start,
+            ..e
+        })
+    }
+
+    fn read(&mut self, addr: usize) -> Result<u8, MemFault> {
+        self.route(addr, |bus, addr| bus.read(addr))
+    }
+
+    fn write(&mut self, addr: usize, val: u8) -> Result<(), MemFault> {
+        self.route(addr, |bus, addr| bus.write(addr, val))
+    }
+
+    fn ivt(&self) -> usize {
+        self.ivt
+    }
+
+    /// Unmapped addresses are left for the access itself to fault.
+    fn protect(&self, addr: usize, size: usize, access: Perms) -> Result<(), MemFault> {
+        let Ok(i) = self.region(addr) else {
+            return Ok(());
+        };
+        let r = &self.regions[i];
+        r.bus
+            .protect(addr - r.start, size, access)
+            .map_err(|e| MemFault {
+                addr: e.addr + r.start,
+                ..e
+            })
+    }
+
+    fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        let mut hits = Vec::new();
+        for r in self.regions.iter_mut() {
+            hits.extend(r.bus.take_watch_hits().into_iter().map(|h| WatchHit {
+                addr: h.addr + r.start,
+                ..h
+            }));
+        }
+        hits
+    }
+
+    fn start_journal(&mut self) {
+        self.regions.iter_mut().for_each(|r| r.bus.start_journal());
+    }
+
+    fn stop_journal(&mut self) -> Vec<MemWrite> {
+        self.journals(|bus| bus.stop_journal())
+    }
+
+    fn take_journal(&mut self) -> Vec<MemWrite> {
+        self.journals(|bus| bus.take_journal())
+    }
+
+    fn poke(&mut self, addr: usize, val: u8) -> Result<(), MemFault> {
+        self.route(addr, |bus, addr| bus.poke(addr, val))
+    }
+}
+
+#[cfg(test)]
+mod bus_tests {
+    use super::*;
+    use crate::asm;
+
+    /// Counts its reads, the count can't be written.
+    struct Counter(u8);
+
+    impl Bus for Counter {
+        fn len(&self) -> usize {
+            2
+        }
+
+        fn peek(&self, _addr: usize) -> Result<u8, MemFault> {
+            Ok(self.0)
+        }
+
+        fn read(&mut self, _addr: usize) -> Result<u8, MemFault> {
+            self.0 += 1;
+            Ok(self.0)
+        }
+
+        fn write(&mut self, addr: usize, _val: u8) -> Result<(), MemFault> {
+            Err(MemFault {
+                addr,
+                kind: MemFaultKind::ReadOnly,
+            })
+        }
+    }
+
+    fn map() -> MemoryMap {
+        let mut map = MemoryMap::new();
+        map.map(0x10, Box::new(Mem::new(0x10)));
+        map.map(0x00, Box::new(Rom::new(vec![1, 2, 3, 4])));
+        map.map(0x20, Box::new(Counter(0)));
+        map
+    }
+
+    #[test]
+    fn routes_to_regions() {
+        let mut map = map();
+
+        assert_eq!(map.len(), 0x22);
+        assert_eq!(map.read_16(0x02), Ok(0x0304));
+        map.write_16(0x1e, -2).unwrap();
+        assert_eq!(map.read(0x1f), Ok(0xfe));
+        assert_eq!(map.read(0x20), Ok(1));
+        assert_eq!(map.read(0x20), Ok(2));
+        assert_eq!(map.peek(0x20), Ok(2));
+    }
+
+    #[test]
+    fn faults() {
+        let mut map = map();
+        fn fault<T>(addr: usize, kind: MemFaultKind) -> Result<T, MemFault> {
+            Err(MemFault { addr, kind })
+        }
+
+        assert_eq!(map.write(0x01, 0), fault(0x01, MemFaultKind::ReadOnly));
+        assert_eq!(map.read(0x08), fault(0x08, MemFaultKind::Unmapped));
+        assert_eq!(map.read(0x22), fault(0x22, MemFaultKind::Unmapped));
+        // neither byte is written if one of them isn't mapped
+        assert_eq!(
+            map.write_16(0x0f, 0x0101),
+            fault(0x0f, MemFaultKind::Unmapped)
+        );
+        assert_eq!(map.peek(0x10), Ok(0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn overlapping_regions() {
+        let mut map = map();
+        map.map(0x1f, Box::new(Mem::new(1)));
+    }
+
+    #[test]
+    fn run_from_rom() {
+        let prog = asm::assemble("ld [0x0100] a\nld a [0x0200]\nhlt").unwrap();
+        let mut map = MemoryMap::new();
+        map.map(0x000, Box::new(Rom::new(prog)));
+        map.map(0x100, Box::new(Counter(41)));
+        map.map(0x200, Box::new(Mem::new(2)));
+
+        let mut cpu = Cpu::default();
+        assert_eq!(cpu.run(&mut map, 10), StopReason::Halt);
+        assert_eq!(cpu.reg_read(Reg::A), 0x2a2b);
+        assert_eq!(map.peek(0x201), Ok(0x2b));
+    }
+
+    #[test]
+    fn watchpoints_in_regions() {
+        let mut ram = Mem::new(4);
+        ram.add_watchpoint(2..4, WatchKind::Write);
+        let mut map = MemoryMap::new();
+        map.map(0x40, Box::new(ram));
+
+        map.write(0x43, 7).unwrap();
+        let hits = map.take_watch_hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].addr, 0x43);
+    }
+}
@@ -1,10 +1,39 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
+use crate::bus::Bus;
+use crate::encoding::{self, DecodeError};
+use crate::graphics::Framebuffer;
+use crate::history::{History, ReverseStop, Undo};
+use crate::pic::Pic;
+use crate::syscall::SyscallHandler;
+use crate::timing::{CycleTable, Event, Scheduler};
+use crate::trace::{Snapshot, TraceEntry, Tracer};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
 const MASK_HIGH: i16 = 0xff00u16 as i16;
 const MASK_LOW: i16 = 0x00ff;
 
-#[derive(Copy, Clone)]
+/// Segment:offset addresses cover 1 MiB, like on the 8088.
+pub const ADDRESS_SPACE: usize = 1 << 20;
+
+/// Physical address of `segment:offset`, wrapping around at 1 MiB.
+pub fn physical(segment: u16, offset: u16) -> usize {
+    (((segment as usize) << 4) + offset as usize) % ADDRESS_SPACE
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SegReg {
+    CS,
+    DS,
+    SS,
+    ES,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Reg {
     A,
     AH,
@@ -20,22 +49,84 @@ pub enum Reg {
     DL,
 }
 
+impl Reg {
+    pub fn width(self) -> Width {
+        match self {
+            Reg::A | Reg::B | Reg::C | Reg::D => Width::Word,
+            _ => Width::Byte,
+        }
+    }
+}
+
+/// Size of an operation, the one of its destination register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Word,
+}
+
+impl Width {
+    pub fn bits(self) -> u32 {
+        match self {
+            Width::Byte => 8,
+            Width::Word => 16,
+        }
+    }
+
+    pub fn mask(self) -> u32 {
+        (1 << self.bits()) - 1
+    }
+
+    pub fn sign_bit(self) -> u32 {
+        1 << (self.bits() - 1)
+    }
+
+    pub fn min(self) -> i32 {
+        -(self.sign_bit() as i32)
+    }
+
+    pub fn max(self) -> i32 {
+        self.sign_bit() as i32 - 1
+    }
+
+    pub fn fits(self, val: i32) -> bool {
+        (self.min()..=self.max()).contains(&val)
+    }
+
+    /// `val` truncated to this width, as a signed number.
+    pub fn signed(self, val: i16) -> i32 {
+        match self {
+            Width::Byte => val as i8 as i32,
+            Width::Word => val as i32,
+        }
+    }
+
+    /// `val` truncated to this width, as an unsigned number.
+    pub fn unsigned(self, val: i16) -> u32 {
+        val as u16 as u32 & self.mask()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Dest {
     Memory(u16),
     Register(Reg),
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Inpt {
     Const(i16),
     Register(Reg),
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum GenerousInpt {
     Const(i16),
     Register(Reg),
     Memory(u16),
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Instruction {
     Ld(GenerousInpt, Dest),
     // integer arithmetic
@@ -51,6 +142,8 @@ pub enum Instruction {
     Xor(Reg, Reg),
     Shr(Inpt, Reg),
     Shl(Inpt, Reg),
+    Sext(Reg, Reg),
+    Zext(Reg, Reg),
 
     // program flow
     Cmp(Reg, Reg),
@@ -63,103 +156,844 @@ pub enum Instruction {
     // stack
     Push(Inpt),
     Pop(Reg),
+
+    // subroutines
+    Call(Inpt),
+    Ret,
+
+    // graphics, points are packed as x in the high byte and y in the low one
+    Pxl(Reg, Reg),
+    Rect(Reg, Reg, Reg),
+    Line(Reg, Reg, Reg),
+    Cls(Reg),
+
+    // segments, `Seg` overrides the data segment of the next instruction
+    LdSeg(Inpt, SegReg),
+    StSeg(SegReg, Reg),
+    Seg(SegReg),
+    JmpFar(Inpt, Inpt),
+    CallFar(Inpt, Inpt),
+    RetFar,
+
+    // machine
+    Hlt,
+    Syscall,
+
+    // interrupts
+    Cli,
+    Sti,
+    Iret,
+
+    // flags
+    Pushf,
+    Popf,
+}
+
+impl Instruction {
+    /// Flags this instruction defines, set or cleared depending on its
+    /// result. The other ones are left alone.
+    pub fn flags_written(&self) -> u16 {
+        match self {
+            Instruction::Sum(..)
+            | Instruction::Sub(..)
+            | Instruction::Mul(..)
+            | Instruction::Div(..)
+            | Instruction::And(..)
+            | Instruction::Or(..)
+            | Instruction::Xor(..)
+            | Instruction::Shr(..)
+            | Instruction::Shl(..) => Cpu::FLAGS_RESULT,
+            Instruction::Cmp(..) => Cpu::FLAGS_RESULT | Cpu::FLAGS_COMPARE,
+            Instruction::Cli | Instruction::Sti => Cpu::FLAG_INTERRUPT,
+            // syscall handlers may report through the flags
+            Instruction::Iret | Instruction::Popf | Instruction::Syscall => Cpu::FLAGS_ALL,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemFaultKind {
+    OutOfBounds,
+    ReadOnly,
+    Unmapped,
+    /// The region holding the address doesn't allow this access.
+    Protected(Perms),
+}
+
+/// Invalid memory access at `addr`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemFault {
+    pub addr: usize,
+    pub kind: MemFaultKind,
+}
+
+impl fmt::Display for MemFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            MemFaultKind::OutOfBounds => write!(f, "address {:#06x} is out of bounds", self.addr),
+            MemFaultKind::ReadOnly => write!(f, "address {:#06x} is read-only", self.addr),
+            MemFaultKind::Unmapped => write!(f, "address {:#06x} is not mapped", self.addr),
+            MemFaultKind::Protected(access) => write!(
+                f,
+                "{} at address {:#06x} isn't allowed",
+                access.access_name(),
+                self.addr
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MemFault {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Which accesses trigger a watchpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::Access, _)
+                | (WatchKind::Read, Access::Read)
+                | (WatchKind::Write, Access::Write)
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: Range<usize>,
+    pub kind: WatchKind,
+}
+
+/// Access of `size` bytes at `addr` that touched a watchpoint. `old` and `new`
+/// are the same for reads.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub addr: usize,
+    pub size: usize,
+    pub access: Access,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// A single byte changed by a write, recorded while journaling is on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemWrite {
+    pub addr: usize,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// What a memory region can be used for, a set of `READ`, `WRITE`,
+/// `EXECUTE` and `STACK`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Perms(u8);
+
+impl Perms {
+    pub const NONE: Perms = Perms(0);
+    pub const READ: Perms = Perms(0b0001);
+    pub const WRITE: Perms = Perms(0b0010);
+    pub const EXECUTE: Perms = Perms(0b0100);
+    /// The stack may grow into the region, pushes also need `WRITE`.
+    pub const STACK: Perms = Perms(0b1000);
+
+    /// Read and execute, code can't be overwritten.
+    pub const CODE: Perms = Perms(0b0101);
+    /// Read and write.
+    pub const DATA: Perms = Perms(0b0011);
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Unknown bits are dropped.
+    pub fn from_bits(bits: u8) -> Perms {
+        Perms(bits & 0b1111)
+    }
+
+    pub fn contains(self, other: Perms) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Name of a single access, used in warnings and faults.
+    fn access_name(self) -> &'static str {
+        match self {
+            Perms::READ => "read",
+            Perms::WRITE => "write",
+            Perms::EXECUTE => "execution",
+            Perms::STACK => "stack push",
+            _ => "access",
+        }
+    }
+}
+
+impl std::ops::BitOr for Perms {
+    type Output = Perms;
+
+    fn bitor(self, rhs: Perms) -> Perms {
+        Perms(self.0 | rhs.0)
+    }
+}
+
+/// `rwxs`, with a `-` for each missing permission.
+impl fmt::Display for Perms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (perm, c) in [
+            (Perms::READ, 'r'),
+            (Perms::WRITE, 'w'),
+            (Perms::EXECUTE, 'x'),
+            (Perms::STACK, 's'),
+        ] {
+            write!(f, "{}", if self.contains(perm) { c } else { '-' })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A named part of memory, e.g. the program's code or its stack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub range: Range<usize>,
+    pub perms: Perms,
+}
+
+/// What happens on an access a region doesn't allow.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Nothing, regions are ignored.
+    Off,
+    /// The access goes through and is recorded, see `Mem::take_violations`.
+    #[default]
+    Warn,
+    /// The access faults with `MemFaultKind::Protected`.
+    Fault,
+}
+
+/// An access that went through although its region doesn't allow it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub addr: usize,
+    pub access: Perms,
+    pub region: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:#06x} in `{}`",
+            self.access.access_name(),
+            self.addr,
+            self.region
+        )
+    }
+}
+
+/// How wide physical addresses are, which bounds the size of memory.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum AddressMode {
+    /// 64 KiB, a single segment.
+    Bits16,
+    /// 1 MiB, `segment:offset` addresses like on the 8088.
+    #[default]
+    Bits20,
+}
+
+impl AddressMode {
+    pub fn bits(self) -> u32 {
+        match self {
+            AddressMode::Bits16 => 16,
+            AddressMode::Bits20 => 20,
+        }
+    }
+
+    /// Number of addresses.
+    pub fn size(self) -> usize {
+        1 << self.bits()
+    }
+}
+
+/// How the bytes of `Mem` are stored.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Backing {
+    /// One allocation for the whole memory.
+    #[default]
+    Dense,
+    /// Pages of `PAGE_SIZE` bytes allocated on their first write, so large
+    /// mostly empty memories stay cheap.
+    Paged,
+}
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// A memory that doesn't fit in its address space.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemSizeError {
+    pub size: usize,
+    pub mode: AddressMode,
+}
+
+impl fmt::Display for MemSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "memory of {:#x} bytes doesn't fit in a {}-bit address space",
+            self.size,
+            self.mode.bits()
+        )
+    }
+}
+
+impl std::error::Error for MemSizeError {}
+
+enum Storage {
+    Dense(Vec<u8>),
+    // unallocated pages read as 0
+    Paged {
+        len: usize,
+        pages: HashMap<usize, Box<[u8]>>,
+    },
+}
+
+impl Storage {
+    fn len(&self) -> usize {
+        match self {
+            Storage::Dense(bytes) => bytes.len(),
+            Storage::Paged { len, .. } => *len,
+        }
+    }
+
+    /// `index` must be in bounds.
+    fn get(&self, index: usize) -> u8 {
+        match self {
+            Storage::Dense(bytes) => bytes[index],
+            Storage::Paged { pages, .. } => pages
+                .get(&(index / PAGE_SIZE))
+                .map_or(0, |page| page[index % PAGE_SIZE]),
+        }
+    }
+
+    /// `index` must be in bounds.
+    fn set(&mut self, index: usize, val: u8) {
+        match self {
+            Storage::Dense(bytes) => bytes[index] = val,
+            Storage::Paged { pages, .. } => {
+                let n = index / PAGE_SIZE;
+                if val == 0 && !pages.contains_key(&n) {
+                    return;
+                }
+                pages
+                    .entry(n)
+                    .or_insert_with(|| vec![0; PAGE_SIZE].into_boxed_slice())[index % PAGE_SIZE] =
+                    val;
+            }
+        }
+    }
+
+    fn allocated(&self) -> usize {
+        match self {
+            Storage::Dense(bytes) => bytes.len(),
+            Storage::Paged { pages, .. } => pages.len() * PAGE_SIZE,
+        }
+    }
 }
 
 pub struct Mem {
-    array: Vec<u8>,
-    // store eventually
-    // - program start pointer
-    // - data start pointer
-    // - stack start pointer
-    // to be able to warn the user
+    storage: Storage,
+    mode: AddressMode,
+    watchpoints: Vec<Watchpoint>,
+    // reads only borrow memory, so hits are recorded through a RefCell
+    watch_hits: RefCell<Vec<WatchHit>>,
+    journal: Option<Vec<MemWrite>>,
+    // interrupt vector table, a segment:offset handler address per vector
+    ivt: usize,
+    // checked in order, the first region holding an address applies
+    regions: Vec<Region>,
+    strictness: Strictness,
+    violations: RefCell<Vec<Violation>>,
 }
 
 impl Default for Mem {
     fn default() -> Self {
-        Mem::new(10)
+        Mem::new(crate::machine::MEM_SIZE)
     }
 }
 
 impl Mem {
+    /// Dense memory of `size` bytes in the 20-bit address space.
+    ///
+    /// Panics if `size` doesn't fit, see `with_mode`.
     pub fn new(size: usize) -> Self {
-        Mem {
-            array: vec![0; size],
+        Mem::with_mode(size, AddressMode::default(), Backing::default())
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Memory of `size` bytes, starting at address 0 of a `mode` address
+    /// space.
+    pub fn with_mode(
+        size: usize,
+        mode: AddressMode,
+        backing: Backing,
+    ) -> Result<Self, MemSizeError> {
+        if size > mode.size() {
+            return Err(MemSizeError { size, mode });
+        }
+
+        let storage = match backing {
+            Backing::Dense => Storage::Dense(vec![0; size]),
+            Backing::Paged => Storage::Paged {
+                len: size,
+                pages: HashMap::new(),
+            },
+        };
+
+        Ok(Mem {
+            storage,
+            mode,
+            watchpoints: Vec::new(),
+            watch_hits: RefCell::new(Vec::new()),
+            journal: None,
+            ivt: 0,
+            regions: Vec::new(),
+            strictness: Strictness::default(),
+            violations: RefCell::new(Vec::new()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.storage.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn mode(&self) -> AddressMode {
+        self.mode
+    }
+
+    pub fn backing(&self) -> Backing {
+        match self.storage {
+            Storage::Dense(_) => Backing::Dense,
+            Storage::Paged { .. } => Backing::Paged,
+        }
+    }
+
+    /// Bytes actually allocated, less than `len` for paged memory that was
+    /// never written to.
+    pub fn allocated(&self) -> usize {
+        self.storage.allocated()
+    }
+
+    /// Copies `bytes` into memory starting at `index`.
+    pub fn load(&mut self, index: usize, bytes: &[u8]) {
+        assert!(index + bytes.len() <= self.len());
+        match &mut self.storage {
+            Storage::Dense(array) => array[index..index + bytes.len()].copy_from_slice(bytes),
+            storage => {
+                for (i, &b) in bytes.iter().enumerate() {
+                    storage.set(index + i, b);
+                }
+            }
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, range: Range<usize>, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { range, kind });
+    }
+
+    /// Removes every watchpoint on exactly `range`.
+    pub fn remove_watchpoint(&mut self, range: Range<usize>) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|w| w.range != range);
+        self.watchpoints.len() != before
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Returns and forgets the watchpoint hits since the last call.
+    pub fn take_watch_hits(&self) -> Vec<WatchHit> {
+        self.watch_hits.take()
+    }
+
+    fn watch(&self, addr: usize, size: usize, access: Access, old: u16, new: u16) {
+        let hit = self
+            .watchpoints
+            .iter()
+            .any(|w| w.kind.matches(access) && w.range.start < addr + size && addr < w.range.end);
+
+        if hit {
+            self.watch_hits.borrow_mut().push(WatchHit {
+                addr,
+                size,
+                access,
+                old,
+                new,
+            });
+        }
+    }
+
+    /// Moves the interrupt vector table, which starts at 0 by default. Each
+    /// vector takes 4 bytes: the handler's offset, then its segment.
+    pub fn set_ivt(&mut self, base: usize) {
+        self.ivt = base;
+    }
+
+    pub fn ivt(&self) -> usize {
+        self.ivt
+    }
+
+    pub fn set_vector(&mut self, vector: u8, segment: u16, offset: u16) -> Result<(), MemFault> {
+        let addr = self.ivt + 4 * vector as usize;
+        self.check(addr + 3)?;
+        self.write_16(addr, offset as i16)?;
+        self.write_16(addr + 2, segment as i16)
+    }
+
+    /// Adds a region, addresses outside of every region can be used for
+    /// anything.
+    pub fn add_region(&mut self, name: &str, range: Range<usize>, perms: Perms) {
+        self.regions.push(Region {
+            name: name.to_string(),
+            range,
+            perms,
+        });
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn region(&self, addr: usize) -> Option<&Region> {
+        self.regions.iter().find(|r| r.range.contains(&addr))
+    }
+
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
+    }
+
+    pub fn strictness(&self) -> Strictness {
+        self.strictness
+    }
+
+    /// Returns and forgets the violations since the last call.
+    pub fn take_violations(&self) -> Vec<Violation> {
+        self.violations.take()
+    }
+
+    /// Checks that the regions holding `addr..addr + size` allow `access`.
+    /// Depending on the strictness, the first byte that doesn't is
+    /// recorded as a violation or faults.
+    pub fn protect(&self, addr: usize, size: usize, access: Perms) -> Result<(), MemFault> {
+        if self.strictness == Strictness::Off {
+            return Ok(());
+        }
+
+        let denied = (addr..addr + size).find_map(|a| {
+            let a = a % self.mode.size();
+            self.region(a)
+                .filter(|r| !r.perms.contains(access))
+                .map(|r| (a, r))
+        });
+        let Some((addr, region)) = denied else {
+            return Ok(());
+        };
+
+        if self.strictness == Strictness::Fault {
+            return Err(MemFault {
+                addr,
+                kind: MemFaultKind::Protected(access),
+            });
+        }
+
+        self.violations.borrow_mut().push(Violation {
+            addr,
+            access,
+            region: region.name.clone(),
+        });
+        Ok(())
+    }
+
+    /// Starts recording every byte written, see `take_journal`.
+    pub fn start_journal(&mut self) {
+        self.journal.get_or_insert_with(Vec::new);
+    }
+
+    /// Stops recording writes and returns what was recorded so far.
+    pub fn stop_journal(&mut self) -> Vec<MemWrite> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    /// Returns the writes recorded so far and keeps recording.
+    pub fn take_journal(&mut self) -> Vec<MemWrite> {
+        self.journal
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn journal(&mut self, addr: usize, old: u8, new: u8) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(MemWrite { addr, old, new });
+        }
+    }
+
+    fn check(&self, index: usize) -> Result<(), MemFault> {
+        if index < self.len() {
+            Ok(())
+        } else {
+            Err(MemFault {
+                addr: index,
+                kind: MemFaultKind::OutOfBounds,
+            })
         }
     }
 
-    pub fn read(&self, index: usize) -> u8 {
-        assert!(index < self.array.len());
-        self.array[index]
+    /// Writes a byte without triggering watchpoints or checking regions, for
+    /// debugging tools.
+    pub fn poke(&mut self, index: usize, val: u8) -> Result<(), MemFault> {
+        self.check(index)?;
+        self.storage.set(index, val);
+        Ok(())
+    }
+
+    /// Reads a byte without triggering watchpoints, for instruction fetches
+    /// and debugging tools.
+    pub fn peek(&self, index: usize) -> Result<u8, MemFault> {
+        self.check(index)?;
+        Ok(self.storage.get(index))
+    }
+
+    /// Address of the second byte of a word at `index`. Words wrap around
+    /// at the top of the address space, so in a memory filling it the word
+    /// at the last address ends at 0. In a smaller one it's out of bounds.
+    fn next(&self, index: usize) -> usize {
+        (index + 1) % self.mode.size()
+    }
+
+    fn peek_16(&self, index: usize) -> Result<u16, MemFault> {
+        Ok(((self.peek(index)? as u16) << 8) | self.peek(self.next(index))? as u16)
+    }
+
+    pub fn read(&self, index: usize) -> Result<u8, MemFault> {
+        let val = self.peek(index)?;
+        self.protect(index, 1, Perms::READ)?;
+        self.watch(index, 1, Access::Read, val.into(), val.into());
+        Ok(val)
     }
 
-    pub fn read_16(&self, index: usize) -> u16 {
-        ((self.array[index] as u16) << 8) | self.array[index + 1] as u16
+    pub fn read_16(&self, index: usize) -> Result<u16, MemFault> {
+        let val = self.peek_16(index)?;
+        self.protect(index, 2, Perms::READ)?;
+        self.watch(index, 2, Access::Read, val, val);
+        Ok(val)
     }
 
-    pub fn write(&mut self, index: usize, val: u8) {
-        assert!(index < self.array.len());
-        self.array[index] = val;
+    pub fn write(&mut self, index: usize, val: u8) -> Result<(), MemFault> {
+        let old = self.peek(index)?;
+        self.protect(index, 1, Perms::WRITE)?;
+        self.storage.set(index, val);
+        self.journal(index, old, val);
+        self.watch(index, 1, Access::Write, old.into(), val.into());
+        Ok(())
     }
 
-    pub fn write_16(&mut self, index: usize, val: i16) {
-        assert!(index + 1 < self.array.len());
+    /// Writes both bytes or none of them.
+    pub fn write_16(&mut self, index: usize, val: i16) -> Result<(), MemFault> {
+        let old = self.peek_16(index)?;
+        self.protect(index, 2, Perms::WRITE)?;
 
         let hl = val.to_be_bytes();
-        println!("{:?}", hl);
-        self.array[index] = hl[0];
-        self.array[index + 1] = hl[1];
+        for (addr, b) in [(index, hl[0]), (self.next(index), hl[1])] {
+            self.journal(addr, self.storage.get(addr), b);
+            self.storage.set(addr, b);
+        }
+        self.watch(index, 2, Access::Write, old, val as u16);
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    Decode(DecodeError),
+    Mem(MemFault),
+    StackOverflow,
+    StackUnderflow,
+    UnknownSyscall(u16),
+    SyscallFailed(u16),
+    NoFramebuffer,
+    InvalidColor(u8),
+    DivideByZero,
+    Overflow,
+}
+
+impl From<MemFault> for Fault {
+    fn from(f: MemFault) -> Self {
+        Fault::Mem(f)
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::Decode(e) => write!(f, "{}", e),
+            Fault::Mem(e) => write!(f, "{}", e),
+            Fault::StackOverflow => write!(f, "stack overflow"),
+            Fault::StackUnderflow => write!(f, "stack underflow"),
+            Fault::UnknownSyscall(n) => write!(f, "unknown syscall {}", n),
+            Fault::SyscallFailed(n) => write!(f, "syscall {} failed", n),
+            Fault::NoFramebuffer => write!(f, "no framebuffer attached"),
+            Fault::InvalidColor(c) => write!(f, "invalid color {}", c),
+            Fault::DivideByZero => write!(f, "division by zero"),
+            Fault::Overflow => write!(f, "arithmetic overflow"),
+        }
     }
 }
 
+impl std::error::Error for Fault {}
+
+/// What arithmetic writes when a signed result doesn't fit in its register.
+/// The overflow flag is set either way.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Keeps the low bits, two's complement, like the 8088.
+    Wrap,
+    /// Clamps to the largest or smallest value.
+    Saturate,
+    /// Writes 0.
+    #[default]
+    Zero,
+    /// Faults with `Fault::Overflow`, leaving the registers untouched.
+    Trap,
+}
+
+/// Why `Cpu::run` (or `Cpu::step`) gave control back to the caller.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Halt,
+    Exit(i16),
+    Fault(Fault),
+    Limit,
+    Breakpoint(usize),
+    Watchpoint(WatchHit),
+}
+
+/// The part of the cpu a program can see or change, saved by snapshots.
+/// Host side configuration (syscalls, timing, breakpoints, tracer,
+/// scheduled events) isn't part of it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuState {
+    pub a: i16,
+    pub b: i16,
+    pub c: i16,
+    pub d: i16,
+    pub flags: u16,
+    pub ip: u16,
+    pub sp: u16,
+    pub cs: u16,
+    pub ds: u16,
+    pub ss: u16,
+    pub es: u16,
+    pub seg_override: Option<SegReg>,
+    pub stack_top: u16,
+    pub stack_size: u16,
+    pub halted: bool,
+    pub exit_status: Option<i16>,
+    pub cycles: u64,
+    pub overflow_policy: OverflowPolicy,
+    pub irq_pending: u8,
+    pub irq_mask: u8,
+}
+
+#[derive(Default)]
 pub struct Cpu {
     // general
     a: i16,
     b: i16,
     c: i16,
     d: i16,
-    flags: u8,
+    flags: u16,
     // pointers
     ip: u16,
     sp: u16,
     // segments
+    cs: u16,
+    ds: u16,
     ss: u16,
+    es: u16,
+    seg_override: Option<SegReg>,
+    // stack bounds, offsets in SS. The stack grows down from `stack_top`.
+    stack_top: u16,
     stack_size: u16,
-    // cs: u16,
-}
-
-impl Default for Cpu {
-    fn default() -> Self {
-        Cpu {
-            a: 0,
-            b: 0,
-            c: 0,
-            d: 0,
-            flags: 0,
-            ip: 0,
-            sp: 0,
-            stack_size: 0,
-            ss: 0,
-        }
-    }
+    halted: bool,
+    exit_status: Option<i16>,
+    // physical addresses
+    breakpoints: HashSet<usize>,
+    // breakpoint the cpu stopped at, skipped when resuming from it
+    stopped_at: Option<usize>,
+    syscalls: HashMap<u16, Box<dyn SyscallHandler>>,
+    framebuffer: Option<Framebuffer>,
+    tracer: Option<Tracer>,
+    cycles: u64,
+    timing: CycleTable,
+    overflow_policy: OverflowPolicy,
+    scheduler: Scheduler,
+    pic: Pic,
+    history: Option<History>,
 }
 
 impl Cpu {
-    pub const FLAG_OVERFLOW: u8 = 0b00000001;
-    pub const FLAG_ZERO: u8 = 0b00000010;
-    pub const FLAG_EQUAL: u8 = 0b00000100;
-    pub const FLAG_GREATER_THAN: u8 = 0b00001000;
-    pub const FLAG_LOWER_THAN: u8 = 0b00010000;
+    pub const FLAG_OVERFLOW: u16 = 0b0000_0000_0001;
+    /// Set when the result is zero.
+    pub const FLAG_ZERO: u16 = 0b0000_0000_0010;
+    pub const FLAG_EQUAL: u16 = 0b0000_0000_0100;
+    pub const FLAG_GREATER_THAN: u16 = 0b0000_0000_1000;
+    pub const FLAG_LOWER_THAN: u16 = 0b0000_0001_0000;
+    pub const FLAG_INTERRUPT: u16 = 0b0000_0010_0000;
+    /// Carry out of (or borrow into) the top bit, unsigned overflow.
+    pub const FLAG_CARRY: u16 = 0b0000_0100_0000;
+    /// Top bit of the result.
+    pub const FLAG_SIGN: u16 = 0b0000_1000_0000;
+    /// Set when the low byte of the result has an even number of bits set.
+    pub const FLAG_PARITY: u16 = 0b0001_0000_0000;
+
+    /// Flags describing the result of arithmetic, logic and shifts.
+    pub const FLAGS_RESULT: u16 = Self::FLAG_CARRY
+        | Self::FLAG_OVERFLOW
+        | Self::FLAG_ZERO
+        | Self::FLAG_SIGN
+        | Self::FLAG_PARITY;
+    /// Flags set by `cmp`, exactly one of them after each comparison.
+    pub const FLAGS_COMPARE: u16 =
+        Self::FLAG_EQUAL | Self::FLAG_GREATER_THAN | Self::FLAG_LOWER_THAN;
+    pub const FLAGS_ALL: u16 = Self::FLAGS_RESULT | Self::FLAGS_COMPARE | Self::FLAG_INTERRUPT;
 
     pub fn reg_write(&mut self, reg: Reg, value: i16) {
         match reg {
             Reg::AH => self.a = (self.a & MASK_LOW) | (value << 8),
-            Reg::AL => self.a = (self.a & MASK_HIGH) | value,
+            Reg::AL => self.a = (self.a & MASK_HIGH) | (value & MASK_LOW),
             Reg::BH => self.b = (self.b & MASK_LOW) | (value << 8),
-            Reg::BL => self.b = (self.b & MASK_HIGH) | value,
+            Reg::BL => self.b = (self.b & MASK_HIGH) | (value & MASK_LOW),
             Reg::CH => self.c = (self.c & MASK_LOW) | (value << 8),
-            Reg::CL => self.c = (self.c & MASK_HIGH) | value,
+            Reg::CL => self.c = (self.c & MASK_HIGH) | (value & MASK_LOW),
             Reg::DH => self.d = (self.d & MASK_LOW) | (value << 8),
-            Reg::DL => self.d = (self.d & MASK_HIGH) | value,
+            Reg::DL => self.d = (self.d & MASK_HIGH) | (value & MASK_LOW),
             Reg::A => self.a = value,
             Reg::B => self.b = value,
             Reg::C => self.c = value,
@@ -169,13 +1003,13 @@ impl Cpu {
 
     pub fn reg_read(&self, reg: Reg) -> i16 {
         match reg {
-            Reg::AH => self.a >> 8,
+            Reg::AH => (self.a >> 8) & MASK_LOW,
             Reg::AL => self.a & MASK_LOW,
-            Reg::BH => self.b >> 8,
+            Reg::BH => (self.b >> 8) & MASK_LOW,
             Reg::BL => self.b & MASK_LOW,
-            Reg::CH => self.c >> 8,
+            Reg::CH => (self.c >> 8) & MASK_LOW,
             Reg::CL => self.c & MASK_LOW,
-            Reg::DH => self.d >> 8,
+            Reg::DH => (self.d >> 8) & MASK_LOW,
             Reg::DL => self.d & MASK_LOW,
             Reg::A => self.a,
             Reg::B => self.b,
@@ -184,115 +1018,156 @@ impl Cpu {
         }
     }
 
-    pub fn flag_set(&mut self, flag: u8) {
+    pub fn flag_set(&mut self, flag: u16) {
         self.flags |= flag;
     }
 
-    // pub fn flag_unset(&mut self, flag: u8) {
-    //     self.flags &= !flag;
-    // }
+    pub fn flag_unset(&mut self, flag: u16) {
+        self.flags &= !flag;
+    }
+
+    /// Sets `flag` if `on`, clears it otherwise.
+    pub fn flag_update(&mut self, flag: u16, on: bool) {
+        if on {
+            self.flag_set(flag);
+        } else {
+            self.flag_unset(flag);
+        }
+    }
+
+    fn instr_ld(&mut self, val: GenerousInpt, dest: Dest, mem: &mut dyn Bus) -> Result<(), Fault> {
+        // a byte register on either side moves a single byte
+        let byte = matches!(val, GenerousInpt::Register(r) if r.width() == Width::Byte)
+            || matches!(dest, Dest::Register(r) if r.width() == Width::Byte);
 
-    fn instr_ld(&mut self, val: GenerousInpt, dest: Dest, mem: &mut Mem) {
         let val = match val {
             GenerousInpt::Const(c) => c,
             GenerousInpt::Register(r) => self.reg_read(r),
-            GenerousInpt::Memory(i) => mem.read_16(i.into()) as i16,
+            GenerousInpt::Memory(i) if byte => mem.read(self.data_addr(i))? as i16,
+            GenerousInpt::Memory(i) => mem.read_16(self.data_addr(i))? as i16,
         };
 
         match dest {
-            Dest::Memory(i) => mem.write_16(i.into(), val),
+            Dest::Memory(i) if byte => mem.write(self.data_addr(i), val as u8)?,
+            Dest::Memory(i) => mem.write_16(self.data_addr(i), val)?,
             Dest::Register(r) => self.reg_write(r, val),
         }
-    }
 
-    fn instr_sum(&mut self, a: Reg, b: Reg) {
-        let sum;
+        Ok(())
+    }
+
+    /// Sets carry and overflow as given, and zero, sign and parity from
+    /// `result` truncated to `width`.
+    fn set_result_flags(&mut self, width: Width, result: i32, carry: bool, overflow: bool) {
+        let result = result as u32 & width.mask();
+        let flags = [
+            (Self::FLAG_CARRY, carry),
+            (Self::FLAG_OVERFLOW, overflow),
+            (Self::FLAG_ZERO, result == 0),
+            (Self::FLAG_SIGN, result & width.sign_bit() != 0),
+            // set if the low byte has an even number of bits set
+            (
+                Self::FLAG_PARITY,
+                (result as u8).count_ones().is_multiple_of(2),
+            ),
+        ];
+
+        for (flag, on) in flags {
+            self.flag_update(flag, on);
+        }
+    }
 
-        {
-            let a = self.reg_read(a);
-            let b = self.reg_read(b);
+    /// Value written for a signed result of `exact`, which may not fit in
+    /// `width`.
+    fn overflowed(&self, width: Width, exact: i32) -> Result<i16, Fault> {
+        if width.fits(exact) {
+            return Ok(exact as i16);
+        }
 
-            let checksum = a as i32 + b as i32;
-            if checksum > std::i16::MAX as i32 || checksum < std::i16::MIN as i32 {
-                sum = 0;
-                self.flag_set(Self::FLAG_OVERFLOW);
-                // TOOD: propagate warning
-            } else {
-                sum = a + b;
-            }
+        match self.overflow_policy {
+            OverflowPolicy::Wrap => Ok(exact as i16),
+            OverflowPolicy::Saturate => Ok(exact.clamp(width.min(), width.max()) as i16),
+            OverflowPolicy::Zero => Ok(0),
+            OverflowPolicy::Trap => Err(Fault::Overflow),
         }
+    }
+
+    fn instr_sum(&mut self, a: Reg, b: Reg) -> Result<(), Fault> {
+        let w = b.width();
+        let a_val = self.reg_read(a);
+        let b_val = self.reg_read(b);
 
+        let exact = w.signed(a_val) + w.signed(b_val);
+        let carry = w.unsigned(a_val) + w.unsigned(b_val) > w.mask();
+        self.set_result_flags(w, exact, carry, !w.fits(exact));
+
+        let sum = self.overflowed(w, exact)?;
         self.reg_write(b, sum);
+        Ok(())
     }
 
-    fn instr_sub(&mut self, a: Reg, b: Reg) {
-        let sub;
-
-        {
-            let a = self.reg_read(a);
-            let b = self.reg_read(b);
+    fn instr_sub(&mut self, a: Reg, b: Reg) -> Result<(), Fault> {
+        let w = b.width();
+        let a_val = self.reg_read(a);
+        let b_val = self.reg_read(b);
 
-            let checksub = a as i32 - b as i32;
-            if checksub > std::i16::MAX as i32 || checksub < std::i16::MIN as i32 {
-                sub = 0;
-                self.flag_set(Self::FLAG_OVERFLOW);
-                // TOOD: propagate warning
-            } else {
-                sub = a - b;
-            }
-        }
+        let exact = w.signed(a_val) - w.signed(b_val);
+        let borrow = w.unsigned(a_val) < w.unsigned(b_val);
+        self.set_result_flags(w, exact, borrow, !w.fits(exact));
 
+        let sub = self.overflowed(w, exact)?;
         self.reg_write(b, sub);
+        Ok(())
     }
 
-    fn instr_mul(&mut self, a: Reg, b: Reg) {
-        let mul;
-
-        {
-            let a = self.reg_read(a);
-            let b = self.reg_read(b);
-
-            let checkmul = a as i32 * b as i32;
-            if checkmul > std::i16::MAX as i32 || checkmul < std::i16::MIN as i32 {
-                mul = 0;
-                self.flag_set(Self::FLAG_OVERFLOW);
-                // TOOD: propagate warning
-            } else {
-                mul = a * b;
-            }
-        }
+    /// Signed multiply. Like `imul`, carry and overflow are set when the
+    /// product doesn't fit in the destination.
+    fn instr_mul(&mut self, a: Reg, b: Reg) -> Result<(), Fault> {
+        let w = b.width();
+        let exact = w.signed(self.reg_read(a)) * w.signed(self.reg_read(b));
+        let overflow = !w.fits(exact);
+        self.set_result_flags(w, exact, overflow, overflow);
 
+        let mul = self.overflowed(w, exact)?;
         self.reg_write(b, mul);
+        Ok(())
     }
 
-    fn instr_div(&mut self, a: Reg, b: Reg) {
-        let div = {
-            let a = self.reg_read(a);
-            let b = self.reg_read(b);
+    /// Signed divide. `i16::MIN / -1` (or `-128 / -1` for bytes) doesn't fit
+    /// and is treated as an overflow.
+    fn instr_div(&mut self, a: Reg, b: Reg) -> Result<(), Fault> {
+        let w = b.width();
+        let a_val = w.signed(self.reg_read(a));
+        let b_val = w.signed(self.reg_read(b));
+        if b_val == 0 {
+            return Err(Fault::DivideByZero);
+        }
 
-            if b != 0 {
-                a / b
-            } else {
-                self.flag_set(Self::FLAG_ZERO);
-                0
-            }
-        };
+        let exact = a_val / b_val;
+        self.set_result_flags(w, exact, false, !w.fits(exact));
 
-        // division can't be overflown
+        let div = self.overflowed(w, exact)?;
+        self.reg_write(b, div);
+        Ok(())
+    }
 
-        self.reg_write(b, div)
+    /// Logic instructions clear carry and overflow.
+    fn logic_result(&mut self, reg: Reg, result: i16) {
+        self.set_result_flags(reg.width(), result as i32, false, false);
+        self.reg_write(reg, result);
     }
 
     fn instr_and(&mut self, a: Reg, b: Reg) {
         let and = self.reg_read(a) & self.reg_read(b);
-        self.reg_write(b, and);
+        self.logic_result(b, and);
     }
 
     fn instr_or(&mut self, a: Reg, b: Reg) {
         let or = self.reg_read(a) | self.reg_read(b);
-        self.reg_write(b, or);
+        self.logic_result(b, or);
     }
 
+    /// Doesn't affect the flags, like on the 8088.
     fn instr_not(&mut self, a: Reg) {
         let not = !self.reg_read(a);
         self.reg_write(a, not);
@@ -300,38 +1175,77 @@ impl Cpu {
 
     fn instr_xor(&mut self, a: Reg, b: Reg) {
         let xor = self.reg_read(a) ^ self.reg_read(b);
-        self.reg_write(b, xor);
+        self.logic_result(b, xor);
     }
 
-    fn instr_shr(&mut self, sh: Inpt, a: Reg) {
-        let sh = match sh {
+    /// Shifts `a` one bit at a time. Carry is the last bit shifted out,
+    /// overflow is only defined for 1 bit shifts. Shifting by 0 leaves the
+    /// flags alone.
+    fn shift(&mut self, sh: Inpt, a: Reg, left: bool) {
+        let count = match sh {
             Inpt::Const(c) => c,
             Inpt::Register(r) => self.reg_read(r),
-        };
-        let val = self.reg_read(a) >> sh;
-        self.reg_write(a, val);
-    }
+        } as u16;
+        if count == 0 {
+            return;
+        }
 
-    fn instr_shl(&mut self, sh: Inpt, a: Reg) {
-        let sh = match sh {
-            Inpt::Const(c) => c,
-            Inpt::Register(r) => self.reg_read(r),
-        };
-        let val = self.reg_read(a) << sh;
-        self.reg_write(a, val);
+        let w = a.width();
+        let sign = w.sign_bit();
+        let mut val = w.unsigned(self.reg_read(a));
+        let mut carry = false;
+        // past bits + 1 shifts, neither the value nor the carry change anymore
+        for _ in 0..count.min(w.bits() as u16 + 1) {
+            if left {
+                carry = val & sign != 0;
+                val = (val << 1) & w.mask();
+            } else {
+                carry = val & 1 != 0;
+                val = (val >> 1) | (val & sign);
+            }
+        }
+
+        // `shr` keeps the sign bit, so it never overflows
+        let overflow = left && count == 1 && (val & sign != 0) != carry;
+        self.set_result_flags(w, val as i32, carry, overflow);
+        self.reg_write(a, val as i16);
+    }
+
+    /// Arithmetic shift, the sign bit is kept.
+    fn instr_shr(&mut self, sh: Inpt, a: Reg) {
+        self.shift(sh, a, false);
+    }
+
+    fn instr_shl(&mut self, sh: Inpt, a: Reg) {
+        self.shift(sh, a, true);
     }
 
+    /// Signed comparison at the width of `b`.
     fn instr_cmp(&mut self, a: Reg, b: Reg) {
-        let a = self.reg_read(a);
-        let b = self.reg_read(b);
+        let w = b.width();
+        let a_val = self.reg_read(a);
+        let b_val = self.reg_read(b);
 
-        if a > b {
-            self.flag_set(Self::FLAG_GREATER_THAN);
-        } else if a < b {
-            self.flag_set(Self::FLAG_LOWER_THAN);
-        } else {
-            self.flag_set(Self::FLAG_EQUAL);
-        }
+        let exact = w.signed(a_val) - w.signed(b_val);
+        let borrow = w.unsigned(a_val) < w.unsigned(b_val);
+        self.set_result_flags(w, exact, borrow, !w.fits(exact));
+
+        self.flag_update(Self::FLAG_GREATER_THAN, exact > 0);
+        self.flag_update(Self::FLAG_LOWER_THAN, exact < 0);
+        self.flag_update(Self::FLAG_EQUAL, exact == 0);
+    }
+
+    /// Sign extends the low byte of `a` into `b`, `sext al a` is the 8088's
+    /// `cbw`.
+    fn instr_sext(&mut self, a: Reg, b: Reg) {
+        let val = self.reg_read(a) as i8;
+        self.reg_write(b, val as i16);
+    }
+
+    /// Zero extends the low byte of `a` into `b`.
+    fn instr_zext(&mut self, a: Reg, b: Reg) {
+        let val = self.reg_read(a) as u8;
+        self.reg_write(b, val as i16);
     }
 
     fn instr_jmp(&mut self, to: Inpt) {
@@ -385,53 +1299,725 @@ impl Cpu {
         }
     }
 
-    fn instr_push(&mut self, val: Inpt, mem: &mut Mem) {
+    /// Address of a data operand, in DS unless a `seg` prefix overrides it.
+    fn data_addr(&self, offset: u16) -> usize {
+        let seg = self.seg_override.unwrap_or(SegReg::DS);
+        physical(self.seg_read(seg), offset)
+    }
+
+    /// Bytes currently on the stack.
+    fn stack_used(&self) -> u16 {
+        self.stack_top.wrapping_sub(self.sp)
+    }
+
+    /// Like on the 8088, `sp` is decremented and then the value is written
+    /// at `ss:sp`.
+    fn stack_push(&mut self, val: i16, mem: &mut dyn Bus) -> Result<(), Fault> {
+        self.stack_room(1)?;
+
+        let sp = self.sp.wrapping_sub(2);
+        mem.protect(physical(self.ss, sp), 2, Perms::STACK)?;
+        mem.write_16(physical(self.ss, sp), val)?;
+        self.sp = sp;
+        Ok(())
+    }
+
+    /// Faults unless `words` more words can be pushed. Instructions pushing
+    /// several words check first so a fault leaves the stack untouched.
+    fn stack_room(&self, words: u32) -> Result<(), Fault> {
+        if self.stack_used() as u32 + 2 * words > self.stack_size as u32 {
+            return Err(Fault::StackOverflow);
+        }
+        Ok(())
+    }
+
+    /// Faults unless `words` words can be popped.
+    fn stack_holds(&self, words: u32) -> Result<(), Fault> {
+        if (self.stack_used() as u32) < 2 * words {
+            return Err(Fault::StackUnderflow);
+        }
+        Ok(())
+    }
+
+    fn stack_pop(&mut self, mem: &mut dyn Bus) -> Result<i16, Fault> {
+        self.stack_holds(1)?;
+
+        let val = mem.read_16(physical(self.ss, self.sp))? as i16;
+        self.sp = self.sp.wrapping_add(2);
+        Ok(val)
+    }
+
+    fn instr_push(&mut self, val: Inpt, mem: &mut dyn Bus) -> Result<(), Fault> {
         let val = match val {
             Inpt::Const(c) => c,
             Inpt::Register(r) => self.reg_read(r),
         };
 
-        if self.sp - self.ss == self.stack_size {
-            self.sp = 0;
-            self.flag_set(Self::FLAG_OVERFLOW);
-        } else {
-            mem.write_16(self.sp.into(), val);
-            self.sp += 2;
+        self.stack_push(val, mem)
+    }
+
+    fn instr_pop(&mut self, reg: Reg, mem: &mut dyn Bus) -> Result<(), Fault> {
+        let val = self.stack_pop(mem)?;
+        self.reg_write(reg, val);
+        Ok(())
+    }
+
+    /// Pushes `ip` and jumps. When run through `step`, `ip` already points
+    /// to the instruction after the call.
+    fn instr_call(&mut self, to: Inpt, mem: &mut dyn Bus) -> Result<(), Fault> {
+        let to = match to {
+            Inpt::Const(c) => c,
+            Inpt::Register(r) => self.reg_read(r),
+        } as u16;
+
+        self.stack_push(self.ip as i16, mem)?;
+        self.ip = to;
+        Ok(())
+    }
+
+    fn instr_ret(&mut self, mem: &mut dyn Bus) -> Result<(), Fault> {
+        self.ip = self.stack_pop(mem)? as u16;
+        Ok(())
+    }
+
+    fn instr_ld_seg(&mut self, val: Inpt, seg: SegReg) {
+        let val = match val {
+            Inpt::Const(c) => c,
+            Inpt::Register(r) => self.reg_read(r),
+        };
+
+        self.seg_write(seg, val as u16);
+    }
+
+    fn instr_jmp_far(&mut self, seg: Inpt, to: Inpt) {
+        (self.cs, self.ip) = self.far_target(seg, to);
+    }
+
+    fn far_target(&self, seg: Inpt, to: Inpt) -> (u16, u16) {
+        let val = |i| match i {
+            Inpt::Const(c) => c as u16,
+            Inpt::Register(r) => self.reg_read(r) as u16,
+        };
+
+        (val(seg), val(to))
+    }
+
+    /// Pushes `cs` then `ip`, and jumps to `seg:to`.
+    fn instr_call_far(&mut self, seg: Inpt, to: Inpt, mem: &mut dyn Bus) -> Result<(), Fault> {
+        let (seg, to) = self.far_target(seg, to);
+
+        self.stack_room(2)?;
+        self.stack_push(self.cs as i16, mem)?;
+        self.stack_push(self.ip as i16, mem)?;
+        self.cs = seg;
+        self.ip = to;
+        Ok(())
+    }
+
+    fn instr_ret_far(&mut self, mem: &mut dyn Bus) -> Result<(), Fault> {
+        self.stack_holds(2)?;
+        let ip = self.stack_pop(mem)? as u16;
+        let cs = self.stack_pop(mem)? as u16;
+        self.ip = ip;
+        self.cs = cs;
+        Ok(())
+    }
+
+    fn instr_popf(&mut self, mem: &mut dyn Bus) -> Result<(), Fault> {
+        self.flags = self.stack_pop(mem)? as u16 & Self::FLAGS_ALL;
+        Ok(())
+    }
+
+    fn instr_iret(&mut self, mem: &mut dyn Bus) -> Result<(), Fault> {
+        self.stack_holds(3)?;
+        let ip = self.stack_pop(mem)? as u16;
+        let cs = self.stack_pop(mem)? as u16;
+        let flags = self.stack_pop(mem)? as u16;
+        self.ip = ip;
+        self.cs = cs;
+        self.flags = flags & Self::FLAGS_ALL;
+        Ok(())
+    }
+
+    /// Enters the handler of the highest priority pending IRQ, like a far
+    /// `call` but pushing `flags` first and disabling interrupts. Nothing is
+    /// delivered between a `seg` prefix and its instruction. If the frame
+    /// can't be pushed the IRQ stays pending and nothing changes.
+    fn interrupt(&mut self, mem: &mut dyn Bus) -> Result<(), Fault> {
+        if self.flags & Self::FLAG_INTERRUPT == 0 || self.seg_override.is_some() {
+            return Ok(());
+        }
+        let line = match self.pic.next() {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+
+        self.stack_room(3)?;
+        let (cs, ip) = mem.vector(self.pic.base() + line)?;
+        self.pic.acknowledge();
+        self.stack_push(self.flags as i16, mem)?;
+        self.stack_push(self.cs as i16, mem)?;
+        self.stack_push(self.ip as i16, mem)?;
+        self.flag_unset(Self::FLAG_INTERRUPT);
+        self.cs = cs;
+        self.ip = ip;
+        self.cycles += self.timing.interrupt;
+        Ok(())
+    }
+
+    /// Whether something could still end a `hlt`.
+    fn may_wake_up(&self) -> bool {
+        self.exit_status.is_none()
+            && self.flags & Self::FLAG_INTERRUPT != 0
+            && (self.pic.next().is_some() || !self.scheduler.is_empty())
+    }
+
+    /// After `hlt` the cpu sleeps until an IRQ comes in, if interrupts are
+    /// enabled. Time jumps to the next scheduled event while it waits.
+    /// Returns whether it woke up.
+    fn wake_up(&mut self, mem: &mut dyn Bus) -> bool {
+        if self.exit_status.is_some() || self.flags & Self::FLAG_INTERRUPT == 0 {
+            return false;
+        }
+
+        while self.pic.next().is_none() {
+            match self.scheduler.next() {
+                Some(at) => {
+                    self.cycles = self.cycles.max(at);
+                    for event in self.scheduler.due(self.cycles) {
+                        event.fire(self, mem);
+                    }
+                }
+                None => return false,
+            }
+
+            if self.exit_status.is_some() {
+                return false;
+            }
         }
+
+        self.halted = false;
+        true
     }
 
-    fn instr_pop(&mut self, reg: Reg, mem: &Mem) {
-        if self.sp == self.ss {
-            self.sp = 0;
-            self.flag_set(Self::FLAG_OVERFLOW);
-        } else {
-            let v = mem.read_16(self.sp.into()) as i16;
-            self.reg_write(reg, v);
-            self.sp -= 2;
+    /// Runs the handler registered for the number in `A`.
+    fn instr_syscall(&mut self, mem: &mut dyn Bus) -> Result<(), Fault> {
+        let num = self.reg_read(Reg::A) as u16;
+        let mut handler = self
+            .syscalls
+            .remove(&num)
+            .ok_or(Fault::UnknownSyscall(num))?;
+
+        let res = handler.call(self, mem);
+        self.syscalls.insert(num, handler);
+        res
+    }
+
+    fn point(&self, reg: Reg) -> (u16, u16) {
+        let p = self.reg_read(reg) as u16;
+        (p >> 8, p & 0xff)
+    }
+
+    /// Framebuffer to draw on, along with the color in the low byte of `color`.
+    fn canvas(&mut self, color: Reg) -> Result<(&mut Framebuffer, u8), Fault> {
+        let color = self.reg_read(color) as u8;
+        let fb = self.framebuffer.as_mut().ok_or(Fault::NoFramebuffer)?;
+
+        if !fb.is_color(color) {
+            return Err(Fault::InvalidColor(color));
         }
+
+        Ok((fb, color))
+    }
+
+    fn instr_pxl(&mut self, at: Reg, color: Reg) -> Result<(), Fault> {
+        let (x, y) = self.point(at);
+        let (fb, color) = self.canvas(color)?;
+        fb.set_pixel(x, y, color);
+        Ok(())
+    }
+
+    fn instr_rect(&mut self, at: Reg, size: Reg, color: Reg) -> Result<(), Fault> {
+        let (x, y) = self.point(at);
+        let (w, h) = self.point(size);
+        let (fb, color) = self.canvas(color)?;
+        fb.fill_rect(x, y, w, h, color);
+        Ok(())
+    }
+
+    fn instr_line(&mut self, from: Reg, to: Reg, color: Reg) -> Result<(), Fault> {
+        let (x0, y0) = self.point(from);
+        let (x1, y1) = self.point(to);
+        let (fb, color) = self.canvas(color)?;
+        fb.draw_line(x0, y0, x1, y1, color);
+        Ok(())
+    }
+
+    fn instr_cls(&mut self, color: Reg) -> Result<(), Fault> {
+        let (fb, color) = self.canvas(color)?;
+        fb.clear(color);
+        Ok(())
     }
 
-    pub fn execute(&mut self, instr: Instruction, mem: &mut Mem) {
+    /// Executes `instr`, which can only change the flags it's documented to
+    /// write in `Instruction::flags_written`. The flags are left alone if it
+    /// faults.
+    pub fn execute(&mut self, instr: Instruction, mem: &mut dyn Bus) -> Result<(), Fault> {
+        let before = self.flags;
+        let result = self.dispatch(instr, mem);
+
+        let written = if result.is_ok() {
+            instr.flags_written()
+        } else {
+            0
+        };
+        self.flags = (before & !written) | (self.flags & written);
+        result
+    }
+
+    fn dispatch(&mut self, instr: Instruction, mem: &mut dyn Bus) -> Result<(), Fault> {
         match instr {
-            Instruction::Ld(val, dest) => self.instr_ld(val, dest, mem),
-            Instruction::Sum(a, b) => self.instr_sum(a, b),
-            Instruction::Sub(a, b) => self.instr_sub(a, b),
-            Instruction::Mul(a, b) => self.instr_mul(a, b),
-            Instruction::Div(a, b) => self.instr_div(a, b),
+            Instruction::Ld(val, dest) => return self.instr_ld(val, dest, mem),
+            Instruction::Sum(a, b) => return self.instr_sum(a, b),
+            Instruction::Sub(a, b) => return self.instr_sub(a, b),
+            Instruction::Mul(a, b) => return self.instr_mul(a, b),
+            Instruction::Div(a, b) => return self.instr_div(a, b),
             Instruction::And(a, b) => self.instr_and(a, b),
             Instruction::Or(a, b) => self.instr_or(a, b),
             Instruction::Not(a) => self.instr_not(a),
             Instruction::Xor(a, b) => self.instr_xor(a, b),
             Instruction::Shr(a, b) => self.instr_shr(a, b),
             Instruction::Shl(a, b) => self.instr_shl(a, b),
+            Instruction::Sext(a, b) => self.instr_sext(a, b),
+            Instruction::Zext(a, b) => self.instr_zext(a, b),
             Instruction::Cmp(a, b) => self.instr_cmp(a, b),
             Instruction::Jmp(to) => self.instr_jmp(to),
             Instruction::Jeq(to) => self.instr_jeq(to),
             Instruction::Jne(to) => self.instr_jne(to),
             Instruction::Jgt(to) => self.instr_jgt(to),
             Instruction::Jlt(to) => self.instr_jlt(to),
-            Instruction::Push(val) => self.instr_push(val, mem),
-            Instruction::Pop(r) => self.instr_pop(r, mem),
+            Instruction::Push(val) => return self.instr_push(val, mem),
+            Instruction::Pop(r) => return self.instr_pop(r, mem),
+            Instruction::Call(to) => return self.instr_call(to, mem),
+            Instruction::Ret => return self.instr_ret(mem),
+            Instruction::Pxl(at, color) => return self.instr_pxl(at, color),
+            Instruction::Rect(at, size, color) => return self.instr_rect(at, size, color),
+            Instruction::Line(from, to, color) => return self.instr_line(from, to, color),
+            Instruction::Cls(color) => return self.instr_cls(color),
+            Instruction::Hlt => self.halted = true,
+            Instruction::Syscall => return self.instr_syscall(mem),
+            Instruction::Cli => self.flag_unset(Self::FLAG_INTERRUPT),
+            Instruction::Sti => self.flag_set(Self::FLAG_INTERRUPT),
+            Instruction::Iret => return self.instr_iret(mem),
+            Instruction::Pushf => return self.stack_push(self.flags as i16, mem),
+            Instruction::Popf => return self.instr_popf(mem),
+            Instruction::LdSeg(val, seg) => self.instr_ld_seg(val, seg),
+            Instruction::StSeg(seg, r) => self.reg_write(r, self.seg_read(seg) as i16),
+            Instruction::Seg(seg) => self.seg_override = Some(seg),
+            Instruction::JmpFar(seg, to) => self.instr_jmp_far(seg, to),
+            Instruction::CallFar(seg, to) => return self.instr_call_far(seg, to, mem),
+            Instruction::RetFar => return self.instr_ret_far(mem),
+        }
+
+        Ok(())
+    }
+
+    pub fn ip(&self) -> u16 {
+        self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: u16) {
+        self.ip = ip;
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    pub fn ss(&self) -> u16 {
+        self.ss
+    }
+
+    pub fn seg_read(&self, seg: SegReg) -> u16 {
+        match seg {
+            SegReg::CS => self.cs,
+            SegReg::DS => self.ds,
+            SegReg::SS => self.ss,
+            SegReg::ES => self.es,
+        }
+    }
+
+    pub fn seg_write(&mut self, seg: SegReg, val: u16) {
+        match seg {
+            SegReg::CS => self.cs = val,
+            SegReg::DS => self.ds = val,
+            SegReg::SS => self.ss = val,
+            SegReg::ES => self.es = val,
+        }
+    }
+
+    /// Physical address of the next instruction, `cs:ip`.
+    pub fn pc(&self) -> usize {
+        physical(self.cs, self.ip)
+    }
+
+    /// Offset in SS right above the stack, `sp` when it's empty.
+    pub fn stack_top(&self) -> u16 {
+        self.stack_top
+    }
+
+    pub fn stack_size(&self) -> u16 {
+        self.stack_size
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    /// Places an empty stack of `size` bytes right below offset `top` of SS.
+    /// A `top` of 0 puts it at the end of the segment.
+    pub fn set_stack(&mut self, top: u16, size: u16) {
+        self.stack_top = top;
+        self.sp = top;
+        self.stack_size = size;
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Halts the cpu, `run` then reports `StopReason::Exit(status)`.
+    pub fn exit(&mut self, status: i16) {
+        self.halted = true;
+        self.exit_status = Some(status);
+    }
+
+    /// Registers `handler` for syscall `num`, replacing any previous one.
+    pub fn register_syscall(&mut self, num: u16, handler: Box<dyn SyscallHandler>) {
+        self.syscalls.insert(num, handler);
+    }
+
+    pub fn attach_framebuffer(&mut self, fb: Framebuffer) {
+        self.framebuffer = Some(fb);
+    }
+
+    pub fn detach_framebuffer(&mut self) -> Option<Framebuffer> {
+        self.framebuffer.take()
+    }
+
+    pub fn framebuffer(&self) -> Option<&Framebuffer> {
+        self.framebuffer.as_ref()
+    }
+
+    pub fn attach_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn detach_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// Cycles spent since the cpu was created.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn timing(&self) -> &CycleTable {
+        &self.timing
+    }
+
+    pub fn set_timing(&mut self, timing: CycleTable) {
+        self.timing = timing;
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow_policy = policy;
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            flags: self.flags,
+            ip: self.ip,
+            sp: self.sp,
+            cs: self.cs,
+            ds: self.ds,
+            ss: self.ss,
+            es: self.es,
+            seg_override: self.seg_override,
+            stack_top: self.stack_top,
+            stack_size: self.stack_size,
+            halted: self.halted,
+            exit_status: self.exit_status,
+            cycles: self.cycles,
+            overflow_policy: self.overflow_policy,
+            irq_pending: self.pic.pending(),
+            irq_mask: self.pic.mask_bits(),
+        }
+    }
+
+    /// Puts the cpu back in `state`, keeping its configuration.
+    pub fn restore(&mut self, state: &CpuState) {
+        self.a = state.a;
+        self.b = state.b;
+        self.c = state.c;
+        self.d = state.d;
+        self.flags = state.flags;
+        self.ip = state.ip;
+        self.sp = state.sp;
+        self.cs = state.cs;
+        self.ds = state.ds;
+        self.ss = state.ss;
+        self.es = state.es;
+        self.seg_override = state.seg_override;
+        self.stack_top = state.stack_top;
+        self.stack_size = state.stack_size;
+        self.halted = state.halted;
+        self.exit_status = state.exit_status;
+        self.cycles = state.cycles;
+        self.overflow_policy = state.overflow_policy;
+        self.pic.restore(state.irq_pending, state.irq_mask);
+    }
+
+    /// Fires `event` once `delay` more cycles have been spent, after the
+    /// instruction that reaches it. Returns the cycle it's due at.
+    pub fn schedule(&mut self, delay: u64, event: Box<dyn Event>) -> u64 {
+        let at = self.cycles + delay;
+        self.scheduler.schedule(at, event);
+        at
+    }
+
+    fn halt_reason(&self) -> StopReason {
+        match self.exit_status {
+            Some(status) => StopReason::Exit(status),
+            None => StopReason::Halt,
+        }
+    }
+
+    pub fn pic(&self) -> &Pic {
+        &self.pic
+    }
+
+    pub fn pic_mut(&mut self) -> &mut Pic {
+        &mut self.pic
+    }
+
+    /// Breakpoints are physical addresses, see `pc`.
+    pub fn add_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Fetches the instruction at `cs:ip`, moves `ip` past it and executes it.
+    /// Returns `None` if the cpu can keep going. On a fault `ip` is left
+    /// pointing at the faulting instruction, after a watchpoint it points to
+    /// the next one.
+    ///
+    /// With history enabled, a step that changes anything can be undone with
+    /// `step_back`.
+    pub fn step(&mut self, mem: &mut dyn Bus) -> Option<StopReason> {
+        self.stopped_at = None;
+        let before = self.history.as_ref().map(|_| self.state());
+        let journaling = before.is_some() || self.tracer.is_some();
+        if journaling {
+            mem.start_journal();
+        }
+
+        let mut writes = Vec::new();
+        let reason = self.step_once(mem, &mut writes);
+        if journaling {
+            writes.extend(mem.stop_journal());
+        }
+
+        if let Some(state) = before {
+            if !writes.is_empty() || state != self.state() {
+                if let Some(history) = self.history.as_mut() {
+                    history.push(Undo { state, writes });
+                }
+            }
+        }
+
+        reason
+    }
+
+    /// `step`, adding what it takes from the memory journal to `writes`.
+    fn step_once(&mut self, mem: &mut dyn Bus, writes: &mut Vec<MemWrite>) -> Option<StopReason> {
+        if self.halted && !self.wake_up(mem) {
+            return Some(self.halt_reason());
+        }
+
+        mem.take_watch_hits();
+
+        if let Err(fault) = self.interrupt(mem) {
+            return Some(StopReason::Fault(fault));
+        }
+
+        let (start_cs, start) = (self.cs, self.ip);
+        let (instr, len) = match encoding::decode(&*mem, self.pc()) {
+            Ok(decoded) => decoded,
+            Err(e) => return Some(StopReason::Fault(Fault::Decode(e))),
+        };
+
+        // every byte of the instruction has to be executable, not just the
+        // opcode
+        if let Err(fault) = mem.protect(self.pc(), len, Perms::EXECUTE) {
+            return Some(StopReason::Fault(Fault::Mem(fault)));
+        }
+
+        // the journal is on while tracing, what's in it so far was written
+        // when entering an interrupt handler
+        let before = self.tracer.as_ref().map(|_| Snapshot::of(self));
+        if before.is_some() {
+            writes.extend(mem.take_journal());
+        }
+
+        self.ip = start.wrapping_add(len as u16);
+        let result = self.execute(instr, mem);
+        let traced = match before {
+            Some(_) => mem.take_journal(),
+            None => Vec::new(),
+        };
+        writes.extend_from_slice(&traced);
+
+        if let Err(fault) = result {
+            (self.cs, self.ip) = (start_cs, start);
+            mem.take_watch_hits();
+            return Some(StopReason::Fault(fault));
+        }
+
+        if !matches!(instr, Instruction::Seg(_)) {
+            self.seg_override = None;
+        }
+
+        if let Some(before) = before {
+            let entry = TraceEntry::new(start, instr, before, self, traced);
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.record(&entry);
+            }
+        }
+
+        let taken = (self.cs, self.ip) != (start_cs, start.wrapping_add(len as u16));
+        self.cycles += self.timing.cost(&instr, taken);
+        for event in self.scheduler.due(self.cycles) {
+            event.fire(self, mem);
+        }
+
+        if let Some(&hit) = mem.take_watch_hits().first() {
+            return Some(StopReason::Watchpoint(hit));
+        }
+
+        if self.halted && !self.may_wake_up() {
+            Some(self.halt_reason())
+        } else {
+            None
+        }
+    }
+
+    /// Steps through at most `limit` instructions.
+    ///
+    /// Breakpoints are checked before every fetch, including the first one,
+    /// except for the breakpoint that just stopped the cpu: calling `run`
+    /// again after hitting it continues past it.
+    pub fn run(&mut self, mem: &mut dyn Bus, limit: usize) -> StopReason {
+        self.run_while(mem, |_, i| i < limit)
+    }
+
+    /// Same as `run` but stops once `budget` cycles have been spent. The
+    /// last instruction may go past the budget.
+    pub fn run_cycles(&mut self, mem: &mut dyn Bus, budget: u64) -> StopReason {
+        let end = self.cycles + budget;
+        self.run_while(mem, |cpu, _| cpu.cycles < end)
+    }
+
+    fn run_while(&mut self, mem: &mut dyn Bus, go_on: impl Fn(&Cpu, usize) -> bool) -> StopReason {
+        let mut i = 0;
+        while go_on(self, i) {
+            let pc = self.pc();
+            if self.breakpoints.contains(&pc) && self.stopped_at != Some(pc) {
+                self.stopped_at = Some(pc);
+                return StopReason::Breakpoint(pc);
+            }
+
+            if let Some(reason) = self.step(mem) {
+                return reason;
+            }
+            i += 1;
+        }
+
+        StopReason::Limit
+    }
+
+    /// Starts recording the last `limit` steps so they can be undone,
+    /// forgetting any previous history.
+    pub fn enable_history(&mut self, limit: usize) {
+        self.history = Some(History::new(limit));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Undoes the last recorded step, except for what it drew on the
+    /// framebuffer. Returns false if there's none.
+    pub fn step_back(&mut self, mem: &mut dyn Bus) -> bool {
+        let Some(undo) = self.history.as_mut().and_then(|h| h.pop()) else {
+            return false;
+        };
+
+        // a step may write the same byte twice, the oldest value goes last
+        for w in undo.writes.iter().rev() {
+            // the byte was written before, so it can be written back
+            let _ = mem.poke(w.addr, w.old);
+        }
+        self.restore(&undo.state);
+        self.stopped_at = None;
+        true
+    }
+
+    /// Steps back until a breakpoint, like `run` backwards. The breakpoint
+    /// the cpu may be sitting at doesn't count.
+    pub fn reverse_continue(&mut self, mem: &mut dyn Bus) -> ReverseStop {
+        while self.step_back(mem) {
+            if self.breakpoints.contains(&self.pc()) {
+                self.stopped_at = Some(self.pc());
+                return ReverseStop::Breakpoint(self.pc());
+            }
+        }
+
+        ReverseStop::Start
+    }
+
+    /// Steps back until right before the last instruction that wrote the
+    /// byte at `addr`.
+    pub fn back_to_write(&mut self, mem: &mut dyn Bus, addr: usize) -> ReverseStop {
+        loop {
+            let wrote = match self.history.as_ref().and_then(|h| h.last()) {
+                Some(undo) => undo.wrote(addr),
+                None => return ReverseStop::Start,
+            };
+
+            self.step_back(mem);
+            if wrote {
+                return ReverseStop::Write(self.pc());
+            }
         }
     }
 }
@@ -439,6 +2025,7 @@ impl Cpu {
 #[cfg(test)]
 mod instruction_tests {
     use super::*;
+    use crate::graphics::DEFAULT_PALETTE;
 
     impl Cpu {
         fn vals(a: i16, b: i16, c: i16) -> Self {
@@ -453,7 +2040,10 @@ mod instruction_tests {
 
     impl Mem {
         fn set(v: Vec<u8>) -> Self {
-            Mem { array: v }
+            Mem {
+                storage: Storage::Dense(v),
+                ..Mem::new(0)
+            }
         }
     }
 
@@ -464,32 +2054,149 @@ mod instruction_tests {
         cpu.execute(
             Instruction::Ld(GenerousInpt::Const(10), Dest::Register(Reg::AL)),
             &mut mem,
-        );
+        )
+        .unwrap();
         cpu.execute(
             Instruction::Ld(GenerousInpt::Const(1), Dest::Register(Reg::AH)),
             &mut mem,
-        );
+        )
+        .unwrap();
 
         assert_eq!(cpu.a, (1 << 8) | 10);
         assert_eq!(cpu.flags, 0);
     }
 
     #[test]
-    fn ld_abc_16() {
-        let mut cpu = Cpu::default();
-        let mut mem = Mem::default();
+    fn byte_registers_truncate() {
+        let mut cpu = Cpu::vals(0x1234, 0, 0);
+
+        cpu.reg_write(Reg::AL, -1);
+        assert_eq!(cpu.a, 0x12ff);
+        cpu.reg_write(Reg::AH, 0x1ff);
+        assert_eq!(cpu.a as u16, 0xffff);
+
+        // no sign extension when reading either half
+        assert_eq!(cpu.reg_read(Reg::AH), 0xff);
+        assert_eq!(cpu.reg_read(Reg::AL), 0xff);
+    }
+
+    #[test]
+    fn ld_bytes_from_and_to_mem() {
+        let mut cpu = Cpu::vals(0x1234, 0, 0);
+        let mut mem = Mem::set(vec![0xab, 0xcd, 0, 0]);
+
         cpu.execute(
-            Instruction::Ld(GenerousInpt::Const(-5), Dest::Register(Reg::A)),
+            Instruction::Ld(GenerousInpt::Memory(1), Dest::Register(Reg::AL)),
             &mut mem,
-        );
+        )
+        .unwrap();
+        assert_eq!(cpu.a, 0x12cd);
+
         cpu.execute(
-            Instruction::Ld(GenerousInpt::Const(1), Dest::Register(Reg::B)),
+            Instruction::Ld(GenerousInpt::Register(Reg::AH), Dest::Memory(3)),
             &mut mem,
+        )
+        .unwrap();
+        assert_eq!(mem.read(2), Ok(0));
+        assert_eq!(mem.read(3), Ok(0x12));
+    }
+
+    #[test]
+    fn byte_arithmetic() {
+        let mut cpu = Cpu::vals(0x10ff, 0x2001, 0);
+        let mut mem = Mem::default();
+        cpu.set_overflow_policy(OverflowPolicy::Wrap);
+
+        // 0xff + 0x01 carries out of the byte, bh is left alone
+        cpu.execute(Instruction::Sum(Reg::AL, Reg::BL), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.b, 0x2000);
+        assert_eq!(
+            cpu.flags,
+            Cpu::FLAG_CARRY | Cpu::FLAG_ZERO | Cpu::FLAG_PARITY
         );
-        cpu.execute(
+
+        // 0x7f + 1 overflows a byte
+        cpu.reg_write(Reg::CL, 0x7f);
+        cpu.reg_write(Reg::BL, 1);
+        cpu.execute(Instruction::Sum(Reg::BL, Reg::CL), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.reg_read(Reg::CL), 0x80);
+        assert_eq!(cpu.c, 0x80);
+        assert_eq!(cpu.flags, Cpu::FLAG_OVERFLOW | Cpu::FLAG_SIGN);
+
+        // 0xff is -1, lower than 1
+        cpu.execute(Instruction::Cmp(Reg::AL, Reg::BL), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.flags & Cpu::FLAGS_COMPARE, Cpu::FLAG_LOWER_THAN);
+
+        cpu.execute(Instruction::Shl(Inpt::Const(1), Reg::CL), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.c, 0);
+        assert!(cpu.flags & Cpu::FLAG_CARRY != 0);
+    }
+
+    #[test]
+    fn byte_overflow_policies() {
+        let mut cpu = Cpu::vals(0x7f, 0x7f, 0);
+        let mut mem = Mem::default();
+
+        cpu.set_overflow_policy(OverflowPolicy::Saturate);
+        cpu.execute(Instruction::Sum(Reg::AL, Reg::BL), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.b, 0x7f);
+
+        cpu.reg_write(Reg::AL, -128);
+        cpu.execute(Instruction::Sub(Reg::AL, Reg::BL), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.b, 0x80);
+
+        cpu.set_overflow_policy(OverflowPolicy::Trap);
+        assert_eq!(
+            cpu.execute(Instruction::Mul(Reg::AL, Reg::BL), &mut mem),
+            Err(Fault::Overflow)
+        );
+    }
+
+    #[test]
+    fn sign_and_zero_extend() {
+        let mut cpu = Cpu::vals(0x1280, 0, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Sext(Reg::AL, Reg::A), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.a, -128);
+
+        cpu.execute(Instruction::Zext(Reg::AL, Reg::B), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.b, 0x80);
+
+        cpu.reg_write(Reg::AL, 0x7f);
+        cpu.execute(Instruction::Sext(Reg::AL, Reg::C), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.c, 0x7f);
+        assert_eq!(cpu.flags, 0);
+    }
+
+    #[test]
+    fn ld_abc_16() {
+        let mut cpu = Cpu::default();
+        let mut mem = Mem::default();
+        cpu.execute(
+            Instruction::Ld(GenerousInpt::Const(-5), Dest::Register(Reg::A)),
+            &mut mem,
+        )
+        .unwrap();
+        cpu.execute(
+            Instruction::Ld(GenerousInpt::Const(1), Dest::Register(Reg::B)),
+            &mut mem,
+        )
+        .unwrap();
+        cpu.execute(
             Instruction::Ld(GenerousInpt::Const(2020), Dest::Register(Reg::C)),
             &mut mem,
-        );
+        )
+        .unwrap();
 
         assert_eq!(cpu.a, -5);
         assert_eq!(cpu.b, 1);
@@ -504,10 +2211,11 @@ mod instruction_tests {
         cpu.execute(
             Instruction::Ld(GenerousInpt::Const(-5), Dest::Memory(0)),
             &mut mem,
-        );
+        )
+        .unwrap();
 
-        assert_eq!(mem.read(0), 255);
-        assert_eq!(mem.read(1), 251);
+        assert_eq!(mem.read(0), Ok(255));
+        assert_eq!(mem.read(1), Ok(251));
         assert_eq!(cpu.flags, 0);
     }
 
@@ -518,15 +2226,18 @@ mod instruction_tests {
         cpu.execute(
             Instruction::Ld(GenerousInpt::Memory(0), Dest::Register(Reg::A)),
             &mut mem,
-        );
+        )
+        .unwrap();
         cpu.execute(
             Instruction::Ld(GenerousInpt::Memory(2), Dest::Register(Reg::B)),
             &mut mem,
-        );
+        )
+        .unwrap();
         cpu.execute(
             Instruction::Ld(GenerousInpt::Memory(4), Dest::Register(Reg::C)),
             &mut mem,
-        );
+        )
+        .unwrap();
 
         assert_eq!(cpu.a, 2);
         assert_eq!(cpu.b, 4);
@@ -538,8 +2249,10 @@ mod instruction_tests {
     fn sum_within_16_bits() {
         let mut cpu = Cpu::vals(0, -3, 4);
         let mut mem = Mem::default();
-        cpu.execute(Instruction::Sum(Reg::A, Reg::B), &mut mem);
-        cpu.execute(Instruction::Sum(Reg::C, Reg::A), &mut mem);
+        cpu.execute(Instruction::Sum(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Sum(Reg::C, Reg::A), &mut mem)
+            .unwrap();
 
         assert_eq!(cpu.b, -3);
         assert_eq!(cpu.a, 4);
@@ -547,351 +2260,1750 @@ mod instruction_tests {
     }
 
     #[test]
-    fn sum_with_overflow() {
-        let mut cpu = Cpu::vals(32767, 4, 0);
+    fn sum_with_overflow() {
+        let mut cpu = Cpu::vals(32767, 4, 0);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Sum(Reg::B, Reg::A), &mut mem)
+            .unwrap();
+
+        assert_eq!(cpu.a, 0);
+        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+    }
+
+    #[test]
+    fn sum_of_negatives_with_overflow() {
+        let mut cpu = Cpu::vals(-32767, -4, 0);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Sum(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+
+        assert_eq!(cpu.b, 0);
+        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+    }
+
+    #[test]
+    fn sub_within_16_bits() {
+        let mut cpu = Cpu::vals(3000, -3100, 15);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Sub(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Sub(Reg::A, Reg::C), &mut mem)
+            .unwrap();
+
+        assert_eq!(cpu.b, 6100);
+        assert_eq!(cpu.c, 2985);
+        // 2985 is 0x0ba9, 0xa9 has 4 bits set
+        assert_eq!(cpu.flags, Cpu::FLAG_PARITY);
+    }
+
+    #[test]
+    fn sub_with_overflow() {
+        let mut cpu = Cpu::vals(-32767, 4, 0);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Sub(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+
+        assert_eq!(cpu.b, 0);
+        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+    }
+
+    #[test]
+    fn mul_within_16_bits() {
+        let mut cpu = Cpu::vals(4, -5, 10);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Mul(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Mul(Reg::A, Reg::C), &mut mem)
+            .unwrap();
+
+        assert_eq!(cpu.b, -20);
+        assert_eq!(cpu.c, 40);
+        assert_eq!(cpu.flags, Cpu::FLAG_PARITY);
+    }
+
+    #[test]
+    fn mul_with_overflow() {
+        let mut cpu = Cpu::vals(-32767, 32767, 0);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Mul(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+
+        assert_eq!(cpu.b, 0);
+        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+    }
+
+    #[test]
+    fn div() {
+        let mut cpu = Cpu::vals(-32767, 1, 4);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Div(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+
+        assert_eq!(cpu.b, -32767);
+        assert_eq!(cpu.flags, Cpu::FLAG_SIGN);
+    }
+
+    #[test]
+    fn div_by_0() {
+        let mut cpu = Cpu::vals(0, -32767, 0);
+        let mut mem = Mem::default();
+
+        assert_eq!(
+            cpu.execute(Instruction::Div(Reg::B, Reg::A), &mut mem),
+            Err(Fault::DivideByZero)
+        );
+        assert_eq!(cpu.a, 0);
+        assert_eq!(cpu.flags, 0);
+    }
+
+    #[test]
+    fn div_overflow() {
+        let mut cpu = Cpu::vals(i16::MIN, -1, 0);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Div(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+
+        assert_eq!(cpu.b, 0);
+        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let results = [
+            (OverflowPolicy::Wrap, [-32765, 32765, 4]),
+            (OverflowPolicy::Saturate, [i16::MAX, i16::MIN, i16::MAX]),
+            (OverflowPolicy::Zero, [0, 0, 0]),
+        ];
+        let mut mem = Mem::default();
+
+        for (policy, [sum, sub, mul]) in results {
+            let mut cpu = Cpu::vals(32767, 4, -32767);
+            cpu.set_overflow_policy(policy);
+            cpu.execute(Instruction::Sum(Reg::A, Reg::B), &mut mem)
+                .unwrap();
+            assert_eq!(cpu.b, sum, "{:?}", policy);
+            assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+
+            let mut cpu = Cpu::vals(-32767, 4, 0);
+            cpu.set_overflow_policy(policy);
+            cpu.execute(Instruction::Sub(Reg::A, Reg::B), &mut mem)
+                .unwrap();
+            assert_eq!(cpu.b, sub, "{:?}", policy);
+
+            let mut cpu = Cpu::vals(0x4001, 4, 0);
+            cpu.set_overflow_policy(policy);
+            cpu.execute(Instruction::Mul(Reg::A, Reg::B), &mut mem)
+                .unwrap();
+            assert_eq!(cpu.b, mul, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn overflow_trap() {
+        let mut cpu = Cpu::vals(32767, 4, 0);
+        let mut mem = Mem::default();
+        cpu.set_overflow_policy(OverflowPolicy::Trap);
+
+        assert_eq!(
+            cpu.execute(Instruction::Sum(Reg::A, Reg::B), &mut mem),
+            Err(Fault::Overflow)
+        );
+        assert_eq!(cpu.b, 4);
+        assert_eq!(cpu.flags, 0);
+
+        // results that fit are written as usual
+        cpu.execute(Instruction::Sub(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.b, 32763);
+    }
+
+    #[test]
+    fn carry_and_zero() {
+        let mut cpu = Cpu::vals(-1, 1, 0);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Sum(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+
+        // 0xffff + 1 carries out but doesn't overflow
+        assert_eq!(cpu.b, 0);
+        assert_eq!(
+            cpu.flags,
+            Cpu::FLAG_CARRY | Cpu::FLAG_ZERO | Cpu::FLAG_PARITY
+        );
+
+        // carry is cleared by the next arithmetic instruction
+        cpu.reg_write(Reg::B, 7);
+        cpu.execute(Instruction::Sub(Reg::B, Reg::B), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.b, 0);
+        assert_eq!(cpu.flags, Cpu::FLAG_ZERO | Cpu::FLAG_PARITY);
+    }
+
+    #[test]
+    fn sign_and_parity() {
+        let mut cpu = Cpu::vals(-2, 1, 0);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Sub(Reg::C, Reg::B), &mut mem)
+            .unwrap();
+
+        // 0 - 1 borrows, 0xffff has 8 bits set in the low byte
+        assert_eq!(cpu.b, -1);
+        assert_eq!(
+            cpu.flags,
+            Cpu::FLAG_CARRY | Cpu::FLAG_SIGN | Cpu::FLAG_PARITY
+        );
+
+        // 0xfe has 7 bits set
+        cpu.execute(Instruction::Sub(Reg::A, Reg::C), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.c, -2);
+        assert_eq!(cpu.flags, Cpu::FLAG_SIGN);
+    }
+
+    #[test]
+    fn logic_clears_carry() {
+        let mut cpu = Cpu::vals(-1, 1, 0x0f0);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Sum(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        assert!(cpu.flags & Cpu::FLAG_CARRY != 0);
+
+        cpu.execute(Instruction::Or(Reg::A, Reg::C), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.c, -1);
+        assert_eq!(cpu.flags, Cpu::FLAG_SIGN | Cpu::FLAG_PARITY);
+
+        // not leaves the flags alone
+        cpu.execute(Instruction::Not(Reg::C), &mut mem).unwrap();
+        assert_eq!(cpu.c, 0);
+        assert_eq!(cpu.flags, Cpu::FLAG_SIGN | Cpu::FLAG_PARITY);
+
+        cpu.execute(Instruction::And(Reg::A, Reg::C), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.flags, Cpu::FLAG_ZERO | Cpu::FLAG_PARITY);
+    }
+
+    #[test]
+    fn shift_flags() {
+        let mut cpu = Cpu::vals(0x4001, 0, 0);
+        let mut mem = Mem::default();
+
+        // the top bit changes, overflow
+        cpu.execute(Instruction::Shl(Inpt::Const(1), Reg::A), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.a as u16, 0x8002);
+        assert_eq!(cpu.flags, Cpu::FLAG_OVERFLOW | Cpu::FLAG_SIGN);
+
+        // last bit shifted out is the carry, overflow as it's not the top
+        // bit anymore
+        cpu.execute(Instruction::Shl(Inpt::Const(1), Reg::A), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.a, 4);
+        assert_eq!(cpu.flags, Cpu::FLAG_CARRY | Cpu::FLAG_OVERFLOW);
+
+        cpu.execute(Instruction::Shr(Inpt::Const(3), Reg::A), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.a, 0);
+        assert_eq!(
+            cpu.flags,
+            Cpu::FLAG_CARRY | Cpu::FLAG_ZERO | Cpu::FLAG_PARITY
+        );
+
+        // shifting by 0 changes nothing, by more than 16 doesn't panic
+        cpu.execute(Instruction::Shr(Inpt::Const(0), Reg::A), &mut mem)
+            .unwrap();
+        assert_eq!(
+            cpu.flags,
+            Cpu::FLAG_CARRY | Cpu::FLAG_ZERO | Cpu::FLAG_PARITY
+        );
+        cpu.reg_write(Reg::A, -4);
+        cpu.execute(Instruction::Shr(Inpt::Const(40), Reg::A), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.a, -1);
+        assert!(cpu.flags & Cpu::FLAG_CARRY != 0);
+    }
+
+    #[test]
+    fn compare_equal() {
+        let mut cpu = Cpu::vals(0, 1, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::C), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_EQUAL == Cpu::FLAG_EQUAL);
+    }
+
+    #[test]
+    fn compare_greater_than() {
+        let mut cpu = Cpu::vals(0, 1, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::B, Reg::C), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_GREATER_THAN == Cpu::FLAG_GREATER_THAN);
+    }
+
+    #[test]
+    fn compare_lower_than() {
+        let mut cpu = Cpu::vals(0, 1, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_LOWER_THAN == Cpu::FLAG_LOWER_THAN);
+    }
+
+    #[test]
+    fn compare_sequence() {
+        let mut cpu = Cpu::vals(2, 1, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.flags & Cpu::FLAGS_COMPARE, Cpu::FLAG_GREATER_THAN);
+
+        cpu.execute(Instruction::Cmp(Reg::B, Reg::A), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.flags & Cpu::FLAGS_COMPARE, Cpu::FLAG_LOWER_THAN);
+        cpu.execute(Instruction::Jgt(Inpt::Const(0x40)), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.ip, 0);
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::A), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.flags & Cpu::FLAGS_COMPARE, Cpu::FLAG_EQUAL);
+        cpu.execute(Instruction::Jlt(Inpt::Const(0x40)), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.ip, 0);
+    }
+
+    #[test]
+    fn instructions_keep_flags_they_dont_write() {
+        let mut cpu = Cpu::vals(-1, 1, 0);
+        let mut mem = Mem::new(0x10);
+        cpu.set_stack(0x08, 0x08);
+
+        cpu.execute(Instruction::Sum(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::C), &mut mem)
+            .unwrap();
+        let flags = cpu.flags;
+
+        cpu.execute(
+            Instruction::Ld(GenerousInpt::Const(5), Dest::Register(Reg::C)),
+            &mut mem,
+        )
+        .unwrap();
+        cpu.execute(Instruction::Not(Reg::C), &mut mem).unwrap();
+        cpu.execute(Instruction::Push(Inpt::Register(Reg::C)), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Jmp(Inpt::Const(0)), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.flags, flags);
+
+        // compare flags survive arithmetic, result flags are redefined
+        cpu.execute(Instruction::Sum(Reg::B, Reg::C), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.flags & Cpu::FLAGS_COMPARE, Cpu::FLAG_LOWER_THAN);
+        assert_eq!(cpu.flags & Cpu::FLAG_CARRY, 0);
+    }
+
+    #[test]
+    fn pushf_popf() {
+        let mut cpu = Cpu::vals(1, 1, 0);
+        let mut mem = Mem::new(0x10);
+        cpu.set_stack(0x08, 0x08);
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        let flags = cpu.flags;
+        cpu.execute(Instruction::Pushf, &mut mem).unwrap();
+        assert_eq!(mem.read_16(0x06), Ok(flags));
+
+        cpu.execute(Instruction::Cmp(Reg::C, Reg::A), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Popf, &mut mem).unwrap();
+        assert_eq!(cpu.flags, flags);
+        assert_eq!(cpu.sp, 0x08);
+
+        // bits that aren't flags are dropped
+        cpu.execute(Instruction::Push(Inpt::Const(-1)), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Popf, &mut mem).unwrap();
+        assert_eq!(cpu.flags, Cpu::FLAGS_ALL);
+    }
+
+    #[test]
+    fn jmp() {
+        let mut cpu = Cpu::vals(0xff, 1, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Jmp(Inpt::Const(45)), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.ip, 45);
+        cpu.execute(Instruction::Jmp(Inpt::Register(Reg::A)), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.ip, 0xff);
+    }
+
+    #[test]
+    fn jeq_after_equal_number_comparison() {
+        let mut cpu = Cpu::vals(3, 3, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Jeq(Inpt::Const(0xab)), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_EQUAL == Cpu::FLAG_EQUAL);
+        assert_eq!(cpu.ip, 0xab);
+    }
+
+    #[test]
+    fn jeq_doesnt_jmp_if_flag_eq_not_set() {
+        let mut cpu = Cpu::vals(3, 4, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Jeq(Inpt::Const(0xab)), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_EQUAL == 0);
+        assert_eq!(cpu.ip, 0);
+    }
+
+    #[test]
+    fn jne_after_not_equal_number_comparison() {
+        let mut cpu = Cpu::vals(3, -3, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Jne(Inpt::Const(0xab)), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_EQUAL == 0);
+        assert_eq!(cpu.ip, 0xab);
+    }
+
+    #[test]
+    fn jne_doesnt_jmp_if_flag_eq_set() {
+        let mut cpu = Cpu::vals(4, 4, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Jne(Inpt::Const(0xab)), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_EQUAL == Cpu::FLAG_EQUAL);
+        assert_eq!(cpu.ip, 0);
+    }
+
+    #[test]
+    fn jgt_with_greater_than_flag_set() {
+        let mut cpu = Cpu::vals(4, 7, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::B, Reg::A), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Jgt(Inpt::Const(0xab)), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_GREATER_THAN == Cpu::FLAG_GREATER_THAN);
+        assert_eq!(cpu.ip, 0xab);
+    }
+
+    #[test]
+    fn jgt_without_greater_than_flag_set() {
+        let mut cpu = Cpu::vals(4, 4, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Jgt(Inpt::Const(0xab)), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_GREATER_THAN == 0);
+        assert_eq!(cpu.ip, 0);
+    }
+
+    #[test]
+    fn jlt_with_lower_than_flag_set() {
+        let mut cpu = Cpu::vals(4, 7, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Jlt(Inpt::Const(0xab)), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_LOWER_THAN == Cpu::FLAG_LOWER_THAN);
+        assert_eq!(cpu.ip, 0xab);
+    }
+
+    #[test]
+    fn jlt_with_greater_than_flag_set() {
+        let mut cpu = Cpu::vals(6, 4, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Jlt(Inpt::Const(0xab)), &mut mem)
+            .unwrap();
+
+        assert!(cpu.flags & Cpu::FLAG_LOWER_THAN == 0);
+        assert_eq!(cpu.ip, 0);
+    }
+
+    #[test]
+    fn and() {
+        let mut cpu = Cpu::vals(0xffabu16 as i16, 0x00ff, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::And(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.b, 0x00ab);
+    }
+
+    #[test]
+    fn or() {
+        let mut cpu = Cpu::vals(0xff00u16 as i16, 0x00ff, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Or(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.b, 0xffffu16 as i16);
+    }
+
+    #[test]
+    fn not() {
+        let mut cpu = Cpu::vals(0xff00u16 as i16, 0, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Not(Reg::A), &mut mem).unwrap();
+        assert_eq!(cpu.a, 0x00ff);
+    }
+
+    #[test]
+    #[allow(clippy::identity_op)]
+    fn xor() {
+        let mut cpu = Cpu::vals(0b1001, 0, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Xor(Reg::A, Reg::B), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.b, 0b1001 ^ 0);
+    }
+
+    #[test]
+    fn shr() {
+        let mut cpu = Cpu::vals(0b10, 0xff, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Shr(Inpt::Const(1), Reg::A), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Shr(Inpt::Const(10), Reg::B), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.a, 1);
+        assert_eq!(cpu.b, 0);
+    }
+
+    #[test]
+    fn shl() {
+        let mut cpu = Cpu::vals(0b10, 0xff, 0);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Shl(Inpt::Const(1), Reg::A), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Shl(Inpt::Const(10), Reg::B), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.a, 4);
+        assert_eq!(cpu.b, 0xff << 10);
+    }
+
+    /// Cpu with an empty stack of `size` bytes below `top`.
+    fn with_stack(top: u16, size: u16) -> Cpu {
+        let mut cpu = Cpu::default();
+        cpu.set_stack(top, size);
+        cpu
+    }
+
+    #[test]
+    fn push() {
+        let mut cpu = with_stack(4, 4);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Push(Inpt::Const(45)), &mut mem)
+            .unwrap();
+
+        assert_eq!(cpu.sp, 2);
+        assert_eq!(mem.read(2), Ok(0));
+        assert_eq!(mem.read(3), Ok(45));
+    }
+
+    #[test]
+    fn push_with_overflow() {
+        let mut cpu = with_stack(4, 2);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Push(Inpt::Const(45)), &mut mem)
+            .unwrap();
+        assert_eq!(
+            cpu.execute(Instruction::Push(Inpt::Const(46)), &mut mem),
+            Err(Fault::StackOverflow)
+        );
+
+        // nothing is lost
+        assert_eq!(mem.read(0), Ok(0));
+        assert_eq!(mem.read(1), Ok(0));
+        assert_eq!(mem.read(3), Ok(45));
+        assert_eq!(cpu.sp, 2);
+        assert_eq!(cpu.flags, 0);
+    }
+
+    #[test]
+    fn pop() {
+        let mut cpu = with_stack(8, 8);
+        cpu.sp = 2;
+        let mut mem = Mem::set(vec![0, 0, 0, 12, 0, 45, 255, 251]);
+
+        cpu.execute(Instruction::Pop(Reg::A), &mut mem).unwrap();
+        cpu.execute(Instruction::Pop(Reg::B), &mut mem).unwrap();
+        cpu.execute(Instruction::Pop(Reg::C), &mut mem).unwrap();
+
+        assert_eq!(cpu.sp, 8);
+        assert_eq!(cpu.a, 12);
+        assert_eq!(cpu.b, 45);
+        assert_eq!(cpu.c, -5);
+    }
+
+    #[test]
+    fn pop_with_underflow() {
+        let mut cpu = with_stack(4, 4);
+        cpu.sp = 2;
+        let mut mem = Mem::set(vec![0, 0, 0, 45, 0, 7]);
+
+        cpu.execute(Instruction::Pop(Reg::A), &mut mem).unwrap();
+        assert_eq!(
+            cpu.execute(Instruction::Pop(Reg::C), &mut mem),
+            Err(Fault::StackUnderflow)
+        );
+
+        assert_eq!(cpu.sp, 4);
+        assert_eq!(cpu.a, 45);
+        assert_eq!(cpu.c, 0);
+        assert_eq!(cpu.flags, 0);
+    }
+
+    #[test]
+    fn push_then_pop() {
+        let mut cpu = with_stack(4, 4);
+        (cpu.a, cpu.b) = (7, -3);
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Push(Inpt::Register(Reg::A)), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Push(Inpt::Register(Reg::B)), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.sp, 0);
+        cpu.execute(Instruction::Pop(Reg::A), &mut mem).unwrap();
+        cpu.execute(Instruction::Pop(Reg::B), &mut mem).unwrap();
+
+        assert_eq!(cpu.a, -3);
+        assert_eq!(cpu.b, 7);
+        assert_eq!(cpu.sp, 4);
+        assert_eq!(cpu.flags, 0);
+    }
+
+    #[test]
+    fn stack_at_end_of_segment() {
+        let mut cpu = with_stack(0, 4);
+        let mut mem = Mem::new(0x10000);
+
+        cpu.execute(Instruction::Push(Inpt::Const(0x0102)), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.sp, 0xfffe);
+        assert_eq!(mem.read_16(0xfffe), Ok(0x0102));
+
+        cpu.execute(Instruction::Pop(Reg::A), &mut mem).unwrap();
+        assert_eq!(cpu.sp, 0);
+        assert_eq!(
+            cpu.execute(Instruction::Pop(Reg::A), &mut mem),
+            Err(Fault::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn call() {
+        let mut cpu = with_stack(4, 4);
+        cpu.ip = 0x0102;
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Call(Inpt::Const(0x40)), &mut mem)
+            .unwrap();
+
+        assert_eq!(cpu.ip, 0x40);
+        assert_eq!(cpu.sp, 2);
+        assert_eq!(mem.read(2), Ok(0x01));
+        assert_eq!(mem.read(3), Ok(0x02));
+    }
+
+    #[test]
+    fn call_with_stack_overflow() {
+        let mut cpu = with_stack(4, 2);
+        cpu.ip = 3;
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Call(Inpt::Const(0x40)), &mut mem)
+            .unwrap();
+        assert_eq!(
+            cpu.execute(Instruction::Call(Inpt::Const(0x50)), &mut mem),
+            Err(Fault::StackOverflow)
+        );
+        assert_eq!(cpu.ip, 0x40);
+        assert_eq!(cpu.sp, 2);
+    }
+
+    #[test]
+    fn far_call_with_stack_overflow() {
+        let mut cpu = with_stack(0x80, 4);
+        cpu.ip = 3;
+        let mut mem = Mem::default();
+
+        cpu.execute(Instruction::Push(Inpt::Const(1)), &mut mem)
+            .unwrap();
+        assert_eq!(
+            cpu.execute(
+                Instruction::CallFar(Inpt::Const(0), Inpt::Const(0)),
+                &mut mem
+            ),
+            Err(Fault::StackOverflow)
+        );
+        assert_eq!((cpu.cs, cpu.ip, cpu.sp), (0, 3, 0x7e));
+        assert_eq!(mem.peek_16(0x7c), Ok(0));
+    }
+
+    #[test]
+    fn iret() {
+        let mut cpu = with_stack(0x80, 8);
+        let mut mem = Mem::default();
+        for word in [-1, 0x20, 0x10] {
+            cpu.execute(Instruction::Push(Inpt::Const(word)), &mut mem)
+                .unwrap();
+        }
+
+        cpu.execute(Instruction::Iret, &mut mem).unwrap();
+        assert_eq!((cpu.cs, cpu.ip, cpu.sp), (0x20, 0x10, 0x80));
+        // bits that aren't flags are dropped
+        assert_eq!(cpu.flags, Cpu::FLAGS_ALL);
+    }
+
+    #[test]
+    fn far_returns_with_stack_underflow() {
+        let mut cpu = with_stack(0x80, 8);
+        let mut mem = Mem::default();
+        cpu.execute(Instruction::Push(Inpt::Const(0x20)), &mut mem)
+            .unwrap();
+        cpu.execute(Instruction::Push(Inpt::Const(0x10)), &mut mem)
+            .unwrap();
+        cpu.flags = Cpu::FLAG_CARRY;
+
+        assert_eq!(
+            cpu.execute(Instruction::Iret, &mut mem),
+            Err(Fault::StackUnderflow)
+        );
+        assert_eq!((cpu.cs, cpu.ip, cpu.sp), (0, 0, 0x7c));
+        assert_eq!(cpu.flags, Cpu::FLAG_CARRY);
+
+        cpu.execute(Instruction::Pop(Reg::A), &mut mem).unwrap();
+        assert_eq!(
+            cpu.execute(Instruction::RetFar, &mut mem),
+            Err(Fault::StackUnderflow)
+        );
+        assert_eq!((cpu.cs, cpu.ip, cpu.sp), (0, 0, 0x7e));
+    }
+
+    #[test]
+    fn ret() {
+        let mut cpu = with_stack(4, 4);
+        cpu.sp = 2;
+        let mut mem = Mem::set(vec![0, 0, 0x01, 0x02]);
+
+        cpu.execute(Instruction::Ret, &mut mem).unwrap();
+
+        assert_eq!(cpu.ip, 0x0102);
+        assert_eq!(cpu.sp, 4);
+    }
+
+    #[test]
+    fn mem_out_of_bounds() {
+        let mut mem = Mem::new(4);
+        let fault = |addr| MemFault {
+            addr,
+            kind: MemFaultKind::OutOfBounds,
+        };
+
+        assert_eq!(mem.read(4), Err(fault(4)));
+        assert_eq!(mem.read_16(3), Err(fault(4)));
+        assert_eq!(mem.write(7, 1), Err(fault(7)));
+        assert_eq!(mem.write_16(3, -1), Err(fault(4)));
+        assert_eq!(mem.read(3), Ok(0));
+        assert_eq!(mem.read_16(2), Ok(0));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut mem = Mem::set(vec![0, 1, 2, 3, 4, 5]);
+        mem.add_watchpoint(1..2, WatchKind::Write);
+        mem.add_watchpoint(4..6, WatchKind::Read);
+
+        mem.write(0, 9).unwrap();
+        mem.read(1).unwrap();
+        mem.read_16(2).unwrap();
+        assert_eq!(mem.take_watch_hits(), vec![]);
+
+        mem.write_16(0, 0x0a0b).unwrap();
+        mem.write(1, 7).unwrap();
+        mem.read_16(3).unwrap();
+        mem.peek(4).unwrap();
+        assert_eq!(
+            mem.take_watch_hits(),
+            vec![
+                WatchHit {
+                    addr: 0,
+                    size: 2,
+                    access: Access::Write,
+                    old: 0x0901,
+                    new: 0x0a0b
+                },
+                WatchHit {
+                    addr: 1,
+                    size: 1,
+                    access: Access::Write,
+                    old: 0x0b,
+                    new: 7
+                },
+                WatchHit {
+                    addr: 3,
+                    size: 2,
+                    access: Access::Read,
+                    old: 0x0304,
+                    new: 0x0304
+                },
+            ]
+        );
+        assert_eq!(mem.take_watch_hits(), vec![]);
+
+        assert!(mem.remove_watchpoint(4..6));
+        mem.add_watchpoint(5..6, WatchKind::Access);
+        mem.read(5).unwrap();
+        mem.write(5, 1).unwrap();
+        assert_eq!(mem.take_watch_hits().len(), 2);
+        assert!(!mem.remove_watchpoint(4..6));
+    }
+
+    #[test]
+    fn words_wrap_at_top_of_address_space() {
+        let mut mem = Mem::with_mode(0x10000, AddressMode::Bits16, Backing::Dense).unwrap();
+        mem.write_16(0xffff, 0x1234).unwrap();
+        assert_eq!(mem.peek(0xffff), Ok(0x12));
+        assert_eq!(mem.peek(0), Ok(0x34));
+        assert_eq!(mem.read_16(0xffff), Ok(0x1234));
+
+        let mut mem = Mem::new(0x10000);
+        assert_eq!(
+            mem.write_16(0xffff, 0x1234),
+            Err(MemFault {
+                addr: 0x10000,
+                kind: MemFaultKind::OutOfBounds
+            })
+        );
+        assert_eq!(mem.peek(0xffff), Ok(0));
+    }
+
+    #[test]
+    fn memory_sizes() {
+        assert!(Mem::with_mode(1 << 16, AddressMode::Bits16, Backing::Dense).is_ok());
+        assert_eq!(
+            Mem::with_mode((1 << 16) + 1, AddressMode::Bits16, Backing::Paged).err(),
+            Some(MemSizeError {
+                size: 0x10001,
+                mode: AddressMode::Bits16
+            })
+        );
+        assert!(Mem::with_mode(1 << 20, AddressMode::Bits20, Backing::Paged).is_ok());
+        assert_eq!(
+            MemSizeError {
+                size: 0x100001,
+                mode: AddressMode::Bits20
+            }
+            .to_string(),
+            "memory of 0x100001 bytes doesn't fit in a 20-bit address space"
+        );
+    }
+
+    #[test]
+    fn paged_memory() {
+        let mut mem = Mem::with_mode(0x10000, AddressMode::Bits16, Backing::Paged).unwrap();
+        mem.load(0x2ffe, &[1, 2, 3, 4]);
+        mem.write(0x8000, 0).unwrap();
+        assert_eq!(mem.allocated(), 2 * PAGE_SIZE);
+
+        assert_eq!(mem.read_16(0x2ffe), Ok(0x0102));
+        assert_eq!(mem.read_16(0x2fff), Ok(0x0203));
+        assert_eq!(mem.read(0x8000), Ok(0));
+        assert_eq!(
+            mem.peek(0x10000).map_err(|e| e.kind),
+            Err(MemFaultKind::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn ld_with_mem_fault() {
+        let mut cpu = Cpu::vals(3, 0, 0);
+        let mut mem = Mem::new(10);
+
+        assert_eq!(
+            cpu.execute(
+                Instruction::Ld(GenerousInpt::Memory(20), Dest::Register(Reg::A)),
+                &mut mem
+            ),
+            Err(Fault::Mem(MemFault {
+                addr: 20,
+                kind: MemFaultKind::OutOfBounds
+            }))
+        );
+        assert_eq!(cpu.a, 3);
+
+        assert_eq!(
+            cpu.execute(
+                Instruction::Ld(GenerousInpt::Register(Reg::A), Dest::Memory(9)),
+                &mut mem
+            ),
+            Err(Fault::Mem(MemFault {
+                addr: 10,
+                kind: MemFaultKind::OutOfBounds
+            }))
+        );
+        assert_eq!(mem.read(9), Ok(0));
+    }
+
+    #[test]
+    fn push_with_mem_fault() {
+        let mut cpu = with_stack(20, 12);
+        let mut mem = Mem::new(10);
+
+        assert!(matches!(
+            cpu.execute(Instruction::Push(Inpt::Const(1)), &mut mem),
+            Err(Fault::Mem(_))
+        ));
+        assert_eq!(cpu.sp, 20);
+        assert_eq!(cpu.flags, 0);
+    }
+
+    #[test]
+    fn graphics() {
+        let mut cpu = Cpu::default();
+        cpu.attach_framebuffer(Framebuffer::new(8, 8, DEFAULT_PALETTE.to_vec()));
         let mut mem = Mem::default();
-        cpu.execute(Instruction::Sum(Reg::B, Reg::A), &mut mem);
 
-        assert_eq!(cpu.a, 0);
-        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+        cpu.reg_write(Reg::A, 0x0102);
+        cpu.reg_write(Reg::B, 0x0203);
+        cpu.reg_write(Reg::C, 4);
+        cpu.reg_write(Reg::D, 0x0707);
+        cpu.execute(Instruction::Cls(Reg::C), &mut mem).unwrap();
+        cpu.reg_write(Reg::C, 1);
+        cpu.execute(Instruction::Rect(Reg::A, Reg::B, Reg::C), &mut mem)
+            .unwrap();
+        cpu.reg_write(Reg::C, 2);
+        cpu.execute(Instruction::Line(Reg::D, Reg::B, Reg::C), &mut mem)
+            .unwrap();
+        cpu.reg_write(Reg::C, 3);
+        cpu.execute(Instruction::Pxl(Reg::D, Reg::C), &mut mem)
+            .unwrap();
+
+        let fb = cpu.framebuffer().unwrap();
+        assert_eq!(fb.pixel(0, 0), Some(4));
+        assert_eq!(fb.pixel(1, 2), Some(1));
+        assert_eq!(fb.pixel(2, 4), Some(1));
+        assert_eq!(fb.pixel(3, 2), Some(4));
+        assert_eq!(fb.pixel(5, 5), Some(2));
+        assert_eq!(fb.pixel(2, 3), Some(2));
+        assert_eq!(fb.pixel(7, 7), Some(3));
     }
 
     #[test]
-    fn sum_of_negatives_with_overflow() {
-        let mut cpu = Cpu::vals(-32767, -4, 0);
+    fn graphics_faults() {
+        let mut cpu = Cpu::vals(0, 0, 16);
         let mut mem = Mem::default();
-        cpu.execute(Instruction::Sum(Reg::A, Reg::B), &mut mem);
 
-        assert_eq!(cpu.b, 0);
-        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+        assert_eq!(
+            cpu.execute(Instruction::Pxl(Reg::A, Reg::B), &mut mem),
+            Err(Fault::NoFramebuffer)
+        );
+
+        cpu.attach_framebuffer(Framebuffer::default());
+        assert_eq!(
+            cpu.execute(Instruction::Cls(Reg::C), &mut mem),
+            Err(Fault::InvalidColor(16))
+        );
     }
 
     #[test]
-    fn sub_within_16_bits() {
-        let mut cpu = Cpu::vals(3000, -3100, 15);
+    fn ret_with_stack_underflow() {
+        let mut cpu = with_stack(4, 4);
+        cpu.ip = 9;
         let mut mem = Mem::default();
-        cpu.execute(Instruction::Sub(Reg::A, Reg::B), &mut mem);
-        cpu.execute(Instruction::Sub(Reg::A, Reg::C), &mut mem);
 
-        assert_eq!(cpu.b, 6100);
-        assert_eq!(cpu.c, 2985);
-        assert_eq!(cpu.flags, 0);
+        assert_eq!(
+            cpu.execute(Instruction::Ret, &mut mem),
+            Err(Fault::StackUnderflow)
+        );
+        assert_eq!(cpu.ip, 9);
+        assert_eq!(cpu.sp, 4);
+    }
+}
+
+#[cfg(test)]
+mod run_tests {
+    use super::*;
+    use crate::encoding::{encode, encode_all};
+
+    fn load(prog: &[Instruction]) -> Mem {
+        let bytes = encode_all(prog);
+        let mut mem = Mem::new(64);
+        mem.load(0, &bytes);
+        mem
+    }
+
+    fn addr_of(prog: &[Instruction], i: usize) -> i16 {
+        encode_all(&prog[..i]).len() as i16
+    }
+
+    fn count_to_three() -> Vec<Instruction> {
+        let mut prog = vec![
+            Instruction::Ld(GenerousInpt::Const(3), Dest::Register(Reg::A)),
+            Instruction::Ld(GenerousInpt::Const(1), Dest::Register(Reg::B)),
+            Instruction::Sum(Reg::B, Reg::C),
+            Instruction::Cmp(Reg::C, Reg::A),
+            Instruction::Jne(Inpt::Const(0)),
+            Instruction::Hlt,
+        ];
+        prog[4] = Instruction::Jne(Inpt::Const(addr_of(&prog, 2)));
+        prog
     }
 
     #[test]
-    fn sub_with_overflow() {
-        let mut cpu = Cpu::vals(-32767, 4, 0);
-        let mut mem = Mem::default();
-        cpu.execute(Instruction::Sub(Reg::A, Reg::B), &mut mem);
+    fn step_advances_ip() {
+        let prog = [
+            Instruction::Ld(GenerousInpt::Const(7), Dest::Register(Reg::A)),
+            Instruction::Not(Reg::A),
+        ];
+        let mut mem = load(&prog);
+        let mut cpu = Cpu::default();
 
-        assert_eq!(cpu.b, 0);
-        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+        assert_eq!(cpu.step(&mut mem), None);
+        assert_eq!(cpu.ip, encode(&prog[0]).len() as u16);
+        assert_eq!(cpu.a, 7);
+
+        assert_eq!(cpu.step(&mut mem), None);
+        assert_eq!(cpu.ip, addr_of(&prog, 2) as u16);
+        assert_eq!(cpu.a, !7);
     }
 
     #[test]
-    fn mul_within_16_bits() {
-        let mut cpu = Cpu::vals(4, -5, 10);
-        let mut mem = Mem::default();
-        cpu.execute(Instruction::Mul(Reg::A, Reg::B), &mut mem);
-        cpu.execute(Instruction::Mul(Reg::A, Reg::C), &mut mem);
+    fn run_until_halt() {
+        let mut mem = load(&count_to_three());
+        let mut cpu = Cpu::default();
 
-        assert_eq!(cpu.b, -20);
-        assert_eq!(cpu.c, 40);
-        assert_eq!(cpu.flags, 0);
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+        assert_eq!(cpu.c, 3);
+        assert!(cpu.halted());
+        assert_eq!(cpu.step(&mut mem), Some(StopReason::Halt));
     }
 
     #[test]
-    fn mul_with_overflow() {
-        let mut cpu = Cpu::vals(-32767, 32767, 0);
-        let mut mem = Mem::default();
-        cpu.execute(Instruction::Mul(Reg::A, Reg::B), &mut mem);
+    fn run_hits_limit() {
+        let prog = [Instruction::Jmp(Inpt::Const(0))];
+        let mut mem = load(&prog);
+        let mut cpu = Cpu::default();
 
-        assert_eq!(cpu.b, 0);
-        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+        assert_eq!(cpu.run(&mut mem, 10), StopReason::Limit);
+        assert_eq!(cpu.ip, 0);
     }
 
     #[test]
-    fn div() {
-        let mut cpu = Cpu::vals(-32767, 1, 4);
-        let mut mem = Mem::default();
-        cpu.execute(Instruction::Div(Reg::A, Reg::B), &mut mem);
+    fn run_faults_on_invalid_opcode() {
+        let mut mem = Mem::new(8);
+        mem.load(0, &encode(&Instruction::Not(Reg::A)));
+        mem.load(2, &[0xff]);
+        let mut cpu = Cpu::default();
 
-        assert_eq!(cpu.b, -32767);
-        assert_eq!(cpu.flags, 0);
+        assert_eq!(
+            cpu.run(&mut mem, 10),
+            StopReason::Fault(Fault::Decode(DecodeError::InvalidOpcode {
+                addr: 2,
+                opcode: 0xff
+            }))
+        );
+        assert_eq!(cpu.ip, 2);
     }
 
     #[test]
-    fn div_by_0() {
-        let mut cpu = Cpu::vals(0, -32767, 0);
-        let mut mem = Mem::default();
-        cpu.execute(Instruction::Div(Reg::B, Reg::A), &mut mem);
+    fn run_subroutine() {
+        // the stack lives right after the program
+        let mut prog = vec![
+            Instruction::Call(Inpt::Const(0)),
+            Instruction::Call(Inpt::Const(0)),
+            Instruction::Hlt,
+            Instruction::Sum(Reg::B, Reg::A),
+            Instruction::Ret,
+        ];
+        let sub = addr_of(&prog, 3);
+        prog[0] = Instruction::Call(Inpt::Const(sub));
+        prog[1] = Instruction::Call(Inpt::Const(sub));
+        let end = addr_of(&prog, prog.len()) as u16;
+
+        let mut mem = load(&prog);
+        let mut cpu = Cpu {
+            b: 5,
+            ..Default::default()
+        };
+        cpu.set_stack(end + 4, 4);
 
-        assert_eq!(cpu.a, 0);
-        assert!(cpu.flags & Cpu::FLAG_ZERO != 0);
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+        assert_eq!(cpu.a, 10);
+        assert_eq!(cpu.sp, end + 4);
     }
 
     #[test]
-    fn compare_equal() {
-        let mut cpu = Cpu::vals(0, 1, 0);
-        let mut mem = Mem::default();
+    fn run_faults_on_stack_overflow() {
+        let prog = [Instruction::Call(Inpt::Const(0))];
+        let mut mem = load(&prog);
+        let mut cpu = Cpu::default();
+        cpu.set_stack(38, 6);
 
-        cpu.execute(Instruction::Cmp(Reg::A, Reg::C), &mut mem);
+        assert_eq!(
+            cpu.run(&mut mem, 100),
+            StopReason::Fault(Fault::StackOverflow)
+        );
+        assert_eq!(cpu.ip, 0);
+        assert_eq!(cpu.sp, 32);
+    }
 
-        assert!(cpu.flags & Cpu::FLAG_EQUAL == Cpu::FLAG_EQUAL);
+    #[test]
+    fn run_faults_on_bad_memory_access() {
+        let prog = [
+            Instruction::Not(Reg::B),
+            Instruction::Ld(GenerousInpt::Const(1), Dest::Memory(0xfff0)),
+            Instruction::Hlt,
+        ];
+        let mut mem = load(&prog);
+        let mut cpu = Cpu::default();
+
+        assert_eq!(
+            cpu.run(&mut mem, 10),
+            StopReason::Fault(Fault::Mem(MemFault {
+                addr: 0xfff0,
+                kind: MemFaultKind::OutOfBounds
+            }))
+        );
+        assert_eq!(cpu.ip, addr_of(&prog, 1) as u16);
+        assert_eq!(cpu.b, -1);
     }
 
     #[test]
-    fn compare_greater_than() {
-        let mut cpu = Cpu::vals(0, 1, 0);
-        let mut mem = Mem::default();
+    fn run_stops_at_watchpoint() {
+        let prog = [
+            Instruction::Ld(GenerousInpt::Const(5), Dest::Register(Reg::A)),
+            Instruction::Push(Inpt::Register(Reg::A)),
+            Instruction::Push(Inpt::Const(-1)),
+            Instruction::Ld(GenerousInpt::Memory(42), Dest::Register(Reg::B)),
+            Instruction::Hlt,
+        ];
+        let mut mem = load(&prog);
+        mem.add_watchpoint(40..42, WatchKind::Write);
+        mem.add_watchpoint(42..43, WatchKind::Read);
+        let mut cpu = Cpu::default();
+        cpu.set_stack(44, 8);
+
+        assert_eq!(
+            cpu.run(&mut mem, 100),
+            StopReason::Watchpoint(WatchHit {
+                addr: 40,
+                size: 2,
+                access: Access::Write,
+                old: 0,
+                new: 0xffff
+            })
+        );
+        assert_eq!(cpu.ip, addr_of(&prog, 3) as u16);
+
+        assert_eq!(
+            cpu.run(&mut mem, 100),
+            StopReason::Watchpoint(WatchHit {
+                addr: 42,
+                size: 2,
+                access: Access::Read,
+                old: 5,
+                new: 5
+            })
+        );
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+    }
 
-        cpu.execute(Instruction::Cmp(Reg::B, Reg::C), &mut mem);
+    #[test]
+    fn fetch_doesnt_trigger_watchpoints() {
+        let mut mem = load(&[Instruction::Not(Reg::A), Instruction::Hlt]);
+        mem.add_watchpoint(0..4, WatchKind::Access);
+        let mut cpu = Cpu::default();
 
-        assert!(cpu.flags & Cpu::FLAG_GREATER_THAN == Cpu::FLAG_GREATER_THAN);
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
     }
 
     #[test]
-    fn compare_lower_than() {
-        let mut cpu = Cpu::vals(0, 1, 0);
-        let mut mem = Mem::default();
+    fn writing_code() {
+        let prog = [
+            Instruction::Ld(GenerousInpt::Const(7), Dest::Memory(1)),
+            Instruction::Hlt,
+        ];
+        let len = addr_of(&prog, 2) as usize;
+
+        let mut mem = load(&prog);
+        mem.add_region("code", 0..len, Perms::CODE);
+        let mut cpu = Cpu::default();
+        assert_eq!(cpu.run(&mut mem, 10), StopReason::Halt);
+        assert_eq!(
+            mem.take_violations(),
+            vec![Violation {
+                addr: 1,
+                access: Perms::WRITE,
+                region: "code".to_string()
+            }]
+        );
+        assert_eq!(mem.take_violations(), vec![]);
 
-        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem);
+        let mut mem = load(&prog);
+        mem.add_region("code", 0..len, Perms::CODE);
+        mem.set_strictness(Strictness::Fault);
+        let mut cpu = Cpu::default();
+        assert_eq!(
+            cpu.run(&mut mem, 10),
+            StopReason::Fault(Fault::Mem(MemFault {
+                addr: 1,
+                kind: MemFaultKind::Protected(Perms::WRITE)
+            }))
+        );
+        assert_eq!(cpu.ip, 0);
+        assert_eq!(mem.peek_16(1), load(&prog).peek_16(1));
 
-        assert!(cpu.flags & Cpu::FLAG_LOWER_THAN == Cpu::FLAG_LOWER_THAN);
+        let mut mem = load(&prog);
+        mem.add_region("code", 0..len, Perms::CODE);
+        mem.set_strictness(Strictness::Off);
+        assert_eq!(Cpu::default().run(&mut mem, 10), StopReason::Halt);
+        assert_eq!(mem.take_violations(), vec![]);
     }
 
     #[test]
-    fn jmp() {
-        let mut cpu = Cpu::vals(0xff, 1, 0);
-        let mut mem = Mem::default();
+    fn executing_data() {
+        let mut mem = load(&[Instruction::Jmp(Inpt::Const(0x20))]);
+        mem.load(0x20, &encode(&Instruction::Hlt));
+        mem.add_region("code", 0..0x10, Perms::CODE);
+        mem.add_region("data", 0x10..0x40, Perms::DATA);
 
-        cpu.execute(Instruction::Jmp(Inpt::Const(45)), &mut mem);
-        assert_eq!(cpu.ip, 45);
-        cpu.execute(Instruction::Jmp(Inpt::Register(Reg::A)), &mut mem);
-        assert_eq!(cpu.ip, 0xff);
+        let mut cpu = Cpu::default();
+        assert_eq!(cpu.run(&mut mem, 10), StopReason::Halt);
+        assert_eq!(
+            mem.take_violations()
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>(),
+            vec!["execution at 0x0020 in `data`"]
+        );
+
+        mem.set_strictness(Strictness::Fault);
+        let mut cpu = Cpu::default();
+        assert_eq!(
+            cpu.run(&mut mem, 10),
+            StopReason::Fault(Fault::Mem(MemFault {
+                addr: 0x20,
+                kind: MemFaultKind::Protected(Perms::EXECUTE)
+            }))
+        );
+        assert_eq!(cpu.ip, 0x20);
     }
 
     #[test]
-    fn jeq_after_equal_number_comparison() {
-        let mut cpu = Cpu::vals(3, 3, 0);
-        let mut mem = Mem::default();
+    fn instruction_running_into_data() {
+        let ld = Instruction::Ld(GenerousInpt::Const(5), Dest::Register(Reg::A));
+        let len = encode(&ld).len();
+        let mut mem = load(&[ld]);
+        mem.add_region("code", 0..1, Perms::CODE);
+        mem.add_region("data", 1..0x10, Perms::DATA);
+        mem.set_strictness(Strictness::Fault);
 
-        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem);
-        cpu.execute(Instruction::Jeq(Inpt::Const(0xab)), &mut mem);
+        let mut cpu = Cpu::default();
+        assert!(len > 1);
+        assert_eq!(
+            cpu.step(&mut mem),
+            Some(StopReason::Fault(Fault::Mem(MemFault {
+                addr: 1,
+                kind: MemFaultKind::Protected(Perms::EXECUTE)
+            })))
+        );
+        assert_eq!((cpu.ip, cpu.a), (0, 0));
+    }
 
-        assert!(cpu.flags & Cpu::FLAG_EQUAL == Cpu::FLAG_EQUAL);
-        assert_eq!(cpu.ip, 0xab);
+    #[test]
+    fn stack_growing_into_data() {
+        let prog = [Instruction::Push(Inpt::Const(1)); 3];
+        let mut mem = load(&prog);
+        mem.add_region("data", 0x20..0x2c, Perms::DATA);
+        mem.add_region("stack", 0x2c..0x30, Perms::DATA | Perms::STACK);
+        mem.set_strictness(Strictness::Fault);
+        let mut cpu = Cpu::default();
+        cpu.set_stack(0x30, 0x10);
+
+        assert_eq!(cpu.step(&mut mem), None);
+        assert_eq!(cpu.step(&mut mem), None);
+        assert_eq!(
+            cpu.step(&mut mem),
+            Some(StopReason::Fault(Fault::Mem(MemFault {
+                addr: 0x2a,
+                kind: MemFaultKind::Protected(Perms::STACK)
+            })))
+        );
+        assert_eq!(cpu.sp, 0x2c);
+        assert_eq!(
+            Fault::Mem(MemFault {
+                addr: 0x2a,
+                kind: MemFaultKind::Protected(Perms::STACK)
+            })
+            .to_string(),
+            "stack push at address 0x002a isn't allowed"
+        );
     }
 
     #[test]
-    fn jeq_doesnt_jmp_if_flag_eq_not_set() {
-        let mut cpu = Cpu::vals(3, 4, 0);
-        let mut mem = Mem::default();
+    fn perms_display() {
+        assert_eq!(Perms::CODE.to_string(), "r-x-");
+        assert_eq!((Perms::DATA | Perms::STACK).to_string(), "rw-s");
+        assert_eq!(Perms::NONE.to_string(), "----");
+    }
 
-        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem);
-        cpu.execute(Instruction::Jeq(Inpt::Const(0xab)), &mut mem);
+    #[test]
+    fn breakpoint_at_entry_point() {
+        let mut mem = load(&count_to_three());
+        let mut cpu = Cpu::default();
+        cpu.add_breakpoint(0);
 
-        assert!(cpu.flags & Cpu::FLAG_EQUAL == 0);
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Breakpoint(0));
         assert_eq!(cpu.ip, 0);
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
     }
 
     #[test]
-    fn jne_after_not_equal_number_comparison() {
-        let mut cpu = Cpu::vals(3, -3, 0);
-        let mut mem = Mem::default();
+    fn run_stops_at_breakpoint() {
+        let prog = count_to_three();
+        let cmp = addr_of(&prog, 3) as usize;
+        let mut mem = load(&prog);
+        let mut cpu = Cpu::default();
+        cpu.add_breakpoint(cmp);
 
-        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem);
-        cpu.execute(Instruction::Jne(Inpt::Const(0xab)), &mut mem);
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Breakpoint(cmp));
+        assert_eq!(cpu.c, 1);
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Breakpoint(cmp));
+        assert_eq!(cpu.c, 2);
 
-        assert!(cpu.flags & Cpu::FLAG_EQUAL == 0);
-        assert_eq!(cpu.ip, 0xab);
+        assert!(cpu.remove_breakpoint(cmp));
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+        assert_eq!(cpu.c, 3);
+    }
+
+    /// Assembles `src` with the stack at 0xc0..0x100 and the vector table
+    /// at 0x80, IRQ 0 going to the `handler` label.
+    fn interrupt_machine(src: &str) -> (Cpu, Mem) {
+        let program = crate::asm::parse_program(src).unwrap();
+        let mut mem = Mem::new(0x100);
+        mem.load(0, &encode_all(&program.instructions));
+        mem.set_ivt(0x80);
+        mem.set_vector(crate::pic::DEFAULT_BASE, 0, program.labels["handler"])
+            .unwrap();
+
+        let mut cpu = Cpu::default();
+        cpu.set_stack(0x100, 0x40);
+        (cpu, mem)
     }
 
     #[test]
-    fn jne_doesnt_jmp_if_flag_eq_set() {
-        let mut cpu = Cpu::vals(4, 4, 0);
-        let mut mem = Mem::default();
+    fn irq_enters_handler_and_iret_returns() {
+        let (mut cpu, mut mem) = interrupt_machine(
+            "
+            sti
+        loop:
+            sum b c
+            jmp loop
+        handler:
+            ld 7 d
+            iret
+            ",
+        );
 
-        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem);
-        cpu.execute(Instruction::Jne(Inpt::Const(0xab)), &mut mem);
+        cpu.step(&mut mem);
+        assert_eq!(cpu.flags, Cpu::FLAG_INTERRUPT);
+        cpu.pic_mut().raise(0);
 
-        assert!(cpu.flags & Cpu::FLAG_EQUAL == Cpu::FLAG_EQUAL);
-        assert_eq!(cpu.ip, 0);
+        // the handler's first instruction runs in the same step
+        assert_eq!(cpu.step(&mut mem), None);
+        assert_eq!(cpu.d, 7);
+        assert_eq!(cpu.flags & Cpu::FLAG_INTERRUPT, 0);
+        assert_eq!(cpu.sp, 0xfa);
+        assert_eq!(mem.read_16(0xfe), Ok(Cpu::FLAG_INTERRUPT));
+        assert_eq!(mem.read_16(0xfc), Ok(0));
+        assert_eq!(mem.read_16(0xfa), Ok(1));
+
+        assert_eq!(cpu.step(&mut mem), None);
+        assert_eq!(cpu.ip, 1);
+        assert_eq!(cpu.sp, 0x100);
+        assert_eq!(cpu.flags, Cpu::FLAG_INTERRUPT);
+        assert_eq!(cpu.pic().pending(), 0);
     }
 
     #[test]
-    fn jgt_with_greater_than_flag_set() {
-        let mut cpu = Cpu::vals(4, 7, 0);
-        let mut mem = Mem::default();
+    fn irq_waits_while_disabled_or_masked() {
+        let (mut cpu, mut mem) = interrupt_machine(
+            "
+            sum b c
+            sti
+            sum b c
+            sum b c
+        handler:
+            hlt
+            ",
+        );
+        cpu.pic_mut().mask(0);
+        cpu.pic_mut().raise(0);
 
-        cpu.execute(Instruction::Cmp(Reg::B, Reg::A), &mut mem);
-        cpu.execute(Instruction::Jgt(Inpt::Const(0xab)), &mut mem);
+        cpu.step(&mut mem);
+        cpu.step(&mut mem);
+        cpu.step(&mut mem);
+        assert_eq!(cpu.sp, 0x100);
 
-        assert!(cpu.flags & Cpu::FLAG_GREATER_THAN == Cpu::FLAG_GREATER_THAN);
-        assert_eq!(cpu.ip, 0xab);
+        cpu.pic_mut().unmask(0);
+        assert_eq!(cpu.step(&mut mem), Some(StopReason::Halt));
+        assert_eq!(cpu.sp, 0xfa);
     }
 
     #[test]
-    fn jgt_without_greater_than_flag_set() {
-        let mut cpu = Cpu::vals(4, 4, 0);
-        let mut mem = Mem::default();
+    fn hlt_waits_for_irq() {
+        let (mut cpu, mut mem) = interrupt_machine(
+            "
+            sti
+            hlt
+            ld 1 a
+            cli
+            hlt
+        handler:
+            ld 5 d
+            iret
+            ",
+        );
+        let timer = cpu.pic().line(0);
+        cpu.schedule(
+            1000,
+            Box::new(move |_: &mut Cpu, _: &mut dyn Bus| timer.raise()),
+        );
 
-        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem);
-        cpu.execute(Instruction::Jgt(Inpt::Const(0xab)), &mut mem);
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+        assert_eq!(cpu.d, 5);
+        assert_eq!(cpu.a, 1);
+        assert!(cpu.cycles() > 1000);
 
-        assert!(cpu.flags & Cpu::FLAG_GREATER_THAN == 0);
-        assert_eq!(cpu.ip, 0);
+        // nothing left that could wake it up
+        assert_eq!(cpu.step(&mut mem), Some(StopReason::Halt));
     }
 
     #[test]
-    fn jlt_with_lower_than_flag_set() {
-        let mut cpu = Cpu::vals(4, 7, 0);
-        let mut mem = Mem::default();
+    fn irq_without_stack_faults() {
+        let (mut cpu, mut mem) = interrupt_machine("sti\nhandler:\nhlt");
+        cpu.set_stack(0xc0, 0);
+        cpu.step(&mut mem);
+        cpu.pic_mut().raise(0);
+
+        assert_eq!(
+            cpu.step(&mut mem),
+            Some(StopReason::Fault(Fault::StackOverflow))
+        );
+        assert_eq!(cpu.pic().pending(), 1);
+        assert_eq!(cpu.sp, 0xc0);
+    }
 
-        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem);
-        cpu.execute(Instruction::Jlt(Inpt::Const(0xab)), &mut mem);
+    #[test]
+    fn irq_with_partial_frame_room_stays_pending() {
+        let (mut cpu, mut mem) = interrupt_machine("sti\nhandler:\nhlt");
+        cpu.set_stack(0xc0, 4);
+        cpu.step(&mut mem);
+        cpu.pic_mut().raise(0);
+
+        assert_eq!(
+            cpu.step(&mut mem),
+            Some(StopReason::Fault(Fault::StackOverflow))
+        );
+        assert_eq!(cpu.pic().pending(), 1);
+        assert_eq!(cpu.sp, 0xc0);
+        assert_eq!(mem.peek_16(0xbe), Ok(0));
 
-        assert!(cpu.flags & Cpu::FLAG_LOWER_THAN == Cpu::FLAG_LOWER_THAN);
-        assert_eq!(cpu.ip, 0xab);
+        // with room for the frame the same IRQ is delivered
+        cpu.set_stack(0xc0, 6);
+        assert_eq!(cpu.step(&mut mem), Some(StopReason::Halt));
+        assert_eq!(cpu.pic().pending(), 0);
+        assert_eq!(cpu.sp, 0xba);
+    }
+
+    fn bytes(mem: &Mem) -> Vec<u8> {
+        (0..mem.len()).map(|a| mem.peek(a).unwrap()).collect()
     }
 
     #[test]
-    fn jlt_with_greater_than_flag_set() {
-        let mut cpu = Cpu::vals(6, 4, 0);
-        let mut mem = Mem::default();
+    fn step_back_undoes_everything() {
+        let (mut cpu, mut mem) = interrupt_machine(
+            "
+            sti
+            ld 0x1234 a
+            push a
+            ld a [0x40]
+            cmp a b
+            hlt
+        handler:
+            ld 7 d
+            iret
+            ",
+        );
+        cpu.enable_history(100);
+        let mut states = vec![(cpu.state(), bytes(&mem))];
+
+        for i in 0..7 {
+            if i == 2 {
+                cpu.pic_mut().raise(0);
+                states.pop();
+                states.push((cpu.state(), bytes(&mem)));
+            }
+            assert_eq!(cpu.step(&mut mem), None);
+            states.push((cpu.state(), bytes(&mem)));
+        }
+        assert_eq!(cpu.step(&mut mem), Some(StopReason::Halt));
+        assert_eq!(cpu.history().unwrap().len(), 8);
+
+        // back over the `hlt`, then every instruction and the interrupt
+        assert!(cpu.step_back(&mut mem));
+        while let Some((state, mem_bytes)) = states.pop() {
+            assert_eq!(cpu.state(), state);
+            assert_eq!(bytes(&mem), mem_bytes);
+            assert_eq!(cpu.step_back(&mut mem), !states.is_empty());
+        }
+    }
+
+    #[test]
+    fn bounded_history() {
+        let mut mem = load(&count_to_three());
+        let mut cpu = Cpu::default();
+        cpu.enable_history(2);
 
-        cpu.execute(Instruction::Cmp(Reg::A, Reg::B), &mut mem);
-        cpu.execute(Instruction::Jlt(Inpt::Const(0xab)), &mut mem);
+        cpu.run(&mut mem, 5);
+        let ip = cpu.ip;
+        assert!(cpu.step_back(&mut mem));
+        assert!(cpu.step_back(&mut mem));
+        assert!(!cpu.step_back(&mut mem));
+        assert_eq!(cpu.ip, addr_of(&count_to_three(), 3) as u16);
+        assert_ne!(cpu.ip, ip);
 
-        assert!(cpu.flags & Cpu::FLAG_LOWER_THAN == 0);
-        assert_eq!(cpu.ip, 0);
+        cpu.disable_history();
+        cpu.step(&mut mem);
+        assert!(!cpu.step_back(&mut mem));
     }
 
     #[test]
-    fn and() {
-        let mut cpu = Cpu::vals(0xffabu16 as i16, 0x00ff, 0);
-        let mut mem = Mem::default();
+    fn faults_and_halts_arent_recorded() {
+        let mut mem = load(&[Instruction::Ld(
+            GenerousInpt::Memory(0x1000),
+            Dest::Register(Reg::A),
+        )]);
+        let mut cpu = Cpu::default();
+        cpu.enable_history(10);
 
-        cpu.execute(Instruction::And(Reg::A, Reg::B), &mut mem);
-        assert_eq!(cpu.b, 0x00ab);
+        assert!(matches!(cpu.step(&mut mem), Some(StopReason::Fault(_))));
+        assert!(cpu.history().unwrap().is_empty());
     }
 
     #[test]
-    fn or() {
-        let mut cpu = Cpu::vals(0xff00u16 as i16, 0x00ff, 0);
-        let mut mem = Mem::default();
+    fn reverse_continue_to_breakpoint() {
+        let prog = count_to_three();
+        let cmp = addr_of(&prog, 3) as usize;
+        let mut mem = load(&prog);
+        let mut cpu = Cpu::default();
+        cpu.enable_history(100);
+        cpu.add_breakpoint(cmp);
 
-        cpu.execute(Instruction::Or(Reg::A, Reg::B), &mut mem);
-        assert_eq!(cpu.b, 0xffffu16 as i16);
+        cpu.remove_breakpoint(cmp);
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+        cpu.add_breakpoint(cmp);
+
+        assert_eq!(cpu.reverse_continue(&mut mem), ReverseStop::Breakpoint(cmp));
+        assert_eq!(cpu.c, 3);
+        assert_eq!(cpu.reverse_continue(&mut mem), ReverseStop::Breakpoint(cmp));
+        assert_eq!(cpu.c, 2);
+        assert_eq!(cpu.reverse_continue(&mut mem), ReverseStop::Breakpoint(cmp));
+        assert_eq!(cpu.c, 1);
+        assert_eq!(cpu.reverse_continue(&mut mem), ReverseStop::Start);
+        assert_eq!((cpu.ip, cpu.a), (0, 0));
     }
 
     #[test]
-    fn not() {
-        let mut cpu = Cpu::vals(0xff00u16 as i16, 0, 0);
-        let mut mem = Mem::default();
+    fn back_to_last_write() {
+        let prog = [
+            Instruction::Ld(GenerousInpt::Const(1), Dest::Memory(0x30)),
+            Instruction::Ld(GenerousInpt::Const(2), Dest::Memory(0x32)),
+            Instruction::Ld(GenerousInpt::Const(3), Dest::Memory(0x30)),
+            Instruction::Not(Reg::A),
+            Instruction::Hlt,
+        ];
+        let mut mem = load(&prog);
+        let mut cpu = Cpu::default();
+        cpu.enable_history(100);
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
 
-        cpu.execute(Instruction::Not(Reg::A), &mut mem);
-        assert_eq!(cpu.a, 0x00ff);
+        let third = addr_of(&prog, 2) as usize;
+        assert_eq!(cpu.back_to_write(&mut mem, 0x31), ReverseStop::Write(third));
+        assert_eq!(mem.peek(0x31), Ok(1));
+        assert_eq!(mem.peek(0x33), Ok(2));
+        assert_eq!(cpu.a, 0);
+
+        assert_eq!(cpu.back_to_write(&mut mem, 0x31), ReverseStop::Write(0));
+        assert_eq!(mem.peek(0x31), Ok(0));
+        assert_eq!(mem.peek(0x33), Ok(0));
+
+        assert_eq!(cpu.back_to_write(&mut mem, 0x31), ReverseStop::Start);
     }
+}
 
-    #[test]
-    fn xor() {
-        let mut cpu = Cpu::vals(0b1001, 0, 0);
-        let mut mem = Mem::default();
+#[cfg(test)]
+mod segment_tests {
+    use super::*;
+    use crate::asm;
 
-        cpu.execute(Instruction::Xor(Reg::A, Reg::B), &mut mem);
-        assert_eq!(cpu.b, 0b1001 ^ 0);
+    /// `src` is loaded at `segment:0`.
+    fn load_at(segment: u16, src: &str) -> Mem {
+        let mut mem = Mem::new(0x1000);
+        mem.load(physical(segment, 0), &asm::assemble(src).unwrap());
+        mem
     }
 
     #[test]
-    fn shr() {
-        let mut cpu = Cpu::vals(0b10, 0xff, 0);
-        let mut mem = Mem::default();
-
-        cpu.execute(Instruction::Shr(Inpt::Const(1), Reg::A), &mut mem);
-        cpu.execute(Instruction::Shr(Inpt::Const(10), Reg::B), &mut mem);
-        assert_eq!(cpu.a, 1);
-        assert_eq!(cpu.b, 0);
+    fn physical_addresses() {
+        assert_eq!(physical(0x1234, 0x0010), 0x12350);
+        assert_eq!(physical(0x0000, 0xffff), 0xffff);
+        assert_eq!(physical(0xffff, 0x0010), 0);
     }
 
     #[test]
-    fn shl() {
-        let mut cpu = Cpu::vals(0b10, 0xff, 0);
-        let mut mem = Mem::default();
+    fn fetch_from_cs() {
+        let mut mem = load_at(0x10, "ld 3 a\nhlt");
+        let mut cpu = Cpu::default();
+        cpu.seg_write(SegReg::CS, 0x10);
 
-        cpu.execute(Instruction::Shl(Inpt::Const(1), Reg::A), &mut mem);
-        cpu.execute(Instruction::Shl(Inpt::Const(10), Reg::B), &mut mem);
-        assert_eq!(cpu.a, 4);
-        assert_eq!(cpu.b, 0xff << 10);
+        assert_eq!(cpu.pc(), 0x100);
+        assert_eq!(cpu.run(&mut mem, 10), StopReason::Halt);
+        assert_eq!(cpu.a, 3);
+        assert_eq!(cpu.ip, 7);
     }
 
     #[test]
-    fn push() {
-        let mut cpu = Cpu {
-            stack_size: 4,
-            ..Default::default()
-        };
-        let mut mem = Mem::default();
-
-        cpu.execute(Instruction::Push(Inpt::Const(45)), &mut mem);
+    fn data_in_ds_with_override() {
+        let mut mem = load_at(
+            0,
+            "
+            ld 0x20 ds
+            ld 0x30 es
+            ld 0x1234 [0x0002]
+            seg es
+            ld [0x0002] b
+            ld [0x0002] c
+            hlt
+            ",
+        );
+        mem.write_16(0x302, 0x5678).unwrap();
+        let mut cpu = Cpu::default();
 
-        assert_eq!(cpu.sp, 2);
-        assert_eq!(mem.read(0), 0);
-        assert_eq!(mem.read(1), 45);
+        assert_eq!(cpu.run(&mut mem, 10), StopReason::Halt);
+        assert_eq!(mem.read_16(0x202), Ok(0x1234));
+        assert_eq!(cpu.b, 0x5678);
+        assert_eq!(cpu.c, 0x1234);
     }
 
     #[test]
-    fn push_with_overflow() {
-        let mut cpu = Cpu {
-            stack_size: 2,
-            ..Default::default()
-        };
-        let mut mem = Mem::default();
+    fn no_irq_after_prefix() {
+        let mut mem = load_at(0, "sti\nseg es\nld [0x0000] a\nhlt");
+        let mut cpu = Cpu::default();
+        cpu.set_stack(0x800, 0x10);
 
-        cpu.execute(Instruction::Push(Inpt::Const(45)), &mut mem);
-        cpu.execute(Instruction::Push(Inpt::Const(45)), &mut mem);
+        cpu.step(&mut mem);
+        cpu.step(&mut mem);
+        cpu.pic_mut().raise(0);
+        cpu.step(&mut mem);
 
-        assert_eq!(mem.read(0), 0);
-        assert_eq!(mem.read(1), 45);
-        assert_eq!(cpu.sp, 0);
-        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+        assert_eq!(cpu.sp, 0x800);
+        assert_eq!(cpu.seg_override, None);
     }
 
     #[test]
-    fn pop() {
-        let mut cpu = Cpu {
-            ss: 0,
-            sp: 6,
-            stack_size: 8,
-            ..Default::default()
-        };
-        let mut mem = Mem::set(vec![0, 0, 255, 251, 0, 45, 0, 12]);
+    fn stack_in_ss() {
+        let mut mem = Mem::new(0x1000);
+        let mut cpu = Cpu::default();
+        cpu.seg_write(SegReg::SS, 0x80);
+        cpu.set_stack(0x10, 4);
 
-        cpu.execute(Instruction::Pop(Reg::A), &mut mem);
-        cpu.execute(Instruction::Pop(Reg::B), &mut mem);
-        cpu.execute(Instruction::Pop(Reg::C), &mut mem);
+        cpu.execute(Instruction::Push(Inpt::Const(0x0102)), &mut mem)
+            .unwrap();
+        assert_eq!(mem.read_16(0x80e), Ok(0x0102));
+        assert_eq!(cpu.sp, 0x0e);
+    }
 
-        assert_eq!(cpu.sp, 0);
-        assert_eq!(cpu.a, 12);
-        assert_eq!(cpu.b, 45);
-        assert_eq!(cpu.c, -5);
+    #[test]
+    fn far_call_and_jump() {
+        let mut mem = load_at(0, "callf 0x20 0\nld cs d\nhlt");
+        mem.load(0x200, &asm::assemble("ld cs c\njmpf 0x30 0").unwrap());
+        mem.load(0x300, &asm::assemble("ld 1 a\nretf").unwrap());
+        let mut cpu = Cpu::default();
+        cpu.set_stack(0x800, 0x10);
+
+        assert_eq!(cpu.run(&mut mem, 10), StopReason::Halt);
+        assert_eq!((cpu.a, cpu.c, cpu.d), (1, 0x20, 0));
+        assert_eq!(cpu.cs, 0);
+        assert_eq!(cpu.ip, 11);
+        assert_eq!(cpu.sp, 0x800);
     }
 
     #[test]
-    fn pop_with_overflow() {
-        let mut cpu = Cpu {
-            ss: 0,
-            sp: 2,
-            ..Default::default()
-        };
-        let mut mem = Mem::set(vec![0, 0, 0, 45]);
+    fn irq_handler_in_other_segment() {
+        let mut mem = load_at(0, "sti\nsum b c\nhlt");
+        mem.load(0x400, &asm::assemble("ld cs a\niret").unwrap());
+        mem.set_ivt(0x100);
+        mem.set_vector(crate::pic::DEFAULT_BASE, 0x40, 0).unwrap();
+        let mut cpu = Cpu::default();
+        cpu.set_stack(0x800, 0x10);
 
-        cpu.execute(Instruction::Pop(Reg::A), &mut mem);
-        cpu.execute(Instruction::Pop(Reg::C), &mut mem);
+        cpu.step(&mut mem);
+        cpu.pic_mut().raise(0);
 
-        assert_eq!(cpu.sp, 0);
-        assert_eq!(cpu.a, 45);
-        assert_eq!(cpu.c, 0);
-        assert!(cpu.flags & Cpu::FLAG_OVERFLOW != 0);
+        assert_eq!(cpu.run(&mut mem, 10), StopReason::Halt);
+        assert_eq!(cpu.a, 0x40);
+        assert_eq!(cpu.cs, 0);
+        assert_eq!(cpu.sp, 0x800);
     }
 }
@@ -0,0 +1,843 @@
+//! Interactive debugger, started with `cpu_sim debug program.asm`.
+
+#![allow(dead_code)]
+
+use crate::asm::{self, AsmError};
+use crate::cpu::*;
+use crate::disasm;
+use crate::history::ReverseStop;
+use crate::machine::{Machine, MachineBuilder};
+use crate::pic;
+use crate::snapshot;
+use crate::syscall;
+use crate::trace::{Format, Tracer};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+
+/// Instructions `continue` runs before giving control back.
+const CONTINUE_LIMIT: usize = 1_000_000;
+
+/// Instructions that can be stepped back over.
+const HISTORY_LIMIT: usize = 100_000;
+
+const HELP: &str = "\
+step [n]                 execute n instructions (default 1)
+continue                 run until a breakpoint, halt or fault
+back [n]                 undo n instructions (default 1)
+rcontinue                run backwards until a breakpoint
+lastwrite <addr|label>   run backwards to the last write of a byte
+break <addr|label>       set a breakpoint
+delete <addr|label>      remove a breakpoint
+watch <addr> [len] [r|w|rw]
+                         stop on reads/writes of memory (default: 2 bytes, w)
+unwatch <addr> [len]     remove a watchpoint
+regs                     show registers and flags
+mem <addr> <len>         hexdump memory
+set reg <reg> <value>    set a register (a..dl, ip, sp, cs, ds, ss, es)
+set mem <addr> <byte>..  write bytes to memory, forgetting the history of
+                         back, rcontinue and lastwrite
+set overflow <wrap|saturate|zero|trap>
+                         what arithmetic does on overflow (default: zero)
+set strictness <off|warn|fault>
+                         what writing code, executing data or overflowing
+                         the stack into data does (default: warn)
+list [addr] [n]          disassemble n instructions (default: from ip)
+trace <file> [text|machine]
+                         write every executed instruction to file
+trace off                stop tracing
+irq <line>               raise an IRQ line (0-7)
+save <file>              save the machine state to a snapshot file
+load <file>              restore the machine state from a snapshot file
+quit                     exit the debugger
+";
+
+const FLAG_NAMES: [(u16, &str); 9] = [
+    (Cpu::FLAG_OVERFLOW, "OF"),
+    (Cpu::FLAG_ZERO, "ZF"),
+    (Cpu::FLAG_EQUAL, "EQ"),
+    (Cpu::FLAG_GREATER_THAN, "GT"),
+    (Cpu::FLAG_LOWER_THAN, "LT"),
+    (Cpu::FLAG_INTERRUPT, "IF"),
+    (Cpu::FLAG_CARRY, "CF"),
+    (Cpu::FLAG_SIGN, "SF"),
+    (Cpu::FLAG_PARITY, "PF"),
+];
+
+pub enum Reply {
+    Text(String),
+    Quit,
+}
+
+pub struct Debugger {
+    cpu: Cpu,
+    mem: Mem,
+    labels: HashMap<String, u16>,
+}
+
+impl Debugger {
+    /// Assembles `src` at address 0 of the default machine, with the
+    /// built-in syscalls connected to stdin/stdout.
+    pub fn new(src: &str) -> Result<Self, AsmError> {
+        let program = asm::parse_program(src)?;
+        let bytes = crate::encoding::encode_all(&program.instructions);
+
+        let Machine { mut cpu, mut mem } = MachineBuilder::new()
+            .program(bytes.len())
+            .build()
+            .expect("the default machine is valid");
+        mem.load(0, &bytes);
+        syscall::install_std(&mut cpu);
+        cpu.enable_history(HISTORY_LIMIT);
+
+        Ok(Debugger::from_parts(cpu, mem, program.labels))
+    }
+
+    pub fn from_parts(cpu: Cpu, mem: Mem, labels: HashMap<String, u16>) -> Self {
+        Debugger { cpu, mem, labels }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn mem(&self) -> &Mem {
+        &self.mem
+    }
+
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut out: W) -> io::Result<()> {
+        write!(out, "{}", self.current())?;
+        write!(out, "(dbg) ")?;
+        out.flush()?;
+
+        for line in input.lines() {
+            match self.command(&line?) {
+                Ok(Reply::Quit) => return Ok(()),
+                Ok(Reply::Text(text)) => write!(out, "{}", text)?,
+                Err(e) => writeln!(out, "error: {}", e)?,
+            }
+
+            write!(out, "(dbg) ")?;
+            out.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn command(&mut self, line: &str) -> Result<Reply, String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let (&cmd, args) = match words.split_first() {
+            Some(split) => split,
+            None => return Ok(Reply::Text(String::new())),
+        };
+
+        let text = match cmd {
+            "step" | "s" => self.step(args)?,
+            "continue" | "c" => self.cont(args)?,
+            "back" => self.back(args)?,
+            "rcontinue" | "rc" => self.reverse_cont(args)?,
+            "lastwrite" => self.last_write(args)?,
+            "break" | "b" => self.set_break(args, true)?,
+            "delete" | "d" => self.set_break(args, false)?,
+            "watch" | "w" => self.watch(args)?,
+            "unwatch" => self.unwatch(args)?,
+            "regs" | "r" => self.regs(args)?,
+            "mem" | "m" => self.hexdump(args)?,
+            "set" => self.set(args)?,
+            "list" | "l" => self.list(args)?,
+            "trace" => self.trace(args)?,
+            "irq" => self.irq(args)?,
+            "save" => self.save(args)?,
+            "load" => self.load(args)?,
+            "help" | "h" => HELP.to_string(),
+            "quit" | "q" => return Ok(Reply::Quit),
+            _ => return Err(format!("unknown command `{}`, try `help`", cmd)),
+        };
+
+        Ok(Reply::Text(text))
+    }
+
+    fn expect_args(args: &[&str], min: usize, max: usize) -> Result<(), String> {
+        if args.len() < min || args.len() > max {
+            return Err("wrong number of arguments, try `help`".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn number(s: &str) -> Result<i16, String> {
+        asm::parse_number(s).map_err(|e| e.to_string())
+    }
+
+    /// Addresses, and values in general, can be a number or a label.
+    fn addr(&self, s: &str) -> Result<u16, String> {
+        match self.labels.get(s) {
+            Some(&addr) => Ok(addr),
+            None => Self::number(s).map(|n| n as u16),
+        }
+    }
+
+    /// The instruction at `cs:ip`.
+    fn current(&self) -> String {
+        let ip = self.cpu.pc();
+        match disasm::disassemble(&self.mem, ip, ip + 8).first() {
+            Some(line) => format!("=> {}\n", line),
+            None => format!("=> {:04x}  <out of memory>\n", ip),
+        }
+    }
+
+    fn stopped(&self, reason: Option<StopReason>) -> String {
+        let mut text = String::new();
+        for v in self.mem.take_violations() {
+            let _ = writeln!(text, "warning: {}", v);
+        }
+
+        text += &match reason {
+            None => String::new(),
+            Some(StopReason::Halt) => "halted\n".to_string(),
+            Some(StopReason::Exit(status)) => format!("exited with status {}\n", status),
+            Some(StopReason::Fault(f)) => format!("fault: {}\n", f),
+            Some(StopReason::Limit) => {
+                format!("stopped after {} instructions\n", CONTINUE_LIMIT)
+            }
+            Some(StopReason::Breakpoint(addr)) => format!("breakpoint at {:#06x}\n", addr),
+            Some(StopReason::Watchpoint(hit)) => {
+                let access = match hit.access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                };
+                let width = hit.size * 2 + 2;
+                format!(
+                    "watchpoint: {} at {:#06x}: {:#0w$x} -> {:#0w$x}\n",
+                    access,
+                    hit.addr,
+                    hit.old,
+                    hit.new,
+                    w = width
+                )
+            }
+        };
+
+        if !self.cpu.halted() {
+            text.push_str(&self.current());
+        }
+
+        text
+    }
+
+    fn step(&mut self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 0, 1)?;
+        let n = match args.first() {
+            Some(n) => n.parse::<usize>().map_err(|e| e.to_string())?,
+            None => 1,
+        };
+
+        let mut reason = None;
+        for _ in 0..n {
+            reason = self.cpu.step(&mut self.mem);
+            if reason.is_some() {
+                break;
+            }
+        }
+
+        Ok(self.stopped(reason))
+    }
+
+    fn cont(&mut self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 0, 0)?;
+        let reason = self.cpu.run(&mut self.mem, CONTINUE_LIMIT);
+        Ok(self.stopped(Some(reason)))
+    }
+
+    fn reversed(&self, stop: ReverseStop) -> String {
+        let text = match stop {
+            ReverseStop::Breakpoint(addr) => format!("breakpoint at {:#06x}\n", addr),
+            ReverseStop::Write(addr) => format!("written by the instruction at {:#06x}\n", addr),
+            ReverseStop::Start => "no more history\n".to_string(),
+        };
+        text + &self.current()
+    }
+
+    fn back(&mut self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 0, 1)?;
+        let n = match args.first() {
+            Some(n) => n.parse::<usize>().map_err(|e| e.to_string())?,
+            None => 1,
+        };
+
+        for _ in 0..n {
+            if !self.cpu.step_back(&mut self.mem) {
+                return Ok(self.reversed(ReverseStop::Start));
+            }
+        }
+
+        Ok(self.current())
+    }
+
+    fn reverse_cont(&mut self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 0, 0)?;
+        let stop = self.cpu.reverse_continue(&mut self.mem);
+        Ok(self.reversed(stop))
+    }
+
+    fn last_write(&mut self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 1, 1)?;
+        let addr = self.addr(args[0])?;
+        let stop = self.cpu.back_to_write(&mut self.mem, addr.into());
+        Ok(self.reversed(stop))
+    }
+
+    fn set_break(&mut self, args: &[&str], set: bool) -> Result<String, String> {
+        Self::expect_args(args, 1, 1)?;
+        let addr = self.addr(args[0])?;
+
+        if set {
+            self.cpu.add_breakpoint(addr.into());
+            Ok(format!("breakpoint set at {:#06x}\n", addr))
+        } else if self.cpu.remove_breakpoint(addr.into()) {
+            Ok(format!("breakpoint at {:#06x} deleted\n", addr))
+        } else {
+            Err(format!("no breakpoint at {:#06x}", addr))
+        }
+    }
+
+    fn watch_range(&self, args: &[&str]) -> Result<std::ops::Range<usize>, String> {
+        let start = self.addr(args[0])? as usize;
+        let len = match args.get(1) {
+            Some(len) => Self::number(len)? as u16 as usize,
+            None => 2,
+        };
+
+        Ok(start..start + len)
+    }
+
+    fn watch(&mut self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 1, 3)?;
+        let range = self.watch_range(args)?;
+        let kind = match args.get(2) {
+            Some(&"r") => WatchKind::Read,
+            Some(&"w") | None => WatchKind::Write,
+            Some(&"rw") => WatchKind::Access,
+            Some(k) => return Err(format!("unknown watchpoint kind `{}`", k)),
+        };
+
+        let text = format!(
+            "watchpoint set at {:#06x}..{:#06x}\n",
+            range.start, range.end
+        );
+        self.mem.add_watchpoint(range, kind);
+        Ok(text)
+    }
+
+    fn unwatch(&mut self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 1, 2)?;
+        let range = self.watch_range(args)?;
+
+        if self.mem.remove_watchpoint(range.clone()) {
+            Ok(format!(
+                "watchpoint at {:#06x}..{:#06x} deleted\n",
+                range.start, range.end
+            ))
+        } else {
+            Err(format!(
+                "no watchpoint at {:#06x}..{:#06x}",
+                range.start, range.end
+            ))
+        }
+    }
+
+    fn trace(&mut self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 1, 2)?;
+        if let Some(mut tracer) = self.cpu.detach_tracer() {
+            tracer.flush().map_err(|e| e.to_string())?;
+        }
+
+        if args == ["off"] {
+            return Ok("tracing stopped\n".to_string());
+        }
+
+        let format = match args.get(1) {
+            Some(&"text") | None => Format::Text,
+            Some(&"machine") => Format::Machine,
+            Some(f) => return Err(format!("unknown trace format `{}`", f)),
+        };
+        let file = File::create(args[0]).map_err(|e| format!("{}: {}", args[0], e))?;
+
+        self.cpu
+            .attach_tracer(Tracer::new(Box::new(BufWriter::new(file)), format));
+        Ok(format!("tracing to {}\n", args[0]))
+    }
+
+    fn irq(&mut self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 1, 1)?;
+        let line = Self::number(args[0])?;
+        if !(0..pic::LINES as i16).contains(&line) {
+            return Err(format!("no IRQ line {}", line));
+        }
+
+        self.cpu.pic_mut().raise(line as u8);
+        Ok(format!("IRQ {} raised\n", line))
+    }
+
+    fn save(&self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 1, 1)?;
+        let file = File::create(args[0]).map_err(|e| format!("{}: {}", args[0], e))?;
+        snapshot::save(&self.cpu, &self.mem, BufWriter::new(file))
+            .map_err(|e| format!("{}: {}", args[0], e))?;
+        Ok(format!("saved to {}\n", args[0]))
+    }
+
+    /// Breakpoints are kept, watchpoints belong to the memory being replaced.
+    fn load(&mut self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 1, 1)?;
+        let file = File::open(args[0]).map_err(|e| format!("{}: {}", args[0], e))?;
+        self.mem = snapshot::restore(&mut self.cpu, io::BufReader::new(file))
+            .map_err(|e| format!("{}: {}", args[0], e))?;
+        if self.cpu.history().is_some() {
+            self.cpu.enable_history(HISTORY_LIMIT);
+        }
+        Ok(format!("loaded {}\n{}", args[0], self.current()))
+    }
+
+    fn regs(&self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 0, 0)?;
+        let mut text = String::new();
+
+        for (name, reg, h, l) in [
+            ("a", Reg::A, Reg::AH, Reg::AL),
+            ("b", Reg::B, Reg::BH, Reg::BL),
+            ("c", Reg::C, Reg::CH, Reg::CL),
+            ("d", Reg::D, Reg::DH, Reg::DL),
+        ] {
+            let v = self.cpu.reg_read(reg);
+            writeln!(
+                text,
+                "{}  {:#06x}  {}h {:#04x}  {}l {:#04x}  {}",
+                name,
+                v as u16,
+                name,
+                self.cpu.reg_read(h) as u8,
+                name,
+                self.cpu.reg_read(l) as u8,
+                v
+            )
+            .unwrap();
+        }
+
+        let flags = self.cpu.flags();
+        let names = FLAG_NAMES
+            .iter()
+            .filter(|(bit, _)| flags & bit != 0)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+
+        writeln!(text, "ip {:#06x}  sp {:#06x}", self.cpu.ip(), self.cpu.sp()).unwrap();
+        writeln!(
+            text,
+            "cs {:#06x}  ds {:#06x}  ss {:#06x}  es {:#06x}",
+            self.cpu.seg_read(SegReg::CS),
+            self.cpu.seg_read(SegReg::DS),
+            self.cpu.seg_read(SegReg::SS),
+            self.cpu.seg_read(SegReg::ES)
+        )
+        .unwrap();
+        writeln!(text, "flags {:#011b} [{}]", flags, names.join(" ")).unwrap();
+        writeln!(text, "cycles {}", self.cpu.cycles()).unwrap();
+
+        Ok(text)
+    }
+
+    fn hexdump(&self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 2, 2)?;
+        let start = self.addr(args[0])? as usize;
+        let len = Self::number(args[1])? as u16 as usize;
+        let end = (start + len).min(self.mem.len());
+
+        let mut text = String::new();
+        for row in (start..end).step_by(16) {
+            let bytes = (row..(row + 16).min(end))
+                .map(|i| self.mem.peek(i).unwrap())
+                .collect::<Vec<_>>();
+            let hex = bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = bytes
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+
+            writeln!(text, "{:04x}  {:<47}  |{}|", row, hex, ascii).unwrap();
+        }
+
+        Ok(text)
+    }
+
+    fn set(&mut self, args: &[&str]) -> Result<String, String> {
+        match args.first() {
+            Some(&"reg") => {
+                Self::expect_args(args, 3, 3)?;
+                let val = self.addr(args[2])? as i16;
+                match args[1] {
+                    "ip" => self.cpu.set_ip(val as u16),
+                    "sp" => self.cpu.set_sp(val as u16),
+                    name => match (asm::parse_seg(name), asm::parse_reg(name)) {
+                        (Some(seg), _) => self.cpu.seg_write(seg, val as u16),
+                        (_, Some(reg)) => self.cpu.reg_write(reg, val),
+                        _ => return Err(format!("unknown register `{}`", name)),
+                    },
+                }
+            }
+            Some(&"mem") => {
+                Self::expect_args(args, 3, usize::MAX)?;
+                let addr = self.addr(args[1])? as usize;
+                let bytes = args[2..]
+                    .iter()
+                    .map(|b| Self::number(b).map(|b| b as u8))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                // all the bytes or none of them
+                let end = addr + bytes.len();
+                if end > self.mem.len() {
+                    let fault = MemFault {
+                        addr: self.mem.len().max(addr),
+                        kind: MemFaultKind::OutOfBounds,
+                    };
+                    return Err(fault.to_string());
+                }
+                for (i, &byte) in bytes.iter().enumerate() {
+                    self.mem.poke(addr + i, byte).map_err(|e| e.to_string())?;
+                }
+                // undoing instructions over bytes changed by hand would bring
+                // back a state that never was
+                if self.cpu.history().is_some() {
+                    self.cpu.enable_history(HISTORY_LIMIT);
+                }
+            }
+            Some(&"overflow") => {
+                Self::expect_args(args, 2, 2)?;
+                let policy = match args[1] {
+                    "wrap" => OverflowPolicy::Wrap,
+                    "saturate" => OverflowPolicy::Saturate,
+                    "zero" => OverflowPolicy::Zero,
+                    "trap" => OverflowPolicy::Trap,
+                    other => return Err(format!("unknown overflow policy `{}`", other)),
+                };
+                self.cpu.set_overflow_policy(policy);
+            }
+            Some(&"strictness") => {
+                Self::expect_args(args, 2, 2)?;
+                let strictness = match args[1] {
+                    "off" => Strictness::Off,
+                    "warn" => Strictness::Warn,
+                    "fault" => Strictness::Fault,
+                    other => return Err(format!("unknown strictness `{}`", other)),
+                };
+                self.mem.set_strictness(strictness);
+            }
+            _ => {
+                return Err(
+                    "expected `set reg`, `set mem`, `set overflow` or `set strictness`".to_string(),
+                )
+            }
+        }
+
+        Ok(String::new())
+    }
+
+    fn list(&self, args: &[&str]) -> Result<String, String> {
+        Self::expect_args(args, 0, 2)?;
+        let start = match args.first() {
+            Some(a) => self.addr(a)?,
+            None => return self.list_from(self.cpu.pc(), args),
+        } as usize;
+        self.list_from(start, args)
+    }
+
+    fn list_from(&self, start: usize, args: &[&str]) -> Result<String, String> {
+        let n = match args.get(1) {
+            Some(n) => n.parse::<usize>().map_err(|e| e.to_string())?,
+            None => 8,
+        };
+
+        // instructions are at most 8 bytes long
+        let end = start.saturating_add(n.saturating_mul(8));
+        let lines = disasm::disassemble(&self.mem, start, end.min(self.mem.len()));
+        let mut text = String::new();
+        for line in lines.iter().take(n) {
+            let marker = if line.addr == self.cpu.pc() {
+                "=>"
+            } else {
+                "  "
+            };
+            writeln!(text, "{} {}", marker, line).unwrap();
+        }
+
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod debugger_tests {
+    use super::*;
+
+    const PROGRAM: &str = "
+        ld 3 a
+        ld 1 b
+    loop:
+        sum b c
+        cmp c a
+        jne loop
+    end:
+        hlt
+    ";
+
+    fn text(dbg: &mut Debugger, cmd: &str) -> String {
+        match dbg.command(cmd) {
+            Ok(Reply::Text(t)) => t,
+            Ok(Reply::Quit) => panic!("unexpected quit"),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn step_and_regs() {
+        let mut dbg = Debugger::new(PROGRAM).unwrap();
+
+        assert!(text(&mut dbg, "step 2").contains("sum b c"));
+        let regs = text(&mut dbg, "regs");
+        assert!(regs.contains("a  0x0003  ah 0x00  al 0x03  3"));
+        assert!(regs.contains("b  0x0001"));
+        assert!(regs.contains("ip 0x000c  sp 0xf000"));
+        assert!(regs.contains("cs 0x0000  ds 0x0000  ss 0x0000  es 0x0000"));
+        assert!(regs.contains("flags 0b000000000 []"));
+        assert!(regs.contains("cycles 8"));
+    }
+
+    #[test]
+    fn break_on_label_and_continue() {
+        let mut dbg = Debugger::new(PROGRAM).unwrap();
+
+        assert_eq!(text(&mut dbg, "break loop"), "breakpoint set at 0x000c\n");
+        assert!(text(&mut dbg, "continue").starts_with("breakpoint at 0x000c\n=> 000c"));
+        assert_eq!(dbg.cpu().reg_read(Reg::C), 0);
+        text(&mut dbg, "c");
+        assert_eq!(dbg.cpu().reg_read(Reg::C), 1);
+
+        text(&mut dbg, "delete loop");
+        assert_eq!(text(&mut dbg, "continue"), "halted\n");
+        assert_eq!(dbg.cpu().reg_read(Reg::C), 3);
+        assert!(text(&mut dbg, "regs").contains("[ZF EQ PF]"));
+    }
+
+    #[test]
+    fn watch() {
+        let src = "
+            ld 0x1234 a
+            ld a [0x200]
+            ld [0x200] b
+            hlt
+        ";
+        let mut dbg = Debugger::new(src).unwrap();
+
+        text(&mut dbg, "watch 0x200");
+        text(&mut dbg, "watch 0x201 1 r");
+        assert!(text(&mut dbg, "c").starts_with("watchpoint: write at 0x0200: 0x0000 -> 0x1234\n"));
+        assert!(text(&mut dbg, "c").starts_with("watchpoint: read at 0x0200: 0x1234 -> 0x1234\n"));
+
+        text(&mut dbg, "unwatch 0x201 1");
+        assert!(dbg.command("unwatch 0x201 1").is_err());
+        assert_eq!(text(&mut dbg, "c"), "halted\n");
+    }
+
+    #[test]
+    fn set_and_hexdump() {
+        let mut dbg = Debugger::new(PROGRAM).unwrap();
+
+        text(&mut dbg, "set mem 0x100 0x41 66 0");
+        text(&mut dbg, "set reg al 0x7f");
+        text(&mut dbg, "set reg ip end");
+
+        assert_eq!(
+            text(&mut dbg, "mem 0x100 3"),
+            format!("0100  {:<47}  |AB.|\n", "41 42 00")
+        );
+        assert_eq!(dbg.cpu().reg_read(Reg::A), 0x7f);
+        assert!(text(&mut dbg, "step").contains("halted"));
+
+        text(&mut dbg, "set overflow wrap");
+        assert_eq!(dbg.cpu().overflow_policy(), OverflowPolicy::Wrap);
+    }
+
+    #[test]
+    fn protections() {
+        let mut dbg = Debugger::new("ld 1 [0x0002]\nhlt").unwrap();
+        assert!(text(&mut dbg, "step").starts_with("warning: write at 0x0002 in `code`\n"));
+
+        let mut dbg = Debugger::new("ld 1 [0x0002]\nhlt").unwrap();
+        text(&mut dbg, "set strictness fault");
+        assert!(
+            text(&mut dbg, "step").starts_with("fault: write at address 0x0002 isn't allowed\n")
+        );
+
+        text(&mut dbg, "set mem 2 0xff");
+        assert_eq!(dbg.mem().peek(2), Ok(0xff));
+    }
+
+    #[test]
+    fn list() {
+        let mut dbg = Debugger::new(PROGRAM).unwrap();
+
+        let listing = text(&mut dbg, "list 0 3");
+        let lines = listing.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("=> 0000") && lines[0].ends_with("ld 3 a"));
+        assert!(lines[2].starts_with("   000c") && lines[2].ends_with("sum b c"));
+    }
+
+    #[test]
+    fn errors() {
+        let mut dbg = Debugger::new(PROGRAM).unwrap();
+
+        assert!(dbg.command("frobnicate").is_err());
+        assert!(dbg.command("break nowhere").is_err());
+        assert!(dbg.command("delete 4").is_err());
+        assert!(dbg.command("set reg x 1").is_err());
+        assert!(dbg.command("set overflow clamp").is_err());
+        assert!(dbg.command("set strictness loud").is_err());
+        assert!(dbg.command("set mem 0xffff 1 2").is_err());
+        assert_eq!(dbg.mem().peek(0xffff), Ok(0));
+        assert!(dbg.command("set mem 0x100 1 nope").is_err());
+        assert_eq!(dbg.mem().peek(0x100), Ok(0));
+        assert!(dbg.command("list 0 3000000000000000000").is_ok());
+        assert!(matches!(dbg.command("quit"), Ok(Reply::Quit)));
+    }
+
+    #[test]
+    fn repl() {
+        let mut dbg = Debugger::new(PROGRAM).unwrap();
+        let mut out = Vec::new();
+
+        dbg.repl("step\nbogus\nquit\nstep\n".as_bytes(), &mut out)
+            .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("=> 0000"));
+        assert!(out.contains("error: unknown command `bogus`"));
+        assert_eq!(out.matches("(dbg) ").count(), 3);
+        assert_eq!(dbg.cpu().ip(), 6);
+    }
+
+    #[test]
+    fn trace_to_file() {
+        let path = std::env::temp_dir().join(format!("cpu_sim_trace_{}", std::process::id()));
+        let mut dbg = Debugger::new(PROGRAM).unwrap();
+
+        assert!(dbg.command("trace out.txt json").is_err());
+        text(&mut dbg, &format!("trace {} machine", path.display()));
+        text(&mut dbg, "step 3");
+        assert_eq!(text(&mut dbg, "trace off"), "tracing stopped\n");
+        text(&mut dbg, "step");
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let ops = trace
+            .lines()
+            .map(|l| l.split('\t').nth(1).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ops, vec!["op=ld", "op=ld", "op=sum"]);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("cpu_sim_snapshot_{}", std::process::id()));
+        let mut dbg = Debugger::new(PROGRAM).unwrap();
+
+        text(&mut dbg, "step 3");
+        text(&mut dbg, "set mem 0x100 0x41");
+        text(&mut dbg, &format!("save {}", path.display()));
+        let state = dbg.cpu().state();
+
+        text(&mut dbg, "continue");
+        text(&mut dbg, "set mem 0x100 0");
+        assert!(text(&mut dbg, &format!("load {}", path.display())).contains("=> 000f"));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(dbg.cpu().state(), state);
+        assert_eq!(dbg.mem().peek(0x100), Ok(0x41));
+        assert!(dbg.command("load /nonexistent/snapshot").is_err());
+    }
+
+    #[test]
+    fn break_at_entry_point() {
+        let mut dbg = Debugger::new("ld 1 a\nhlt").unwrap();
+
+        text(&mut dbg, "break 0");
+        assert!(text(&mut dbg, "continue").starts_with("breakpoint at 0x0000\n"));
+        assert!(text(&mut dbg, "continue").starts_with("halted"));
+    }
+
+    #[test]
+    fn reverse() {
+        let mut dbg = Debugger::new(PROGRAM).unwrap();
+
+        assert!(text(&mut dbg, "continue").contains("halted"));
+        assert_eq!(dbg.cpu().reg_read(Reg::C), 3);
+        text(&mut dbg, "break 0x000f");
+
+        assert!(text(&mut dbg, "back").ends_with("hlt\n"));
+        assert!(text(&mut dbg, "back").ends_with("jne 0x000c\n"));
+        let stop = text(&mut dbg, "rcontinue");
+        assert!(stop.starts_with("breakpoint at 0x000f\n=> 000f"));
+        assert!(stop.ends_with("cmp c a\n"));
+        assert_eq!(dbg.cpu().reg_read(Reg::C), 3);
+        text(&mut dbg, "rc");
+        assert_eq!(dbg.cpu().reg_read(Reg::C), 2);
+
+        assert!(text(&mut dbg, "back 100").starts_with("no more history\n=> 0000"));
+        assert_eq!(dbg.cpu().reg_read(Reg::A), 0);
+    }
+
+    #[test]
+    fn last_write() {
+        let mut dbg = Debugger::new("ld 1 [0x100]\nld 2 [0x102]\nhlt").unwrap();
+
+        text(&mut dbg, "continue");
+        let stop = text(&mut dbg, "lastwrite 0x101");
+        assert!(stop.starts_with("written by the instruction at 0x0000\n=> 0000"));
+        assert_eq!(dbg.mem().peek(0x101), Ok(0));
+        assert_eq!(dbg.mem().peek(0x103), Ok(0));
+        assert!(text(&mut dbg, "lastwrite 0x101").starts_with("no more history"));
+
+        text(&mut dbg, "continue");
+        text(&mut dbg, "set mem 0x101 7");
+        assert!(text(&mut dbg, "back").starts_with("no more history"));
+        assert_eq!(dbg.mem().peek(0x101), Ok(7));
+        assert_eq!(dbg.mem().peek(0x103), Ok(2));
+    }
+
+    #[test]
+    fn raise_irq() {
+        let mut dbg = Debugger::new("sti\nloop:\njmp loop\nhandler:\nld 9 d\niret").unwrap();
+
+        // vector 8 points to `0000:handler`
+        text(&mut dbg, "set mem 0xf020 0x00 0x05 0x00 0x00");
+        text(&mut dbg, "step 2");
+        assert!(dbg.command("irq 8").is_err());
+        assert_eq!(text(&mut dbg, "irq 0"), "IRQ 0 raised\n");
+        text(&mut dbg, "step");
+
+        assert_eq!(dbg.cpu().reg_read(Reg::D), 9);
+        assert!(text(&mut dbg, "regs").contains("[]"));
+        text(&mut dbg, "step");
+        assert!(text(&mut dbg, "regs").contains("[IF]"));
+    }
+}
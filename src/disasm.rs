@@ -0,0 +1,413 @@
+//! Disassembler, turns machine code back into the README syntax.
+
+#![allow(dead_code)]
+
+use crate::bus::Bus;
+use crate::cpu::*;
+use crate::encoding;
+use std::fmt;
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Reg::A => "a",
+            Reg::AH => "ah",
+            Reg::AL => "al",
+            Reg::B => "b",
+            Reg::BH => "bh",
+            Reg::BL => "bl",
+            Reg::C => "c",
+            Reg::CH => "ch",
+            Reg::CL => "cl",
+            Reg::D => "d",
+            Reg::DH => "dh",
+            Reg::DL => "dl",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for SegReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SegReg::CS => "cs",
+            SegReg::DS => "ds",
+            SegReg::SS => "ss",
+            SegReg::ES => "es",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for Inpt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inpt::Const(c) => write!(f, "{}", c),
+            Inpt::Register(r) => write!(f, "{}", r),
+        }
+    }
+}
+
+impl fmt::Display for GenerousInpt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GenerousInpt::Const(c) => write!(f, "{}", c),
+            GenerousInpt::Register(r) => write!(f, "{}", r),
+            GenerousInpt::Memory(a) => write!(f, "[{:#06x}]", a),
+        }
+    }
+}
+
+impl fmt::Display for Dest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dest::Register(r) => write!(f, "{}", r),
+            Dest::Memory(a) => write!(f, "[{:#06x}]", a),
+        }
+    }
+}
+
+/// Jump targets and segments are addresses, so constants are shown in hex.
+struct Target(Inpt);
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Inpt::Const(c) => write!(f, "{:#06x}", c as u16),
+            Inpt::Register(r) => write!(f, "{}", r),
+        }
+    }
+}
+
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Ld(..) => "ld",
+            Instruction::Sum(..) => "sum",
+            Instruction::Sub(..) => "sub",
+            Instruction::Mul(..) => "mul",
+            Instruction::Div(..) => "div",
+            Instruction::And(..) => "and",
+            Instruction::Or(..) => "or",
+            Instruction::Not(..) => "not",
+            Instruction::Xor(..) => "xor",
+            Instruction::Shr(..) => "shr",
+            Instruction::Shl(..) => "shl",
+            Instruction::Sext(..) => "sext",
+            Instruction::Zext(..) => "zext",
+            Instruction::Cmp(..) => "cmp",
+            Instruction::Jmp(..) => "jmp",
+            Instruction::Jeq(..) => "jeq",
+            Instruction::Jne(..) => "jne",
+            Instruction::Jgt(..) => "jgt",
+            Instruction::Jlt(..) => "jlt",
+            Instruction::Push(..) => "push",
+            Instruction::Pop(..) => "pop",
+            Instruction::Call(..) => "call",
+            Instruction::Ret => "ret",
+            Instruction::Pxl(..) => "pxl",
+            Instruction::Rect(..) => "rect",
+            Instruction::Line(..) => "line",
+            Instruction::Cls(..) => "cls",
+            Instruction::Hlt => "hlt",
+            Instruction::Syscall => "syscall",
+            Instruction::Cli => "cli",
+            Instruction::Sti => "sti",
+            Instruction::Iret => "iret",
+            Instruction::Pushf => "pushf",
+            Instruction::Popf => "popf",
+            Instruction::LdSeg(..) | Instruction::StSeg(..) => "ld",
+            Instruction::Seg(..) => "seg",
+            Instruction::JmpFar(..) => "jmpf",
+            Instruction::CallFar(..) => "callf",
+            Instruction::RetFar => "retf",
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Ld(val, dest) => write!(f, "ld {} {}", val, dest),
+            Instruction::Sum(a, b) => write!(f, "sum {} {}", a, b),
+            Instruction::Sub(a, b) => write!(f, "sub {} {}", a, b),
+            Instruction::Mul(a, b) => write!(f, "mul {} {}", a, b),
+            Instruction::Div(a, b) => write!(f, "div {} {}", a, b),
+            Instruction::And(a, b) => write!(f, "and {} {}", a, b),
+            Instruction::Or(a, b) => write!(f, "or {} {}", a, b),
+            Instruction::Not(a) => write!(f, "not {}", a),
+            Instruction::Xor(a, b) => write!(f, "xor {} {}", a, b),
+            Instruction::Shr(sh, a) => write!(f, "shr {} {}", sh, a),
+            Instruction::Shl(sh, a) => write!(f, "shl {} {}", sh, a),
+            Instruction::Sext(a, b) => write!(f, "sext {} {}", a, b),
+            Instruction::Zext(a, b) => write!(f, "zext {} {}", a, b),
+            Instruction::Cmp(a, b) => write!(f, "cmp {} {}", a, b),
+            Instruction::Jmp(to) => write!(f, "jmp {}", Target(to)),
+            Instruction::Jeq(to) => write!(f, "jeq {}", Target(to)),
+            Instruction::Jne(to) => write!(f, "jne {}", Target(to)),
+            Instruction::Jgt(to) => write!(f, "jgt {}", Target(to)),
+            Instruction::Jlt(to) => write!(f, "jlt {}", Target(to)),
+            Instruction::Push(val) => write!(f, "push {}", val),
+            Instruction::Pop(r) => write!(f, "pop {}", r),
+            Instruction::Call(to) => write!(f, "call {}", Target(to)),
+            Instruction::Ret => f.write_str("ret"),
+            Instruction::Pxl(at, c) => write!(f, "pxl {} {}", at, c),
+            Instruction::Rect(at, size, c) => write!(f, "rect {} {} {}", at, size, c),
+            Instruction::Line(from, to, c) => write!(f, "line {} {} {}", from, to, c),
+            Instruction::Cls(c) => write!(f, "cls {}", c),
+            Instruction::Hlt => f.write_str("hlt"),
+            Instruction::Syscall => f.write_str("syscall"),
+            Instruction::Cli => f.write_str("cli"),
+            Instruction::Sti => f.write_str("sti"),
+            Instruction::Iret => f.write_str("iret"),
+            Instruction::Pushf => f.write_str("pushf"),
+            Instruction::Popf => f.write_str("popf"),
+            Instruction::LdSeg(val, seg) => write!(f, "ld {} {}", Target(val), seg),
+            Instruction::StSeg(seg, r) => write!(f, "ld {} {}", seg, r),
+            Instruction::Seg(seg) => write!(f, "seg {}", seg),
+            Instruction::JmpFar(seg, to) => write!(f, "jmpf {} {}", Target(seg), Target(to)),
+            Instruction::CallFar(seg, to) => write!(f, "callf {} {}", Target(seg), Target(to)),
+            Instruction::RetFar => f.write_str("retf"),
+        }
+    }
+}
+
+/// A single disassembled instruction, or a data byte if `instr` is `None`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub addr: usize,
+    pub bytes: Vec<u8>,
+    pub instr: Option<Instruction>,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        write!(f, "{:04x}  {:<20}  ", self.addr, bytes)?;
+        match self.instr {
+            Some(instr) => write!(f, "{}", instr),
+            None => write!(f, "db {:#04x}", self.bytes[0]),
+        }
+    }
+}
+
+/// Disassembles the bytes in `start..end`. Bytes that don't decode into an
+/// instruction, or whose instruction would run past `end`, are listed one by
+/// one as data.
+pub fn disassemble(mem: &dyn Bus, start: usize, end: usize) -> Vec<Line> {
+    let end = end.min(mem.len());
+    let mut lines = Vec::new();
+    let mut addr = start;
+
+    while addr < end {
+        let len = match encoding::decode(mem, addr) {
+            Ok((instr, len)) if addr + len <= end => {
+                lines.push(Line {
+                    addr,
+                    bytes: (addr..addr + len)
+                        .filter_map(|i| mem.peek(i).ok())
+                        .collect(),
+                    instr: Some(instr),
+                });
+                len
+            }
+            _ => {
+                lines.push(Line {
+                    addr,
+                    bytes: mem.peek(addr).into_iter().collect(),
+                    instr: None,
+                });
+                1
+            }
+        };
+
+        addr += len;
+    }
+
+    lines
+}
+
+/// Same as `disassemble`, one line per instruction.
+pub fn listing(mem: &dyn Bus, start: usize, end: usize) -> String {
+    disassemble(mem, start, end)
+        .iter()
+        .map(|l| format!("{}\n", l))
+        .collect()
+}
+
+#[cfg(test)]
+mod disasm_tests {
+    use super::*;
+    use crate::asm;
+
+    #[test]
+    fn display_matches_readme_syntax() {
+        let cases = [
+            (
+                Instruction::Ld(GenerousInpt::Const(-5), Dest::Register(Reg::A)),
+                "ld -5 a",
+            ),
+            (
+                Instruction::Ld(GenerousInpt::Memory(0x10), Dest::Register(Reg::BH)),
+                "ld [0x0010] bh",
+            ),
+            (
+                Instruction::Ld(GenerousInpt::Register(Reg::CL), Dest::Memory(2)),
+                "ld cl [0x0002]",
+            ),
+            (Instruction::Sum(Reg::A, Reg::B), "sum a b"),
+            (Instruction::Not(Reg::DL), "not dl"),
+            (Instruction::Shr(Inpt::Const(2), Reg::AL), "shr 2 al"),
+            (Instruction::Shl(Inpt::Register(Reg::C), Reg::D), "shl c d"),
+            (Instruction::Sext(Reg::AL, Reg::A), "sext al a"),
+            (Instruction::Jmp(Inpt::Const(0x2a)), "jmp 0x002a"),
+            (Instruction::Jne(Inpt::Register(Reg::A)), "jne a"),
+            (Instruction::Push(Inpt::Const(300)), "push 300"),
+            (Instruction::Pop(Reg::AH), "pop ah"),
+            (Instruction::Call(Inpt::Const(-1)), "call 0xffff"),
+            (Instruction::Ret, "ret"),
+            (Instruction::Hlt, "hlt"),
+            (Instruction::Syscall, "syscall"),
+            (Instruction::Rect(Reg::A, Reg::B, Reg::CL), "rect a b cl"),
+        ];
+
+        for (instr, text) in cases.iter() {
+            assert_eq!(&instr.to_string(), text);
+            assert!(text.starts_with(instr.mnemonic()));
+        }
+    }
+
+    #[test]
+    fn disassemble_program() {
+        let bytes = asm::assemble("ld 1 a\nsum a b\nhlt").unwrap();
+        let mut mem = Mem::new(16);
+        mem.load(0, &bytes);
+
+        let lines = disassemble(&mem, 0, bytes.len());
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].addr, 0);
+        assert_eq!(lines[0].bytes, bytes[..6].to_vec());
+        assert_eq!(lines[1].addr, 6);
+        assert_eq!(lines[1].instr, Some(Instruction::Sum(Reg::A, Reg::B)));
+        assert_eq!(lines[2].addr, 9);
+        assert_eq!(lines[2].instr, Some(Instruction::Hlt));
+
+        assert_eq!(lines[1].to_string(), "0006  02 00 03              sum a b");
+    }
+
+    #[test]
+    fn invalid_bytes_are_data() {
+        let mut mem = Mem::new(8);
+        mem.load(
+            0,
+            &[0xff, encoding::op::NOT, 0x00, encoding::op::PUSH, 0x00],
+        );
+
+        let lines = disassemble(&mem, 0, 5);
+        assert_eq!(
+            lines,
+            vec![
+                Line {
+                    addr: 0,
+                    bytes: vec![0xff],
+                    instr: None
+                },
+                Line {
+                    addr: 1,
+                    bytes: vec![encoding::op::NOT, 0x00],
+                    instr: Some(Instruction::Not(Reg::A))
+                },
+                Line {
+                    addr: 3,
+                    bytes: vec![encoding::op::PUSH],
+                    instr: None
+                },
+                Line {
+                    addr: 4,
+                    bytes: vec![0x00],
+                    instr: None
+                },
+            ]
+        );
+        assert!(lines[0].to_string().ends_with("db 0xff"));
+    }
+
+    #[test]
+    fn end_past_memory() {
+        let mem = Mem::new(2);
+        assert_eq!(disassemble(&mem, 0, 100).len(), 2);
+    }
+
+    #[test]
+    fn assembler_round_trip() {
+        let src = "
+            ld -5 a
+            ld [0x0010] bh
+            ld cl [0x0002]
+            sum a b
+            sub b c
+            mul c d
+            div d a
+            and ah al
+            or bh bl
+            not ch
+            xor cl dh
+            shr 2 dl
+            shl a b
+            sext al a
+            zext bl c
+            cmp a b
+            jmp 0x0020
+            jeq a
+            jne 0x0003
+            jgt b
+            jlt 0x0004
+            push -1
+            pop d
+            call 0x0030
+            ret
+            hlt
+            syscall
+            pxl a b
+            rect a b c
+            line d c b
+            cls al
+            cli
+            sti
+            iret
+            pushf
+            popf
+            ld 0x0010 ds
+            ld b es
+            ld ss c
+            seg es
+            jmpf 0x1000 0x0020
+            callf a b
+            retf
+        ";
+        let bytes = asm::assemble(src).unwrap();
+        let mut mem = Mem::new(bytes.len());
+        mem.load(0, &bytes);
+
+        let text = disassemble(&mem, 0, bytes.len())
+            .iter()
+            .map(|l| l.instr.unwrap().to_string())
+            .collect::<Vec<_>>();
+        let expected = src
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<_>>();
+
+        assert_eq!(text, expected);
+        assert_eq!(asm::assemble(&text.join("\n")).unwrap(), bytes);
+    }
+}
@@ -0,0 +1,633 @@
+//! Machine code format.
+//!
+//! Every instruction is an opcode byte followed by its operands, in the same
+//! order they appear in the `Instruction` variant:
+//!
+//! - `Reg` is a single register code byte.
+//! - `Inpt`, `GenerousInpt` and `Dest` start with a mode byte, followed by
+//!   either a register code byte or a big endian 16 bit constant/address.
+//!
+//! Multi-byte values are big endian, same as `Mem::read_16`/`Mem::write_16`.
+
+#![allow(dead_code)]
+
+use crate::bus::Bus;
+use crate::cpu::*;
+use std::fmt;
+
+pub mod op {
+    pub const LD: u8 = 0x01;
+    pub const SUM: u8 = 0x02;
+    pub const SUB: u8 = 0x03;
+    pub const MUL: u8 = 0x04;
+    pub const DIV: u8 = 0x05;
+    pub const AND: u8 = 0x06;
+    pub const OR: u8 = 0x07;
+    pub const NOT: u8 = 0x08;
+    pub const XOR: u8 = 0x09;
+    pub const SHR: u8 = 0x0a;
+    pub const SHL: u8 = 0x0b;
+    pub const CMP: u8 = 0x0c;
+    pub const JMP: u8 = 0x0d;
+    pub const JEQ: u8 = 0x0e;
+    pub const JNE: u8 = 0x0f;
+    pub const JGT: u8 = 0x10;
+    pub const JLT: u8 = 0x11;
+    pub const PUSH: u8 = 0x12;
+    pub const POP: u8 = 0x13;
+    pub const HLT: u8 = 0x14;
+    pub const CALL: u8 = 0x15;
+    pub const RET: u8 = 0x16;
+    pub const SYSCALL: u8 = 0x17;
+    pub const PXL: u8 = 0x18;
+    pub const RECT: u8 = 0x19;
+    pub const LINE: u8 = 0x1a;
+    pub const CLS: u8 = 0x1b;
+    pub const CLI: u8 = 0x1c;
+    pub const STI: u8 = 0x1d;
+    pub const IRET: u8 = 0x1e;
+    pub const SEG: u8 = 0x1f;
+    pub const LDSEG: u8 = 0x20;
+    pub const STSEG: u8 = 0x21;
+    pub const JMPF: u8 = 0x22;
+    pub const CALLF: u8 = 0x23;
+    pub const RETF: u8 = 0x24;
+    pub const PUSHF: u8 = 0x25;
+    pub const POPF: u8 = 0x26;
+    pub const SEXT: u8 = 0x27;
+    pub const ZEXT: u8 = 0x28;
+}
+
+pub mod mode {
+    pub const CONST: u8 = 0x00;
+    pub const REGISTER: u8 = 0x01;
+    pub const MEMORY: u8 = 0x02;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    InvalidOpcode { addr: usize, opcode: u8 },
+    InvalidRegister { addr: usize, code: u8 },
+    InvalidMode { addr: usize, mode: u8 },
+    Truncated { addr: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::InvalidOpcode { addr, opcode } => {
+                write!(f, "invalid opcode {:#04x} at {:#06x}", opcode, addr)
+            }
+            DecodeError::InvalidRegister { addr, code } => {
+                write!(f, "invalid register code {:#04x} at {:#06x}", code, addr)
+            }
+            DecodeError::InvalidMode { addr, mode } => {
+                write!(f, "invalid operand mode {:#04x} at {:#06x}", mode, addr)
+            }
+            DecodeError::Truncated { addr } => {
+                write!(
+                    f,
+                    "instruction at {:#06x} runs past the end of memory",
+                    addr
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+const REGS: [Reg; 12] = [
+    Reg::A,
+    Reg::AH,
+    Reg::AL,
+    Reg::B,
+    Reg::BH,
+    Reg::BL,
+    Reg::C,
+    Reg::CH,
+    Reg::CL,
+    Reg::D,
+    Reg::DH,
+    Reg::DL,
+];
+
+fn reg_code(reg: Reg) -> u8 {
+    REGS.iter().position(|&r| r == reg).unwrap() as u8
+}
+
+const SEGS: [SegReg; 4] = [SegReg::CS, SegReg::DS, SegReg::SS, SegReg::ES];
+
+pub(crate) fn seg_code(seg: SegReg) -> u8 {
+    SEGS.iter().position(|&s| s == seg).unwrap() as u8
+}
+
+pub(crate) fn seg_from_code(code: u8) -> Option<SegReg> {
+    SEGS.get(code as usize).copied()
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn reg(&mut self, reg: Reg) {
+        self.bytes.push(reg_code(reg));
+    }
+
+    fn word(&mut self, w: u16) {
+        self.bytes.extend_from_slice(&w.to_be_bytes());
+    }
+
+    fn inpt(&mut self, i: Inpt) {
+        match i {
+            Inpt::Const(c) => {
+                self.bytes.push(mode::CONST);
+                self.word(c as u16);
+            }
+            Inpt::Register(r) => {
+                self.bytes.push(mode::REGISTER);
+                self.reg(r);
+            }
+        }
+    }
+
+    fn generous(&mut self, i: GenerousInpt) {
+        match i {
+            GenerousInpt::Const(c) => self.inpt(Inpt::Const(c)),
+            GenerousInpt::Register(r) => self.inpt(Inpt::Register(r)),
+            GenerousInpt::Memory(a) => {
+                self.bytes.push(mode::MEMORY);
+                self.word(a);
+            }
+        }
+    }
+
+    fn dest(&mut self, d: Dest) {
+        match d {
+            Dest::Register(r) => self.generous(GenerousInpt::Register(r)),
+            Dest::Memory(a) => self.generous(GenerousInpt::Memory(a)),
+        }
+    }
+}
+
+pub fn encode(instr: &Instruction) -> Vec<u8> {
+    let mut e = Encoder { bytes: Vec::new() };
+
+    match *instr {
+        Instruction::Ld(val, dest) => {
+            e.bytes.push(op::LD);
+            e.generous(val);
+            e.dest(dest);
+        }
+        Instruction::Sum(a, b) => e.bytes.extend([op::SUM, reg_code(a), reg_code(b)]),
+        Instruction::Sub(a, b) => e.bytes.extend([op::SUB, reg_code(a), reg_code(b)]),
+        Instruction::Mul(a, b) => e.bytes.extend([op::MUL, reg_code(a), reg_code(b)]),
+        Instruction::Div(a, b) => e.bytes.extend([op::DIV, reg_code(a), reg_code(b)]),
+        Instruction::And(a, b) => e.bytes.extend([op::AND, reg_code(a), reg_code(b)]),
+        Instruction::Or(a, b) => e.bytes.extend([op::OR, reg_code(a), reg_code(b)]),
+        Instruction::Not(a) => e.bytes.extend([op::NOT, reg_code(a)]),
+        Instruction::Xor(a, b) => e.bytes.extend([op::XOR, reg_code(a), reg_code(b)]),
+        Instruction::Sext(a, b) => e.bytes.extend([op::SEXT, reg_code(a), reg_code(b)]),
+        Instruction::Zext(a, b) => e.bytes.extend([op::ZEXT, reg_code(a), reg_code(b)]),
+        Instruction::Shr(sh, a) => {
+            e.bytes.push(op::SHR);
+            e.inpt(sh);
+            e.reg(a);
+        }
+        Instruction::Shl(sh, a) => {
+            e.bytes.push(op::SHL);
+            e.inpt(sh);
+            e.reg(a);
+        }
+        Instruction::Cmp(a, b) => e.bytes.extend([op::CMP, reg_code(a), reg_code(b)]),
+        Instruction::Jmp(to) => {
+            e.bytes.push(op::JMP);
+            e.inpt(to);
+        }
+        Instruction::Jeq(to) => {
+            e.bytes.push(op::JEQ);
+            e.inpt(to);
+        }
+        Instruction::Jne(to) => {
+            e.bytes.push(op::JNE);
+            e.inpt(to);
+        }
+        Instruction::Jgt(to) => {
+            e.bytes.push(op::JGT);
+            e.inpt(to);
+        }
+        Instruction::Jlt(to) => {
+            e.bytes.push(op::JLT);
+            e.inpt(to);
+        }
+        Instruction::Push(val) => {
+            e.bytes.push(op::PUSH);
+            e.inpt(val);
+        }
+        Instruction::Pop(r) => e.bytes.extend([op::POP, reg_code(r)]),
+        Instruction::Call(to) => {
+            e.bytes.push(op::CALL);
+            e.inpt(to);
+        }
+        Instruction::Ret => e.bytes.push(op::RET),
+        Instruction::Pxl(at, c) => e.bytes.extend([op::PXL, reg_code(at), reg_code(c)]),
+        Instruction::Rect(at, size, c) => {
+            e.bytes
+                .extend([op::RECT, reg_code(at), reg_code(size), reg_code(c)])
+        }
+        Instruction::Line(from, to, c) => {
+            e.bytes
+                .extend([op::LINE, reg_code(from), reg_code(to), reg_code(c)])
+        }
+        Instruction::Cls(c) => e.bytes.extend([op::CLS, reg_code(c)]),
+        Instruction::Hlt => e.bytes.push(op::HLT),
+        Instruction::Syscall => e.bytes.push(op::SYSCALL),
+        Instruction::Cli => e.bytes.push(op::CLI),
+        Instruction::Sti => e.bytes.push(op::STI),
+        Instruction::Iret => e.bytes.push(op::IRET),
+        Instruction::Pushf => e.bytes.push(op::PUSHF),
+        Instruction::Popf => e.bytes.push(op::POPF),
+        Instruction::LdSeg(val, seg) => {
+            e.bytes.push(op::LDSEG);
+            e.inpt(val);
+            e.bytes.push(seg_code(seg));
+        }
+        Instruction::StSeg(seg, r) => e.bytes.extend([op::STSEG, seg_code(seg), reg_code(r)]),
+        Instruction::Seg(seg) => e.bytes.extend([op::SEG, seg_code(seg)]),
+        Instruction::JmpFar(seg, to) => {
+            e.bytes.push(op::JMPF);
+            e.inpt(seg);
+            e.inpt(to);
+        }
+        Instruction::CallFar(seg, to) => {
+            e.bytes.push(op::CALLF);
+            e.inpt(seg);
+            e.inpt(to);
+        }
+        Instruction::RetFar => e.bytes.push(op::RETF),
+    }
+
+    e.bytes
+}
+
+/// Encodes a sequence of instructions back to back.
+pub fn encode_all(instrs: &[Instruction]) -> Vec<u8> {
+    instrs.iter().flat_map(encode).collect()
+}
+
+struct Decoder<'a> {
+    mem: &'a dyn Bus,
+    start: usize,
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let b = self
+            .mem
+            .peek(self.pos)
+            .map_err(|_| DecodeError::Truncated { addr: self.start })?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn word(&mut self) -> Result<u16, DecodeError> {
+        let h = self.byte()?;
+        let l = self.byte()?;
+        Ok(u16::from_be_bytes([h, l]))
+    }
+
+    fn reg(&mut self) -> Result<Reg, DecodeError> {
+        let addr = self.pos;
+        let code = self.byte()?;
+        REGS.get(code as usize)
+            .copied()
+            .ok_or(DecodeError::InvalidRegister { addr, code })
+    }
+
+    fn seg(&mut self) -> Result<SegReg, DecodeError> {
+        let addr = self.pos;
+        let code = self.byte()?;
+        SEGS.get(code as usize)
+            .copied()
+            .ok_or(DecodeError::InvalidRegister { addr, code })
+    }
+
+    fn generous(&mut self) -> Result<GenerousInpt, DecodeError> {
+        let addr = self.pos;
+        match self.byte()? {
+            mode::CONST => Ok(GenerousInpt::Const(self.word()? as i16)),
+            mode::REGISTER => Ok(GenerousInpt::Register(self.reg()?)),
+            mode::MEMORY => Ok(GenerousInpt::Memory(self.word()?)),
+            mode => Err(DecodeError::InvalidMode { addr, mode }),
+        }
+    }
+
+    fn inpt(&mut self) -> Result<Inpt, DecodeError> {
+        let addr = self.pos;
+        match self.generous()? {
+            GenerousInpt::Const(c) => Ok(Inpt::Const(c)),
+            GenerousInpt::Register(r) => Ok(Inpt::Register(r)),
+            GenerousInpt::Memory(_) => Err(DecodeError::InvalidMode {
+                addr,
+                mode: mode::MEMORY,
+            }),
+        }
+    }
+
+    fn dest(&mut self) -> Result<Dest, DecodeError> {
+        let addr = self.pos;
+        match self.generous()? {
+            GenerousInpt::Register(r) => Ok(Dest::Register(r)),
+            GenerousInpt::Memory(a) => Ok(Dest::Memory(a)),
+            GenerousInpt::Const(_) => Err(DecodeError::InvalidMode {
+                addr,
+                mode: mode::CONST,
+            }),
+        }
+    }
+}
+
+/// Decodes the instruction stored at `addr`, returning it along with its
+/// length in bytes.
+pub fn decode(mem: &dyn Bus, addr: usize) -> Result<(Instruction, usize), DecodeError> {
+    let mut d = Decoder {
+        mem,
+        start: addr,
+        pos: addr,
+    };
+
+    let instr = match d.byte()? {
+        op::LD => Instruction::Ld(d.generous()?, d.dest()?),
+        op::SUM => Instruction::Sum(d.reg()?, d.reg()?),
+        op::SUB => Instruction::Sub(d.reg()?, d.reg()?),
+        op::MUL => Instruction::Mul(d.reg()?, d.reg()?),
+        op::DIV => Instruction::Div(d.reg()?, d.reg()?),
+        op::AND => Instruction::And(d.reg()?, d.reg()?),
+        op::OR => Instruction::Or(d.reg()?, d.reg()?),
+        op::NOT => Instruction::Not(d.reg()?),
+        op::XOR => Instruction::Xor(d.reg()?, d.reg()?),
+        op::SHR => Instruction::Shr(d.inpt()?, d.reg()?),
+        op::SHL => Instruction::Shl(d.inpt()?, d.reg()?),
+        op::SEXT => Instruction::Sext(d.reg()?, d.reg()?),
+        op::ZEXT => Instruction::Zext(d.reg()?, d.reg()?),
+        op::CMP => Instruction::Cmp(d.reg()?, d.reg()?),
+        op::JMP => Instruction::Jmp(d.inpt()?),
+        op::JEQ => Instruction::Jeq(d.inpt()?),
+        op::JNE => Instruction::Jne(d.inpt()?),
+        op::JGT => Instruction::Jgt(d.inpt()?),
+        op::JLT => Instruction::Jlt(d.inpt()?),
+        op::PUSH => Instruction::Push(d.inpt()?),
+        op::POP => Instruction::Pop(d.reg()?),
+        op::HLT => Instruction::Hlt,
+        op::CALL => Instruction::Call(d.inpt()?),
+        op::RET => Instruction::Ret,
+        op::SYSCALL => Instruction::Syscall,
+        op::PXL => Instruction::Pxl(d.reg()?, d.reg()?),
+        op::RECT => Instruction::Rect(d.reg()?, d.reg()?, d.reg()?),
+        op::LINE => Instruction::Line(d.reg()?, d.reg()?, d.reg()?),
+        op::CLS => Instruction::Cls(d.reg()?),
+        op::CLI => Instruction::Cli,
+        op::STI => Instruction::Sti,
+        op::IRET => Instruction::Iret,
+        op::PUSHF => Instruction::Pushf,
+        op::POPF => Instruction::Popf,
+        op::LDSEG => Instruction::LdSeg(d.inpt()?, d.seg()?),
+        op::STSEG => Instruction::StSeg(d.seg()?, d.reg()?),
+        op::SEG => Instruction::Seg(d.seg()?),
+        op::JMPF => Instruction::JmpFar(d.inpt()?, d.inpt()?),
+        op::CALLF => Instruction::CallFar(d.inpt()?, d.inpt()?),
+        op::RETF => Instruction::RetFar,
+        opcode => return Err(DecodeError::InvalidOpcode { addr, opcode }),
+    };
+
+    Ok((instr, d.pos - addr))
+}
+
+#[cfg(test)]
+mod encoding_tests {
+    use super::*;
+
+    fn round_trip(instr: Instruction) {
+        let bytes = encode(&instr);
+        let mut mem = Mem::new(bytes.len() + 3);
+        mem.load(3, &bytes);
+
+        assert_eq!(decode(&mem, 3), Ok((instr, bytes.len())));
+    }
+
+    #[test]
+    fn round_trip_ld() {
+        let srcs = [
+            GenerousInpt::Const(-5),
+            GenerousInpt::Const(i16::MAX),
+            GenerousInpt::Register(Reg::B),
+            GenerousInpt::Register(Reg::CL),
+            GenerousInpt::Memory(0xbeef),
+        ];
+        let dests = [
+            Dest::Register(Reg::A),
+            Dest::Register(Reg::DH),
+            Dest::Memory(0x1234),
+        ];
+
+        for &src in srcs.iter() {
+            for &dest in dests.iter() {
+                round_trip(Instruction::Ld(src, dest));
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_two_registers() {
+        for &a in REGS.iter() {
+            for &b in REGS.iter() {
+                round_trip(Instruction::Sum(a, b));
+                round_trip(Instruction::Sub(a, b));
+                round_trip(Instruction::Mul(a, b));
+                round_trip(Instruction::Div(a, b));
+                round_trip(Instruction::And(a, b));
+                round_trip(Instruction::Or(a, b));
+                round_trip(Instruction::Xor(a, b));
+                round_trip(Instruction::Sext(a, b));
+                round_trip(Instruction::Zext(a, b));
+                round_trip(Instruction::Cmp(a, b));
+                round_trip(Instruction::Pxl(a, b));
+                round_trip(Instruction::Rect(a, b, Reg::CL));
+                round_trip(Instruction::Line(Reg::D, a, b));
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_one_register() {
+        for &r in REGS.iter() {
+            round_trip(Instruction::Not(r));
+            round_trip(Instruction::Pop(r));
+            round_trip(Instruction::Cls(r));
+        }
+    }
+
+    #[test]
+    fn round_trip_shifts() {
+        for &r in REGS.iter() {
+            round_trip(Instruction::Shr(Inpt::Const(3), r));
+            round_trip(Instruction::Shr(Inpt::Register(Reg::CL), r));
+            round_trip(Instruction::Shl(Inpt::Const(-1), r));
+            round_trip(Instruction::Shl(Inpt::Register(Reg::D), r));
+        }
+    }
+
+    #[test]
+    fn round_trip_inpt() {
+        let inpts = [
+            Inpt::Const(0),
+            Inpt::Const(-32768),
+            Inpt::Register(Reg::A),
+            Inpt::Register(Reg::AH),
+        ];
+
+        for &i in inpts.iter() {
+            round_trip(Instruction::Jmp(i));
+            round_trip(Instruction::Jeq(i));
+            round_trip(Instruction::Jne(i));
+            round_trip(Instruction::Jgt(i));
+            round_trip(Instruction::Jlt(i));
+            round_trip(Instruction::Push(i));
+            round_trip(Instruction::Call(i));
+        }
+    }
+
+    #[test]
+    fn round_trip_segments() {
+        for seg in SEGS {
+            round_trip(Instruction::LdSeg(Inpt::Const(0x1234), seg));
+            round_trip(Instruction::LdSeg(Inpt::Register(Reg::B), seg));
+            round_trip(Instruction::StSeg(seg, Reg::DL));
+            round_trip(Instruction::Seg(seg));
+        }
+        round_trip(Instruction::JmpFar(
+            Inpt::Const(0x1000),
+            Inpt::Register(Reg::A),
+        ));
+        round_trip(Instruction::CallFar(
+            Inpt::Register(Reg::C),
+            Inpt::Const(-2),
+        ));
+    }
+
+    #[test]
+    fn invalid_segment() {
+        let mut mem = Mem::new(2);
+        mem.load(0, &[op::SEG, 4]);
+
+        assert_eq!(
+            decode(&mem, 0),
+            Err(DecodeError::InvalidRegister { addr: 1, code: 4 })
+        );
+    }
+
+    #[test]
+    fn round_trip_no_operands() {
+        round_trip(Instruction::Ret);
+        round_trip(Instruction::Hlt);
+        round_trip(Instruction::Cli);
+        round_trip(Instruction::Sti);
+        round_trip(Instruction::Iret);
+        round_trip(Instruction::Pushf);
+        round_trip(Instruction::Popf);
+        round_trip(Instruction::RetFar);
+        round_trip(Instruction::Syscall);
+    }
+
+    #[test]
+    fn encoding_layout() {
+        assert_eq!(
+            encode(&Instruction::Ld(
+                GenerousInpt::Const(-5),
+                Dest::Memory(0x0102)
+            )),
+            vec![op::LD, mode::CONST, 0xff, 0xfb, mode::MEMORY, 0x01, 0x02]
+        );
+        assert_eq!(
+            encode(&Instruction::Sum(Reg::AL, Reg::B)),
+            vec![op::SUM, 2, 3]
+        );
+    }
+
+    #[test]
+    fn decode_sequence() {
+        let prog = [
+            Instruction::Ld(GenerousInpt::Const(1), Dest::Register(Reg::A)),
+            Instruction::Sum(Reg::A, Reg::B),
+            Instruction::Jmp(Inpt::Const(0)),
+        ];
+        let bytes = encode_all(&prog);
+        let mut mem = Mem::new(bytes.len());
+        mem.load(0, &bytes);
+
+        let mut addr = 0;
+        for instr in prog.iter() {
+            let (decoded, len) = decode(&mem, addr).unwrap();
+            assert_eq!(&decoded, instr);
+            addr += len;
+        }
+        assert_eq!(addr, bytes.len());
+    }
+
+    #[test]
+    fn decode_invalid_opcode() {
+        let mut mem = Mem::new(4);
+        mem.load(1, &[0xff]);
+
+        assert_eq!(
+            decode(&mem, 1),
+            Err(DecodeError::InvalidOpcode {
+                addr: 1,
+                opcode: 0xff
+            })
+        );
+    }
+
+    #[test]
+    fn decode_invalid_register() {
+        let mut mem = Mem::new(3);
+        mem.load(0, &[op::NOT, 12]);
+
+        assert_eq!(
+            decode(&mem, 0),
+            Err(DecodeError::InvalidRegister { addr: 1, code: 12 })
+        );
+    }
+
+    #[test]
+    fn decode_invalid_mode() {
+        let mut mem = Mem::new(8);
+        mem.load(0, &[op::JMP, mode::MEMORY, 0, 0]);
+        assert_eq!(
+            decode(&mem, 0),
+            Err(DecodeError::InvalidMode {
+                addr: 1,
+                mode: mode::MEMORY
+            })
+        );
+
+        mem.load(0, &[op::LD, mode::CONST, 0, 0, mode::CONST, 0, 0]);
+        assert_eq!(
+            decode(&mem, 0),
+            Err(DecodeError::InvalidMode {
+                addr: 4,
+                mode: mode::CONST
+            })
+        );
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let mut mem = Mem::new(3);
+        mem.load(0, &[op::PUSH, mode::CONST, 0]);
+
+        assert_eq!(decode(&mem, 0), Err(DecodeError::Truncated { addr: 0 }));
+    }
+}
@@ -0,0 +1,209 @@
+//! Framebuffer used by the graphics instructions.
+//!
+//! Pixels store an index into the palette. Everything lives in memory, so it
+//! works without a display; `write_ppm` dumps the picture to an image file.
+
+#![allow(dead_code)]
+
+use std::io::{self, Write};
+
+/// The 16 CGA colors.
+pub const DEFAULT_PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xaa],
+    [0x00, 0xaa, 0x00],
+    [0x00, 0xaa, 0xaa],
+    [0xaa, 0x00, 0x00],
+    [0xaa, 0x00, 0xaa],
+    [0xaa, 0x55, 0x00],
+    [0xaa, 0xaa, 0xaa],
+    [0x55, 0x55, 0x55],
+    [0x55, 0x55, 0xff],
+    [0x55, 0xff, 0x55],
+    [0x55, 0xff, 0xff],
+    [0xff, 0x55, 0x55],
+    [0xff, 0x55, 0xff],
+    [0xff, 0xff, 0x55],
+    [0xff, 0xff, 0xff],
+];
+
+pub struct Framebuffer {
+    width: u16,
+    height: u16,
+    palette: Vec<[u8; 3]>,
+    pixels: Vec<u8>,
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Framebuffer::new(256, 192, DEFAULT_PALETTE.to_vec())
+    }
+}
+
+impl Framebuffer {
+    /// Coordinates are packed in a single register (x high, y low), so the
+    /// resolution is at most 256x256.
+    pub fn new(width: u16, height: u16, palette: Vec<[u8; 3]>) -> Self {
+        assert!(width <= 256 && height <= 256);
+        assert!(!palette.is_empty() && palette.len() <= 256);
+
+        Framebuffer {
+            width,
+            height,
+            palette,
+            pixels: vec![0; width as usize * height as usize],
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    pub fn palette(&self) -> &[[u8; 3]] {
+        &self.palette
+    }
+
+    pub fn is_color(&self, color: u8) -> bool {
+        (color as usize) < self.palette.len()
+    }
+
+    /// Palette index at (x, y), `None` if it's off screen.
+    pub fn pixel(&self, x: u16, y: u16) -> Option<u8> {
+        if x < self.width && y < self.height {
+            Some(self.pixels[y as usize * self.width as usize + x as usize])
+        } else {
+            None
+        }
+    }
+
+    pub fn rgb(&self, x: u16, y: u16) -> Option<[u8; 3]> {
+        self.pixel(x, y).map(|c| self.palette[c as usize])
+    }
+
+    /// Off screen pixels are clipped.
+    pub fn set_pixel(&mut self, x: u16, y: u16, color: u8) {
+        if x < self.width && y < self.height {
+            self.pixels[y as usize * self.width as usize + x as usize] = color;
+        }
+    }
+
+    pub fn clear(&mut self, color: u8) {
+        self.pixels.iter_mut().for_each(|p| *p = color);
+    }
+
+    pub fn fill_rect(&mut self, x: u16, y: u16, w: u16, h: u16, color: u8) {
+        for py in y..y.saturating_add(h).min(self.height) {
+            for px in x..x.saturating_add(w).min(self.width) {
+                self.set_pixel(px, py, color);
+            }
+        }
+    }
+
+    /// Bresenham line, both ends included.
+    pub fn draw_line(&mut self, x0: u16, y0: u16, x1: u16, y1: u16, color: u8) {
+        let (mut x, mut y) = (x0 as i32, y0 as i32);
+        let (x1, y1) = (x1 as i32, y1 as i32);
+        let dx = (x1 - x).abs();
+        let dy = -(y1 - y).abs();
+        let sx = if x < x1 { 1 } else { -1 };
+        let sy = if y < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+
+        loop {
+            self.set_pixel(x as u16, y as u16, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// Writes the framebuffer as a binary PPM image.
+    pub fn write_ppm<W: Write>(&self, mut out: W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for &p in self.pixels.iter() {
+            out.write_all(&self.palette[p as usize])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod graphics_tests {
+    use super::*;
+
+    fn lit(fb: &Framebuffer, color: u8) -> Vec<(u16, u16)> {
+        let mut v = Vec::new();
+        for y in 0..fb.height() {
+            for x in 0..fb.width() {
+                if fb.pixel(x, y) == Some(color) {
+                    v.push((x, y));
+                }
+            }
+        }
+        v
+    }
+
+    #[test]
+    fn set_pixel_and_clip() {
+        let mut fb = Framebuffer::new(4, 3, DEFAULT_PALETTE.to_vec());
+        fb.set_pixel(1, 2, 5);
+        fb.set_pixel(4, 0, 5);
+
+        assert_eq!(fb.pixel(1, 2), Some(5));
+        assert_eq!(fb.rgb(1, 2), Some(DEFAULT_PALETTE[5]));
+        assert_eq!(fb.pixel(4, 0), None);
+        assert_eq!(lit(&fb, 5), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn fill_rect_clipped() {
+        let mut fb = Framebuffer::new(4, 4, DEFAULT_PALETTE.to_vec());
+        fb.fill_rect(2, 1, 5, 2, 3);
+
+        assert_eq!(lit(&fb, 3), vec![(2, 1), (3, 1), (2, 2), (3, 2)]);
+    }
+
+    #[test]
+    fn draw_line() {
+        let mut fb = Framebuffer::new(5, 5, DEFAULT_PALETTE.to_vec());
+        fb.draw_line(4, 4, 0, 0, 1);
+        fb.draw_line(0, 4, 3, 4, 2);
+
+        assert_eq!(lit(&fb, 1), vec![(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)]);
+        assert_eq!(lit(&fb, 2), vec![(0, 4), (1, 4), (2, 4), (3, 4)]);
+    }
+
+    #[test]
+    fn clear() {
+        let mut fb = Framebuffer::new(2, 2, DEFAULT_PALETTE.to_vec());
+        fb.set_pixel(0, 0, 4);
+        fb.clear(7);
+
+        assert_eq!(lit(&fb, 7).len(), 4);
+    }
+
+    #[test]
+    fn ppm() {
+        let mut fb = Framebuffer::new(2, 1, vec![[0, 0, 0], [1, 2, 3]]);
+        fb.set_pixel(1, 0, 1);
+
+        let mut out = Vec::new();
+        fb.write_ppm(&mut out).unwrap();
+        assert_eq!(out, b"P6\n2 1\n255\n\x00\x00\x00\x01\x02\x03".to_vec());
+    }
+}
@@ -0,0 +1,117 @@
+//! Undo log for reverse execution.
+//!
+//! When history is enabled on the `Cpu`, every step that changes something
+//! records the cpu state before it and the bytes it wrote, so it can be
+//! undone by `Cpu::step_back`. Only the most recent steps are kept.
+//! Syscall output, fired scheduler events and drawing on the framebuffer
+//! (`cls`, `pxl`, `line`, `rect`) can't be taken back.
+
+#![allow(dead_code)]
+
+use crate::cpu::*;
+use std::collections::VecDeque;
+
+/// What it takes to undo a step.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Undo {
+    pub state: CpuState,
+    pub writes: Vec<MemWrite>,
+}
+
+impl Undo {
+    pub fn wrote(&self, addr: usize) -> bool {
+        self.writes.iter().any(|w| w.addr == addr)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    undos: VecDeque<Undo>,
+    limit: usize,
+}
+
+impl History {
+    /// Keeps the last `limit` steps.
+    pub fn new(limit: usize) -> Self {
+        History {
+            undos: VecDeque::new(),
+            limit,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn len(&self) -> usize {
+        self.undos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undos.is_empty()
+    }
+
+    /// Records a step, forgetting the oldest one when full.
+    pub fn push(&mut self, undo: Undo) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.undos.len() == self.limit {
+            self.undos.pop_front();
+        }
+        self.undos.push_back(undo);
+    }
+
+    /// The most recent step.
+    pub fn pop(&mut self) -> Option<Undo> {
+        self.undos.pop_back()
+    }
+
+    pub fn last(&self) -> Option<&Undo> {
+        self.undos.back()
+    }
+}
+
+/// Why running backwards stopped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReverseStop {
+    /// Back at a breakpoint.
+    Breakpoint(usize),
+    /// Right before the instruction at this address, which wrote the byte
+    /// that was looked for.
+    Write(usize),
+    /// No more history.
+    Start,
+}
+
+#[cfg(test)]
+mod history_tests {
+    use super::*;
+
+    fn undo(ip: u16) -> Undo {
+        Undo {
+            state: CpuState {
+                ip,
+                ..CpuState::default()
+            },
+            writes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn bounded() {
+        let mut history = History::new(2);
+        history.push(undo(1));
+        history.push(undo(2));
+        history.push(undo(3));
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop().map(|u| u.state.ip), Some(3));
+        assert_eq!(history.pop().map(|u| u.state.ip), Some(2));
+        assert_eq!(history.pop(), None);
+
+        let mut history = History::new(0);
+        history.push(undo(1));
+        assert!(history.is_empty());
+    }
+}
@@ -0,0 +1,424 @@
+//! Building a cpu and its memory from a configuration.
+//!
+//! The defaults give the machine the debugger runs programs on: 64 KiB of
+//! memory at the bottom of the 20-bit address space, code from address 0, a
+//! 4 KiB stack right below the interrupt vector table at 0xf000. As memory
+//! is smaller than the address space, a word at 0xffff is out of bounds
+//! rather than wrapping to address 0, which takes the 16-bit address mode.
+//!
+//! `program` also splits memory into protected regions: the code can't be
+//! written, the data can't be executed and the stack can't grow into either.
+
+#![allow(dead_code)]
+
+use crate::cpu::*;
+use crate::timing::CycleTable;
+use std::fmt;
+use std::ops::Range;
+
+pub const MEM_SIZE: usize = 0x10000;
+pub const STACK_TOP: u16 = 0xf000;
+pub const STACK_SIZE: u16 = 0x1000;
+/// The interrupt vector table sits right above the stack.
+pub const IVT_START: usize = 0xf000;
+/// 256 vectors of 4 bytes.
+pub const IVT_SIZE: usize = 0x400;
+
+/// A configuration that can't be built.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Memory is larger than its address space.
+    Memory(MemSizeError),
+    /// The stack would start below offset 0 of its segment.
+    StackBelowSegment { top: u16, size: u16 },
+    /// Part of the stack is past the end of memory.
+    StackOutOfMemory { end: usize, mem_size: usize },
+    /// Stacks hold words, their size must be even.
+    OddStackSize(u16),
+    /// Part of the vector table is past the end of memory.
+    IvtOutOfMemory { start: usize, mem_size: usize },
+    /// The vector table and the stack share bytes.
+    IvtOverlapsStack { start: usize, stack_start: usize },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Memory(e) => write!(f, "{}", e),
+            ConfigError::StackBelowSegment { top, size } => write!(
+                f,
+                "stack of {:#06x} bytes doesn't fit below offset {:#06x}",
+                size, top
+            ),
+            ConfigError::StackOutOfMemory { end, mem_size } => write!(
+                f,
+                "stack ends at {:#07x}, past the end of memory ({:#07x})",
+                end, mem_size
+            ),
+            ConfigError::OddStackSize(size) => write!(f, "odd stack size {:#06x}", size),
+            ConfigError::IvtOutOfMemory { start, mem_size } => write!(
+                f,
+                "vector table at {:#07x} runs past the end of memory ({:#07x})",
+                start, mem_size
+            ),
+            ConfigError::IvtOverlapsStack { start, stack_start } => write!(
+                f,
+                "vector table at {:#07x} overlaps the stack at {:#07x}",
+                start, stack_start
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub struct Machine {
+    pub cpu: Cpu,
+    pub mem: Mem,
+}
+
+#[derive(Clone, Debug)]
+pub struct MachineBuilder {
+    mem_size: usize,
+    address_mode: AddressMode,
+    backing: Backing,
+    stack_segment: u16,
+    stack_top: u16,
+    stack_size: u16,
+    ivt: usize,
+    overflow_policy: OverflowPolicy,
+    timing: CycleTable,
+    program: Option<usize>,
+    regions: Vec<Region>,
+    strictness: Strictness,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        MachineBuilder {
+            mem_size: MEM_SIZE,
+            address_mode: AddressMode::default(),
+            backing: Backing::default(),
+            stack_segment: 0,
+            stack_top: STACK_TOP,
+            stack_size: STACK_SIZE,
+            ivt: IVT_START,
+            overflow_policy: OverflowPolicy::default(),
+            timing: CycleTable::default(),
+            program: None,
+            regions: Vec::new(),
+            strictness: Strictness::default(),
+        }
+    }
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        MachineBuilder::default()
+    }
+
+    /// Memory of `size` bytes from address 0, up to the whole address space.
+    pub fn memory(mut self, size: usize) -> Self {
+        self.mem_size = size;
+        self
+    }
+
+    pub fn address_mode(mut self, mode: AddressMode) -> Self {
+        self.address_mode = mode;
+        self
+    }
+
+    pub fn backing(mut self, backing: Backing) -> Self {
+        self.backing = backing;
+        self
+    }
+
+    /// Stack of `size` bytes growing down from `segment:top`. A `top` of 0
+    /// is the end of the segment.
+    pub fn stack(mut self, segment: u16, top: u16, size: u16) -> Self {
+        self.stack_segment = segment;
+        self.stack_top = top;
+        self.stack_size = size;
+        self
+    }
+
+    pub fn ivt(mut self, start: usize) -> Self {
+        self.ivt = start;
+        self
+    }
+
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    pub fn timing(mut self, timing: CycleTable) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    /// Lays out regions for a program of `len` bytes loaded at 0: `code`,
+    /// then `data` up to the `stack` and the `ivt`.
+    pub fn program(mut self, len: usize) -> Self {
+        self.program = Some(len);
+        self
+    }
+
+    /// Adds a region, checked before the ones laid out by `program`.
+    pub fn region(mut self, name: &str, range: Range<usize>, perms: Perms) -> Self {
+        self.regions.push(Region {
+            name: name.to_string(),
+            range,
+            perms,
+        });
+        self
+    }
+
+    pub fn strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    /// Physical addresses of the stack, from its lowest byte to its top.
+    fn stack_range(&self) -> Range<usize> {
+        let top = match self.stack_top {
+            0 => 0x10000,
+            top => top as usize,
+        };
+        let end = ((self.stack_segment as usize) << 4) + top;
+        end - self.stack_size as usize..end
+    }
+
+    /// Checks that memory fits in the address space, and the stack and
+    /// vector table in memory without overlapping.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.mem_size > self.address_mode.size() {
+            return Err(ConfigError::Memory(MemSizeError {
+                size: self.mem_size,
+                mode: self.address_mode,
+            }));
+        }
+
+        if !self.stack_size.is_multiple_of(2) {
+            return Err(ConfigError::OddStackSize(self.stack_size));
+        }
+
+        let top = match self.stack_top {
+            0 => 0x10000,
+            top => top as usize,
+        };
+        if (self.stack_size as usize) > top {
+            return Err(ConfigError::StackBelowSegment {
+                top: self.stack_top,
+                size: self.stack_size,
+            });
+        }
+
+        let end = self.stack_range().end;
+        if self.stack_size > 0 && end > self.mem_size {
+            return Err(ConfigError::StackOutOfMemory {
+                end,
+                mem_size: self.mem_size,
+            });
+        }
+
+        let ivt = self.ivt..self.ivt + IVT_SIZE;
+        if ivt.end > self.mem_size {
+            return Err(ConfigError::IvtOutOfMemory {
+                start: self.ivt,
+                mem_size: self.mem_size,
+            });
+        }
+
+        let stack = self.stack_range();
+        if self.stack_size > 0 && ivt.start < stack.end && stack.start < ivt.end {
+            return Err(ConfigError::IvtOverlapsStack {
+                start: self.ivt,
+                stack_start: stack.start,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<Machine, ConfigError> {
+        self.validate()?;
+
+        let mut mem = Mem::with_mode(self.mem_size, self.address_mode, self.backing)
+            .expect("the memory size was validated");
+        mem.set_ivt(self.ivt);
+        mem.set_strictness(self.strictness);
+        for r in self.regions.iter() {
+            mem.add_region(&r.name, r.range.clone(), r.perms);
+        }
+        if let Some(len) = self.program {
+            let stack = self.stack_range();
+            mem.add_region("code", 0..len, Perms::CODE);
+            mem.add_region("stack", stack.clone(), Perms::DATA | Perms::STACK);
+            mem.add_region("ivt", self.ivt..self.ivt + IVT_SIZE, Perms::DATA);
+            mem.add_region("data", len..stack.start, Perms::DATA);
+        }
+
+        let mut cpu = Cpu::default();
+        cpu.seg_write(SegReg::SS, self.stack_segment);
+        cpu.set_stack(self.stack_top, self.stack_size);
+        cpu.set_overflow_policy(self.overflow_policy);
+        cpu.set_timing(self.timing);
+
+        Ok(Machine { cpu, mem })
+    }
+}
+
+#[cfg(test)]
+mod machine_tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let Machine { cpu, mem } = MachineBuilder::new().build().unwrap();
+
+        assert_eq!(mem.len(), MEM_SIZE);
+        assert_eq!(mem.mode(), AddressMode::Bits20);
+        assert_eq!(mem.allocated(), MEM_SIZE);
+        assert_eq!(mem.ivt(), IVT_START);
+        assert_eq!(cpu.sp(), STACK_TOP);
+        assert_eq!(cpu.stack_size(), STACK_SIZE);
+        assert_eq!(cpu.overflow_policy(), OverflowPolicy::Zero);
+    }
+
+    #[test]
+    fn last_word() {
+        let Machine { mut mem, .. } = MachineBuilder::new().build().unwrap();
+        assert_eq!(
+            mem.read_16(0xffff),
+            Err(MemFault {
+                addr: 0x10000,
+                kind: MemFaultKind::OutOfBounds
+            })
+        );
+        assert!(mem.write_16(0xffff, 0x1234).is_err());
+        assert_eq!(mem.peek(0xffff), Ok(0));
+
+        let Machine { mut mem, .. } = MachineBuilder::new()
+            .address_mode(AddressMode::Bits16)
+            .build()
+            .unwrap();
+        mem.write_16(0xffff, 0x1234).unwrap();
+        assert_eq!((mem.peek(0xffff), mem.peek(0)), (Ok(0x12), Ok(0x34)));
+        assert_eq!(mem.read_16(0xffff), Ok(0x1234));
+    }
+
+    #[test]
+    fn stack_in_segment() {
+        let Machine { mut cpu, mut mem } = MachineBuilder::new()
+            .memory(0x2000)
+            .stack(0x100, 0x1000, 0x100)
+            .ivt(0)
+            .build()
+            .unwrap();
+
+        cpu.execute(Instruction::Push(Inpt::Const(7)), &mut mem)
+            .unwrap();
+        assert_eq!(cpu.sp(), 0x0ffe);
+        assert_eq!(mem.read_16(0x1ffe), Ok(7));
+    }
+
+    #[test]
+    fn program_layout() {
+        let Machine { mem, .. } = MachineBuilder::new()
+            .program(0x40)
+            .region("screen", 0x8000..0x9000, Perms::WRITE)
+            .strictness(Strictness::Fault)
+            .build()
+            .unwrap();
+
+        let regions = mem
+            .regions()
+            .iter()
+            .map(|r| (r.name.as_str(), r.range.clone(), r.perms))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            regions,
+            vec![
+                ("screen", 0x8000..0x9000, Perms::WRITE),
+                ("code", 0..0x40, Perms::CODE),
+                ("stack", 0xe000..0xf000, Perms::DATA | Perms::STACK),
+                ("ivt", 0xf000..0xf400, Perms::DATA),
+                ("data", 0x40..0xe000, Perms::DATA),
+            ]
+        );
+        assert_eq!(mem.region(0x8800).unwrap().name, "screen");
+        assert_eq!(mem.strictness(), Strictness::Fault);
+    }
+
+    #[test]
+    fn paged_address_space() {
+        let Machine { mut cpu, mut mem } = MachineBuilder::new()
+            .memory(1 << 20)
+            .backing(Backing::Paged)
+            .stack(0xf000, 0, 0x100)
+            .build()
+            .unwrap();
+        assert_eq!(mem.len(), 1 << 20);
+        assert_eq!(mem.allocated(), 0);
+
+        cpu.execute(Instruction::Push(Inpt::Const(7)), &mut mem)
+            .unwrap();
+        assert_eq!(mem.read_16(0xffffe), Ok(7));
+        assert_eq!(mem.allocated(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let err = |b: MachineBuilder| b.build().err();
+
+        assert_eq!(
+            err(MachineBuilder::new()
+                .address_mode(AddressMode::Bits16)
+                .memory(0x20000)),
+            Some(ConfigError::Memory(MemSizeError {
+                size: 0x20000,
+                mode: AddressMode::Bits16
+            }))
+        );
+        assert_eq!(
+            err(MachineBuilder::new().stack(0, 0x10, 0x20)),
+            Some(ConfigError::StackBelowSegment {
+                top: 0x10,
+                size: 0x20
+            })
+        );
+        assert_eq!(
+            err(MachineBuilder::new().memory(0x8000)),
+            Some(ConfigError::StackOutOfMemory {
+                end: 0xf000,
+                mem_size: 0x8000
+            })
+        );
+        assert_eq!(
+            err(MachineBuilder::new().stack(0, 0x100, 3)),
+            Some(ConfigError::OddStackSize(3))
+        );
+        assert_eq!(
+            err(MachineBuilder::new().ivt(0x10000)),
+            Some(ConfigError::IvtOutOfMemory {
+                start: 0x10000,
+                mem_size: 0x10000
+            })
+        );
+        assert_eq!(
+            err(MachineBuilder::new().ivt(0xfe00)),
+            Some(ConfigError::IvtOutOfMemory {
+                start: 0xfe00,
+                mem_size: 0x10000
+            })
+        );
+        assert_eq!(
+            err(MachineBuilder::new().ivt(0xec00)),
+            Some(ConfigError::IvtOverlapsStack {
+                start: 0xec00,
+                stack_start: 0xe000
+            })
+        );
+    }
+}
@@ -1,7 +1,45 @@
+mod asm;
+mod bus;
 mod cpu;
-use cpu::*;
+mod debugger;
+mod disasm;
+mod encoding;
+mod graphics;
+mod history;
+mod machine;
+mod pic;
+mod snapshot;
+mod syscall;
+mod timing;
+mod trace;
+
+use debugger::Debugger;
+use std::{env, fs, io, process};
+
+const USAGE: &str = "usage: cpu_sim debug <program.asm>";
 
 fn main() {
-    let mut cpu = Cpu::default();
-    cpu.execute(Instruction::Ld(-5, Dest::RegA));
+    let args = env::args().collect::<Vec<_>>();
+    let path = match args.as_slice() {
+        [_, cmd, path] if cmd == "debug" => path,
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let src = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+
+    let mut dbg = Debugger::new(&src).unwrap_or_else(|e| {
+        eprintln!("{}:{}", path, e);
+        process::exit(1);
+    });
+
+    if let Err(e) = dbg.repl(io::stdin().lock(), io::stdout()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
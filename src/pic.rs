@@ -0,0 +1,159 @@
+//! Programmable interrupt controller.
+//!
+//! Eight IRQ lines, line 0 has the highest priority. Devices raise a line
+//! through an `IrqLine` handle; the cpu acknowledges the highest priority
+//! pending line that isn't masked before fetching the next instruction, if
+//! interrupts are enabled.
+
+#![allow(dead_code)]
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+pub const LINES: u8 = 8;
+
+/// Vector of IRQ 0 by default, like on the IBM PC.
+pub const DEFAULT_BASE: u8 = 8;
+
+/// Handle a device keeps to raise its IRQ line.
+#[derive(Clone, Debug)]
+pub struct IrqLine {
+    line: u8,
+    pending: Rc<Cell<u8>>,
+}
+
+impl IrqLine {
+    pub fn line(&self) -> u8 {
+        self.line
+    }
+
+    pub fn raise(&self) {
+        self.pending.set(self.pending.get() | 1 << self.line);
+    }
+}
+
+#[derive(Debug)]
+pub struct Pic {
+    pending: Rc<Cell<u8>>,
+    mask: u8,
+    base: u8,
+}
+
+impl Default for Pic {
+    fn default() -> Self {
+        Pic::new(DEFAULT_BASE)
+    }
+}
+
+impl Pic {
+    /// IRQ `n` is delivered as interrupt vector `base + n`.
+    pub fn new(base: u8) -> Self {
+        assert!(base.checked_add(LINES - 1).is_some());
+
+        Pic {
+            pending: Rc::new(Cell::new(0)),
+            mask: 0,
+            base,
+        }
+    }
+
+    pub fn base(&self) -> u8 {
+        self.base
+    }
+
+    pub fn line(&self, line: u8) -> IrqLine {
+        assert!(line < LINES);
+
+        IrqLine {
+            line,
+            pending: Rc::clone(&self.pending),
+        }
+    }
+
+    pub fn raise(&self, line: u8) {
+        self.line(line).raise();
+    }
+
+    /// Masked lines stay pending until they're unmasked.
+    pub fn mask(&mut self, line: u8) {
+        assert!(line < LINES);
+        self.mask |= 1 << line;
+    }
+
+    pub fn unmask(&mut self, line: u8) {
+        assert!(line < LINES);
+        self.mask &= !(1 << line);
+    }
+
+    pub fn is_masked(&self, line: u8) -> bool {
+        self.mask & 1 << line != 0
+    }
+
+    /// Bit `n` is set if line `n` is masked.
+    pub fn mask_bits(&self) -> u8 {
+        self.mask
+    }
+
+    /// Replaces the pending and masked lines, e.g. when restoring a
+    /// snapshot. Devices keep their lines.
+    pub fn restore(&mut self, pending: u8, mask: u8) {
+        self.pending.set(pending);
+        self.mask = mask;
+    }
+
+    /// Bit `n` is set if line `n` was raised and not acknowledged yet.
+    pub fn pending(&self) -> u8 {
+        self.pending.get()
+    }
+
+    /// The highest priority line that can be delivered.
+    pub fn next(&self) -> Option<u8> {
+        let ready = self.pending.get() & !self.mask;
+        (ready != 0).then(|| ready.trailing_zeros() as u8)
+    }
+
+    /// Clears the highest priority line that can be delivered and returns
+    /// its interrupt vector.
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let line = self.next()?;
+        self.pending.set(self.pending.get() & !(1 << line));
+        Some(self.base + line)
+    }
+}
+
+#[cfg(test)]
+mod pic_tests {
+    use super::*;
+
+    #[test]
+    fn priority() {
+        let mut pic = Pic::default();
+        let timer = pic.line(0);
+        let keyboard = pic.line(1);
+
+        keyboard.raise();
+        timer.raise();
+        pic.raise(5);
+        assert_eq!(pic.pending(), 0b0010_0011);
+
+        assert_eq!(pic.acknowledge(), Some(8));
+        assert_eq!(pic.acknowledge(), Some(9));
+        assert_eq!(pic.acknowledge(), Some(13));
+        assert_eq!(pic.acknowledge(), None);
+    }
+
+    #[test]
+    fn mask() {
+        let mut pic = Pic::new(0x20);
+        pic.mask(0);
+        pic.raise(0);
+        pic.raise(3);
+
+        assert!(pic.is_masked(0));
+        assert_eq!(pic.acknowledge(), Some(0x23));
+        assert_eq!(pic.acknowledge(), None);
+
+        pic.unmask(0);
+        assert_eq!(pic.acknowledge(), Some(0x20));
+    }
+}
@@ -0,0 +1,564 @@
+//! Machine snapshots.
+//!
+//! A snapshot holds the `CpuState`, the framebuffer and the memory with its
+//! layout, so a machine can be saved to a file and restored bit for bit. The
+//! format is written by hand, all numbers are big-endian:
+//!
+//! ```text
+//! magic "CPUS", version u16
+//! cpu       a b c d flags ip sp cs ds ss es (u16 each)
+//!           seg override u8 (0xff for none), stack top u16, stack size u16,
+//!           halted u8, exit status u8 (0 or 1) then i16, cycles u64,
+//!           overflow policy u8, pending IRQs u8, masked IRQs u8
+//! screen    present u8, then width u16, height u16, palette length u16,
+//!           palette (3 bytes per color), one byte per pixel
+//! memory    address bits u8, backing u8, size u32, ivt u32, strictness u8
+//! regions   count u16, then for each: name length u8, name, start u32,
+//!           end u32, permissions u8
+//! pages     count u32, then for each: page number u32, the page's bytes
+//! ```
+//!
+//! Only pages holding a non-zero byte are stored, the last page of memory
+//! may be shorter than `PAGE_SIZE`.
+
+#![allow(dead_code)]
+
+use crate::cpu::*;
+use crate::encoding;
+use crate::graphics::Framebuffer;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+pub const MAGIC: [u8; 4] = *b"CPUS";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// Not a snapshot.
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// The snapshot ends in the middle of a field.
+    Truncated,
+    /// A field holds a value that can't be restored.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::BadMagic => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Invalid(field) => write!(f, "invalid {} in snapshot", field),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: usize) {
+        self.0.extend_from_slice(&(v as u32).to_be_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.0.extend_from_slice(v);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if n > self.bytes.len() {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn i16(&mut self) -> Result<i16, SnapshotError> {
+        Ok(self.u16()? as i16)
+    }
+
+    fn u32(&mut self) -> Result<usize, SnapshotError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_be_bytes(b))
+    }
+
+    fn bool(&mut self, field: &'static str) -> Result<bool, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Invalid(field)),
+        }
+    }
+}
+
+const POLICIES: [OverflowPolicy; 4] = [
+    OverflowPolicy::Wrap,
+    OverflowPolicy::Saturate,
+    OverflowPolicy::Zero,
+    OverflowPolicy::Trap,
+];
+const STRICTNESS: [Strictness; 3] = [Strictness::Off, Strictness::Warn, Strictness::Fault];
+const BACKINGS: [Backing; 2] = [Backing::Dense, Backing::Paged];
+
+fn code<T: PartialEq>(table: &[T], v: T) -> u8 {
+    table.iter().position(|t| *t == v).unwrap() as u8
+}
+
+fn from_code<T: Copy>(table: &[T], code: u8, field: &'static str) -> Result<T, SnapshotError> {
+    table
+        .get(code as usize)
+        .copied()
+        .ok_or(SnapshotError::Invalid(field))
+}
+
+fn write_cpu(w: &mut Writer, cpu: &Cpu) {
+    let s = cpu.state();
+    for r in [s.a, s.b, s.c, s.d] {
+        w.u16(r as u16);
+    }
+    for r in [s.flags, s.ip, s.sp, s.cs, s.ds, s.ss, s.es] {
+        w.u16(r);
+    }
+    w.u8(s.seg_override.map_or(0xff, encoding::seg_code));
+    w.u16(s.stack_top);
+    w.u16(s.stack_size);
+    w.u8(s.halted as u8);
+    w.u8(s.exit_status.is_some() as u8);
+    w.u16(s.exit_status.unwrap_or(0) as u16);
+    w.u64(s.cycles);
+    w.u8(code(&POLICIES, s.overflow_policy));
+    w.u8(s.irq_pending);
+    w.u8(s.irq_mask);
+
+    match cpu.framebuffer() {
+        None => w.u8(0),
+        Some(fb) => {
+            w.u8(1);
+            w.u16(fb.width());
+            w.u16(fb.height());
+            w.u16(fb.palette().len() as u16);
+            for rgb in fb.palette() {
+                w.bytes(rgb);
+            }
+            for y in 0..fb.height() {
+                for x in 0..fb.width() {
+                    w.u8(fb.pixel(x, y).unwrap());
+                }
+            }
+        }
+    }
+}
+
+fn read_cpu(r: &mut Reader) -> Result<(CpuState, Option<Framebuffer>), SnapshotError> {
+    let mut s = CpuState {
+        a: r.i16()?,
+        b: r.i16()?,
+        c: r.i16()?,
+        d: r.i16()?,
+        flags: r.u16()?,
+        ip: r.u16()?,
+        sp: r.u16()?,
+        cs: r.u16()?,
+        ds: r.u16()?,
+        ss: r.u16()?,
+        es: r.u16()?,
+        ..CpuState::default()
+    };
+    s.seg_override = match r.u8()? {
+        0xff => None,
+        code => Some(encoding::seg_from_code(code).ok_or(SnapshotError::Invalid("segment"))?),
+    };
+    s.stack_top = r.u16()?;
+    s.stack_size = r.u16()?;
+    s.halted = r.bool("halted flag")?;
+    let exited = r.bool("exit status")?;
+    let status = r.i16()?;
+    s.exit_status = exited.then_some(status);
+    s.cycles = r.u64()?;
+    s.overflow_policy = from_code(&POLICIES, r.u8()?, "overflow policy")?;
+    s.irq_pending = r.u8()?;
+    s.irq_mask = r.u8()?;
+
+    if !r.bool("screen")? {
+        return Ok((s, None));
+    }
+
+    let (width, height) = (r.u16()?, r.u16()?);
+    let colors = r.u16()? as usize;
+    if width > 256 || height > 256 || colors == 0 || colors > 256 {
+        return Err(SnapshotError::Invalid("screen"));
+    }
+    let palette = r
+        .bytes(3 * colors)?
+        .chunks(3)
+        .map(|c| [c[0], c[1], c[2]])
+        .collect();
+    let mut fb = Framebuffer::new(width, height, palette);
+    let pixels = r.bytes(width as usize * height as usize)?;
+    for (i, &color) in pixels.iter().enumerate() {
+        if color as usize >= colors {
+            return Err(SnapshotError::Invalid("screen"));
+        }
+        let (x, y) = (i % width as usize, i / width as usize);
+        fb.set_pixel(x as u16, y as u16, color);
+    }
+
+    Ok((s, Some(fb)))
+}
+
+fn write_mem(w: &mut Writer, mem: &Mem) -> io::Result<()> {
+    w.u8(mem.mode().bits() as u8);
+    w.u8(code(&BACKINGS, mem.backing()));
+    w.u32(mem.len());
+    w.u32(mem.ivt());
+    w.u8(code(&STRICTNESS, mem.strictness()));
+
+    let regions = u16::try_from(mem.regions().len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a snapshot holds at most 65535 memory regions",
+        )
+    })?;
+    w.u16(regions);
+    for region in mem.regions() {
+        // names are cut to 255 bytes, on a char boundary so they still read
+        let mut len = region.name.len().min(255);
+        while !region.name.is_char_boundary(len) {
+            len -= 1;
+        }
+        w.u8(len as u8);
+        w.bytes(&region.name.as_bytes()[..len]);
+        w.u32(region.range.start);
+        w.u32(region.range.end);
+        w.u8(region.perms.bits());
+    }
+
+    let pages = (0..mem.len())
+        .step_by(PAGE_SIZE)
+        .map(|start| {
+            let end = (start + PAGE_SIZE).min(mem.len());
+            let bytes = (start..end)
+                .map(|a| mem.peek(a).unwrap())
+                .collect::<Vec<_>>();
+            (start / PAGE_SIZE, bytes)
+        })
+        .filter(|(_, bytes)| bytes.iter().any(|&b| b != 0))
+        .collect::<Vec<_>>();
+
+    w.u32(pages.len());
+    for (n, bytes) in pages {
+        w.u32(n);
+        w.bytes(&bytes);
+    }
+    Ok(())
+}
+
+fn read_mem(r: &mut Reader) -> Result<Mem, SnapshotError> {
+    let mode = match r.u8()? {
+        16 => AddressMode::Bits16,
+        20 => AddressMode::Bits20,
+        _ => return Err(SnapshotError::Invalid("address mode")),
+    };
+    let backing = from_code(&BACKINGS, r.u8()?, "backing")?;
+    let len = r.u32()?;
+    let mut mem =
+        Mem::with_mode(len, mode, backing).map_err(|_| SnapshotError::Invalid("memory size"))?;
+    mem.set_ivt(r.u32()?);
+    mem.set_strictness(from_code(&STRICTNESS, r.u8()?, "strictness")?);
+
+    for _ in 0..r.u16()? {
+        let name_len = r.u8()? as usize;
+        let name = std::str::from_utf8(r.bytes(name_len)?)
+            .map_err(|_| SnapshotError::Invalid("region name"))?;
+        let range = r.u32()?..r.u32()?;
+        mem.add_region(name, range, Perms::from_bits(r.u8()?));
+    }
+
+    for _ in 0..r.u32()? {
+        let start = r.u32()? * PAGE_SIZE;
+        if start >= len {
+            return Err(SnapshotError::Invalid("page number"));
+        }
+        let bytes = r.bytes(PAGE_SIZE.min(len - start))?;
+        mem.load(start, bytes);
+    }
+
+    Ok(mem)
+}
+
+/// Writes a snapshot of `cpu` and `mem` to `out`. Nothing is written if
+/// `mem` has more regions than a snapshot holds.
+pub fn save<W: Write>(cpu: &Cpu, mem: &Mem, mut out: W) -> io::Result<()> {
+    let mut w = Writer::default();
+    w.bytes(&MAGIC);
+    w.u16(VERSION);
+    write_cpu(&mut w, cpu);
+    write_mem(&mut w, mem)?;
+
+    out.write_all(&w.0)?;
+    out.flush()
+}
+
+/// Reads a snapshot, puts `cpu` back in its state and returns its memory.
+/// The cpu keeps its configuration (syscalls, timing, breakpoints, ...) and
+/// is left untouched if the snapshot can't be read.
+pub fn restore<R: Read>(cpu: &mut Cpu, mut input: R) -> Result<Mem, SnapshotError> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    let mut r = Reader { bytes: &bytes };
+
+    if r.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = r.u16()?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let (state, fb) = read_cpu(&mut r)?;
+    let mem = read_mem(&mut r)?;
+    if !r.bytes.is_empty() {
+        return Err(SnapshotError::Invalid("trailing data"));
+    }
+
+    cpu.restore(&state);
+    match fb {
+        Some(fb) => cpu.attach_framebuffer(fb),
+        None => {
+            cpu.detach_framebuffer();
+        }
+    }
+
+    Ok(mem)
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+    use crate::asm;
+    use crate::machine::{Machine, MachineBuilder};
+
+    fn snapshot(cpu: &Cpu, mem: &Mem) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        save(cpu, mem, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn busy_machine() -> Machine {
+        let bytes = asm::assemble("ld 0x1234 a\nld 7 bl\npush a\ncmp bl al\nsti\nhlt").unwrap();
+        let mut m = MachineBuilder::new()
+            .program(bytes.len())
+            .backing(Backing::Paged)
+            .strictness(Strictness::Fault)
+            .overflow_policy(OverflowPolicy::Trap)
+            .build()
+            .unwrap();
+        m.mem.load(0, &bytes);
+        m.mem.write(0x8000, 0x55).unwrap();
+
+        let mut fb = Framebuffer::new(4, 3, vec![[0, 0, 0], [1, 2, 3]]);
+        fb.set_pixel(2, 1, 1);
+        m.cpu.attach_framebuffer(fb);
+        m.cpu.pic_mut().mask(3);
+        m.cpu.pic().raise(5);
+        m.cpu.run(&mut m.mem, 5);
+        m
+    }
+
+    #[test]
+    fn round_trip() {
+        let Machine { cpu, mem } = busy_machine();
+        let bytes = snapshot(&cpu, &mem);
+
+        let mut restored = Cpu::default();
+        let restored_mem = restore(&mut restored, &bytes[..]).unwrap();
+
+        assert_eq!(restored.state(), cpu.state());
+        assert_eq!(restored.state().irq_mask, 0b1000);
+        assert_eq!(restored.state().irq_pending, 0b10_0000);
+        let fb = restored.framebuffer().unwrap();
+        assert_eq!((fb.width(), fb.height()), (4, 3));
+        assert_eq!(fb.palette(), &[[0, 0, 0], [1, 2, 3]]);
+        assert_eq!(fb.pixel(2, 1), Some(1));
+
+        assert_eq!(restored_mem.len(), mem.len());
+        assert_eq!(restored_mem.mode(), mem.mode());
+        assert_eq!(restored_mem.backing(), Backing::Paged);
+        assert_eq!(restored_mem.allocated(), mem.allocated());
+        assert_eq!(restored_mem.ivt(), mem.ivt());
+        assert_eq!(restored_mem.strictness(), Strictness::Fault);
+        assert_eq!(restored_mem.regions(), mem.regions());
+        assert!((0..mem.len()).all(|a| restored_mem.peek(a) == mem.peek(a)));
+
+        assert_eq!(snapshot(&restored, &restored_mem), bytes);
+    }
+
+    #[test]
+    fn resumes_where_it_stopped() {
+        let bytes = asm::assemble("ld 1 a\nsum a b\nsum a b\nsum a b\nhlt").unwrap();
+        let Machine { mut cpu, mut mem } = MachineBuilder::new().build().unwrap();
+        mem.load(0, &bytes);
+        cpu.run(&mut mem, 2);
+        let snap = snapshot(&cpu, &mem);
+
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+
+        let mut resumed = Cpu::default();
+        let mut resumed_mem = restore(&mut resumed, &snap[..]).unwrap();
+        assert_eq!(resumed.reg_read(Reg::B), 1);
+        assert_eq!(resumed.run(&mut resumed_mem, 100), StopReason::Halt);
+        assert_eq!(resumed.state(), cpu.state());
+    }
+
+    #[test]
+    fn header() {
+        let Machine { cpu, mem } = MachineBuilder::new()
+            .memory(0x800)
+            .stack(0, 0x800, 0x10)
+            .ivt(0)
+            .build()
+            .unwrap();
+        let bytes = snapshot(&cpu, &mem);
+        assert_eq!(&bytes[..6], b"CPUS\0\x01");
+        // no page holds anything
+        assert_eq!(&bytes[bytes.len() - 4..], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let Machine { cpu, mem } = busy_machine();
+        let bytes = snapshot(&cpu, &mem);
+        let err = |bytes: &[u8]| restore(&mut Cpu::default(), bytes).err().unwrap();
+
+        assert!(matches!(err(b"ELF\0\0\x01"), SnapshotError::BadMagic));
+
+        let mut newer = bytes.clone();
+        newer[5] = 2;
+        assert!(matches!(err(&newer), SnapshotError::UnsupportedVersion(2)));
+
+        assert!(matches!(
+            err(&bytes[..bytes.len() - 1]),
+            SnapshotError::Truncated
+        ));
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(matches!(err(&longer), SnapshotError::Invalid(_)));
+
+        // the overflow policy follows the 22 bytes of registers and the
+        // segment override, stack, halted and exit status fields
+        let mut policy = bytes.clone();
+        policy[6 + 22 + 1 + 4 + 1 + 3 + 8] = 9;
+        assert_eq!(
+            err(&policy).to_string(),
+            "invalid overflow policy in snapshot"
+        );
+    }
+
+    #[test]
+    fn restoring_without_screen() {
+        let Machine { cpu, mem } = MachineBuilder::new().build().unwrap();
+        let bytes = snapshot(&cpu, &mem);
+
+        let Machine { mut cpu, .. } = busy_machine();
+        restore(&mut cpu, &bytes[..]).unwrap();
+        assert!(cpu.framebuffer().is_none());
+    }
+
+    #[test]
+    fn pixel_outside_palette() {
+        let Machine { cpu, mem } = busy_machine();
+        let mut bytes = snapshot(&cpu, &mem);
+
+        // the 4x3 pixels follow the two colors of the palette
+        let palette = [0, 0, 0, 1, 2, 3];
+        let at = bytes.windows(6).position(|w| w == palette).unwrap() + 6;
+        assert_eq!(bytes[at + 6], 1);
+        bytes[at + 6] = 2;
+        assert!(matches!(
+            restore(&mut Cpu::default(), &bytes[..]),
+            Err(SnapshotError::Invalid("screen"))
+        ));
+    }
+
+    #[test]
+    fn long_region_name() {
+        let Machine { cpu, mut mem } = MachineBuilder::new().build().unwrap();
+        // 127 two byte chars then a three byte one straddling byte 255
+        let name = "é".repeat(127) + "€";
+        mem.add_region(&name, 0x100..0x200, Perms::DATA);
+
+        let restored = restore(&mut Cpu::default(), &snapshot(&cpu, &mem)[..]).unwrap();
+        let region = restored.regions().last().unwrap();
+        assert_eq!(region.name, "é".repeat(127));
+        assert_eq!(region.range, 0x100..0x200);
+    }
+
+    #[test]
+    fn too_many_regions() {
+        let Machine { cpu, mut mem } = MachineBuilder::new().build().unwrap();
+        for _ in 0..=u16::MAX as usize {
+            mem.add_region("r", 0..1, Perms::DATA);
+        }
+        let mut bytes = Vec::new();
+        let err = save(&cpu, &mem, &mut bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn failed_restore_keeps_cpu() {
+        let mut cpu = Cpu::default();
+        cpu.reg_write(Reg::A, 5);
+        assert!(restore(&mut cpu, &b"CPUS\0\x01\0"[..]).is_err());
+        assert_eq!(cpu.reg_read(Reg::A), 5);
+    }
+}
@@ -0,0 +1,192 @@
+//! Host side of the `syscall` instruction.
+//!
+//! The syscall number goes in `A` and its arguments in `B`, `C` and `D`.
+//! Syscalls that produce a value leave it in `A`.
+
+#![allow(dead_code)]
+
+use crate::bus::Bus;
+use crate::cpu::*;
+use std::io::{self, Read, Write};
+
+pub const EXIT: u16 = 0;
+pub const PRINT_CHAR: u16 = 1;
+pub const PRINT_INT: u16 = 2;
+pub const READ_CHAR: u16 = 3;
+
+pub trait SyscallHandler {
+    fn call(&mut self, cpu: &mut Cpu, _mem: &mut dyn Bus) -> Result<(), Fault>;
+}
+
+/// Stops the machine with the status in `B`.
+pub struct Exit;
+
+impl SyscallHandler for Exit {
+    fn call(&mut self, cpu: &mut Cpu, _mem: &mut dyn Bus) -> Result<(), Fault> {
+        cpu.exit(cpu.reg_read(Reg::B));
+        Ok(())
+    }
+}
+
+/// Writes the low byte of `B` as a character.
+pub struct PrintChar<W: Write>(pub W);
+
+impl<W: Write> SyscallHandler for PrintChar<W> {
+    fn call(&mut self, cpu: &mut Cpu, _mem: &mut dyn Bus) -> Result<(), Fault> {
+        let c = cpu.reg_read(Reg::B) as u8;
+        self.0
+            .write_all(&[c])
+            .and_then(|_| self.0.flush())
+            .map_err(|_| Fault::SyscallFailed(PRINT_CHAR))
+    }
+}
+
+/// Writes `B` as a signed decimal number.
+pub struct PrintInt<W: Write>(pub W);
+
+impl<W: Write> SyscallHandler for PrintInt<W> {
+    fn call(&mut self, cpu: &mut Cpu, _mem: &mut dyn Bus) -> Result<(), Fault> {
+        write!(self.0, "{}", cpu.reg_read(Reg::B))
+            .and_then(|_| self.0.flush())
+            .map_err(|_| Fault::SyscallFailed(PRINT_INT))
+    }
+}
+
+/// Reads a single byte into `A`, or -1 at the end of the input.
+pub struct ReadChar<R: Read>(pub R);
+
+impl<R: Read> SyscallHandler for ReadChar<R> {
+    fn call(&mut self, cpu: &mut Cpu, _mem: &mut dyn Bus) -> Result<(), Fault> {
+        let mut buf = [0];
+        let val = match self.0.read(&mut buf) {
+            Ok(0) => -1,
+            Ok(_) => buf[0] as i16,
+            Err(_) => return Err(Fault::SyscallFailed(READ_CHAR)),
+        };
+
+        cpu.reg_write(Reg::A, val);
+        Ok(())
+    }
+}
+
+/// Registers the built-in syscalls, connected to the process' stdin/stdout.
+pub fn install_std(cpu: &mut Cpu) {
+    cpu.register_syscall(EXIT, Box::new(Exit));
+    cpu.register_syscall(PRINT_CHAR, Box::new(PrintChar(io::stdout())));
+    cpu.register_syscall(PRINT_INT, Box::new(PrintInt(io::stdout())));
+    cpu.register_syscall(READ_CHAR, Box::new(ReadChar(io::stdin())));
+}
+
+#[cfg(test)]
+mod syscall_tests {
+    use super::*;
+    use crate::asm;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn machine(src: &str, input: &'static [u8]) -> (Cpu, Mem, Output) {
+        let bytes = asm::assemble(src).unwrap();
+        let mut mem = Mem::new(bytes.len());
+        mem.load(0, &bytes);
+
+        let out = Output::default();
+        let mut cpu = Cpu::default();
+        cpu.register_syscall(EXIT, Box::new(Exit));
+        cpu.register_syscall(PRINT_CHAR, Box::new(PrintChar(out.clone())));
+        cpu.register_syscall(PRINT_INT, Box::new(PrintInt(out.clone())));
+        cpu.register_syscall(READ_CHAR, Box::new(ReadChar(input)));
+
+        (cpu, mem, out)
+    }
+
+    #[test]
+    fn print_and_exit() {
+        let (mut cpu, mut mem, out) = machine(
+            "
+            ld 1 a
+            ld 104 b
+            syscall
+            ld 105 b
+            syscall
+            ld 2 a
+            ld -42 b
+            syscall
+            ld 0 a
+            ld 3 b
+            syscall
+            hlt
+            ",
+            b"",
+        );
+
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Exit(3));
+        assert_eq!(out.0.borrow().as_slice(), b"hi-42");
+        assert!(cpu.halted());
+    }
+
+    #[test]
+    fn read_char() {
+        let (mut cpu, mut mem, _) = machine(
+            "
+            ld 3 a
+            syscall
+            ld a c
+            ld 3 a
+            syscall
+            ld a d
+            ld 3 a
+            syscall
+            hlt
+            ",
+            b"ok",
+        );
+
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+        assert_eq!(cpu.reg_read(Reg::C), b'o' as i16);
+        assert_eq!(cpu.reg_read(Reg::D), b'k' as i16);
+        assert_eq!(cpu.reg_read(Reg::A), -1);
+    }
+
+    #[test]
+    fn unknown_syscall() {
+        let (mut cpu, mut mem, _) = machine("ld 99 a\nsyscall", b"");
+
+        assert_eq!(
+            cpu.run(&mut mem, 100),
+            StopReason::Fault(Fault::UnknownSyscall(99))
+        );
+        assert_eq!(cpu.ip(), 6);
+    }
+
+    #[test]
+    fn custom_handler() {
+        struct Double;
+
+        impl SyscallHandler for Double {
+            fn call(&mut self, cpu: &mut Cpu, _mem: &mut dyn Bus) -> Result<(), Fault> {
+                let v = cpu.reg_read(Reg::B) * 2;
+                cpu.reg_write(Reg::A, v);
+                Ok(())
+            }
+        }
+
+        let (mut cpu, mut mem, _) = machine("ld 10 a\nld 21 b\nsyscall\nhlt", b"");
+        cpu.register_syscall(10, Box::new(Double));
+
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+        assert_eq!(cpu.reg_read(Reg::A), 42);
+    }
+}
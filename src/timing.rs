@@ -0,0 +1,254 @@
+//! Cycle costs and scheduled events.
+//!
+//! The default costs are taken from the 8088 instruction it's closest to,
+//! memory operands pay the extra cost of the effective address calculation
+//! and of moving a word over the 8-bit bus.
+
+#![allow(dead_code)]
+
+use crate::bus::Bus;
+use crate::cpu::*;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CycleTable {
+    costs: HashMap<&'static str, u64>,
+    /// Extra cost of each `[addr]` operand.
+    pub memory_operand: u64,
+    /// Extra cost of a conditional jump when it's taken.
+    pub branch_taken: u64,
+    /// Cost of entering an interrupt handler.
+    pub interrupt: u64,
+}
+
+/// 8088 costs, 16-bit operands, register forms.
+const COSTS_8088: [(&str, u64); 38] = [
+    ("ld", 4),       // mov reg, imm
+    ("sum", 3),      // add reg, reg
+    ("sub", 3),      // sub reg, reg
+    ("mul", 118),    // mul reg16
+    ("div", 144),    // div reg16
+    ("and", 3),      // and reg, reg
+    ("or", 3),       // or reg, reg
+    ("not", 3),      // not reg
+    ("xor", 3),      // xor reg, reg
+    ("shr", 8),      // shr reg, cl
+    ("shl", 8),      // shl reg, cl
+    ("sext", 2),     // cbw
+    ("zext", 2),     // no 8088 equivalent, like cbw
+    ("cmp", 3),      // cmp reg, reg
+    ("jmp", 15),     // jmp near
+    ("jeq", 4),      // je, not taken
+    ("jne", 4),      // jne, not taken
+    ("jgt", 4),      // jg, not taken
+    ("jlt", 4),      // jl, not taken
+    ("push", 15),    // push reg
+    ("pop", 12),     // pop reg
+    ("call", 23),    // call near
+    ("ret", 20),     // ret near
+    ("hlt", 2),      // hlt
+    ("syscall", 71), // int n
+    ("cli", 2),      // cli
+    ("sti", 2),      // sti
+    ("iret", 44),    // iret
+    ("pushf", 14),   // pushf
+    ("popf", 12),    // popf
+    ("seg", 2),      // segment override prefix
+    ("jmpf", 15),    // jmp far
+    ("callf", 36),   // call far
+    ("retf", 34),    // ret far
+    ("pxl", 10),     // no 8088 equivalent, a couple of memory writes
+    ("rect", 40),
+    ("line", 40),
+    ("cls", 40),
+];
+
+impl Default for CycleTable {
+    fn default() -> Self {
+        CycleTable {
+            costs: COSTS_8088.iter().copied().collect(),
+            memory_operand: 10,
+            branch_taken: 12,
+            interrupt: 61,
+        }
+    }
+}
+
+impl CycleTable {
+    /// Sets the base cost of the instructions with this mnemonic.
+    pub fn set(&mut self, mnemonic: &'static str, cycles: u64) {
+        self.costs.insert(mnemonic, cycles);
+    }
+
+    pub fn base(&self, mnemonic: &str) -> u64 {
+        self.costs.get(mnemonic).copied().unwrap_or(0)
+    }
+
+    /// Cost of `instr`, `taken` tells whether it jumped.
+    pub fn cost(&self, instr: &Instruction, taken: bool) -> u64 {
+        let mut cycles = self.base(instr.mnemonic());
+
+        if let Instruction::Ld(val, dest) = instr {
+            if let GenerousInpt::Memory(_) = val {
+                cycles += self.memory_operand;
+            }
+            if let Dest::Memory(_) = dest {
+                cycles += self.memory_operand;
+            }
+        }
+
+        match instr {
+            Instruction::Jeq(_)
+            | Instruction::Jne(_)
+            | Instruction::Jgt(_)
+            | Instruction::Jlt(_)
+                if taken =>
+            {
+                cycles += self.branch_taken
+            }
+            _ => {}
+        }
+
+        cycles
+    }
+}
+
+/// Something that happens at a given cycle, e.g. a device finishing its work.
+pub trait Event {
+    fn fire(self: Box<Self>, cpu: &mut Cpu, mem: &mut dyn Bus);
+}
+
+impl<F: FnOnce(&mut Cpu, &mut dyn Bus)> Event for F {
+    fn fire(self: Box<Self>, cpu: &mut Cpu, mem: &mut dyn Bus) {
+        self(cpu, mem)
+    }
+}
+
+/// Pending events, ordered by cycle. Events due at the same cycle fire in the
+/// order they were scheduled.
+#[derive(Default)]
+pub struct Scheduler {
+    events: Vec<(u64, Box<dyn Event>)>,
+}
+
+impl Scheduler {
+    pub fn schedule(&mut self, at: u64, event: Box<dyn Event>) {
+        let i = self.events.partition_point(|(t, _)| *t <= at);
+        self.events.insert(i, (at, event));
+    }
+
+    /// Removes and returns the events due at `now`.
+    pub fn due(&mut self, now: u64) -> Vec<Box<dyn Event>> {
+        let n = self.events.partition_point(|(t, _)| *t <= now);
+        self.events.drain(..n).map(|(_, e)| e).collect()
+    }
+
+    pub fn next(&self) -> Option<u64> {
+        self.events.first().map(|(t, _)| *t)
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod timing_tests {
+    use super::*;
+    use crate::asm;
+
+    fn load(src: &str) -> (Cpu, Mem) {
+        let bytes = asm::assemble(src).unwrap();
+        let mut mem = Mem::new(0x100);
+        mem.load(0, &bytes);
+
+        let mut cpu = Cpu::default();
+        cpu.set_stack(0xc0, 0x40);
+        (cpu, mem)
+    }
+
+    #[test]
+    fn default_costs() {
+        let t = CycleTable::default();
+        let mem = GenerousInpt::Memory(0x10);
+        let reg = Dest::Register(Reg::A);
+
+        assert_eq!(t.cost(&Instruction::Sum(Reg::A, Reg::B), false), 3);
+        assert_eq!(t.cost(&Instruction::Ld(mem, reg), false), 14);
+        assert_eq!(t.cost(&Instruction::Ld(mem, Dest::Memory(0x20)), false), 24);
+        assert_eq!(t.cost(&Instruction::Jne(Inpt::Const(0)), false), 4);
+        assert_eq!(t.cost(&Instruction::Jne(Inpt::Const(0)), true), 16);
+        assert_eq!(t.cost(&Instruction::Jmp(Inpt::Const(0)), true), 15);
+    }
+
+    #[test]
+    fn counts_cycles() {
+        let (mut cpu, mut mem) = load(
+            "
+            ld 2 a
+            ld 1 b
+        loop:
+            sum b c
+            cmp c a
+            jne loop
+            hlt
+            ",
+        );
+        let mut table = CycleTable::default();
+        table.set("sum", 5);
+        cpu.set_timing(table);
+
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+        // 2 * ld, 2 * (sum, cmp), jne taken, jne not taken, hlt
+        assert_eq!(cpu.cycles(), 2 * 4 + 2 * (5 + 3) + 16 + 4 + 2);
+    }
+
+    #[test]
+    fn run_for_cycles() {
+        let (mut cpu, mut mem) = load("loop:\nsum a b\njmp loop");
+
+        // the instruction that starts before the budget runs out completes
+        assert_eq!(cpu.run_cycles(&mut mem, 40), StopReason::Limit);
+        assert_eq!(cpu.cycles(), 54);
+        assert_eq!(cpu.run_cycles(&mut mem, 1), StopReason::Limit);
+        assert_eq!(cpu.cycles(), 57);
+    }
+
+    #[test]
+    fn scheduled_events() {
+        let (mut cpu, mut mem) = load("loop:\nsum a b\njmp loop");
+
+        cpu.schedule(
+            20,
+            Box::new(|cpu: &mut Cpu, _: &mut dyn Bus| cpu.reg_write(Reg::B, 1)),
+        );
+        cpu.schedule(5, Box::new(|cpu: &mut Cpu, _: &mut dyn Bus| cpu.exit(7)));
+        cpu.schedule(
+            1,
+            Box::new(|cpu: &mut Cpu, _: &mut dyn Bus| cpu.reg_write(Reg::C, cpu.cycles() as i16)),
+        );
+
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Exit(7));
+        assert_eq!(cpu.cycles(), 18);
+        assert_eq!(cpu.reg_read(Reg::C), 3);
+        assert_eq!(cpu.reg_read(Reg::B), 0);
+    }
+
+    #[test]
+    fn scheduler_order() {
+        let mut s = Scheduler::default();
+        let ev = || Box::new(|_: &mut Cpu, _: &mut dyn Bus| {});
+        s.schedule(10, ev());
+        s.schedule(5, ev());
+        s.schedule(10, ev());
+
+        assert_eq!(s.next(), Some(5));
+        assert_eq!(s.due(4).len(), 0);
+        assert_eq!(s.due(10).len(), 3);
+        assert!(s.is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+//! Execution tracer.
+//!
+//! When a `Tracer` is attached to the `Cpu`, every instruction that completes
+//! is recorded with the registers it changed, the bytes it wrote and the
+//! resulting flags. Instructions that fault are not recorded.
+
+#![allow(dead_code)]
+
+use crate::cpu::*;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::ops::Range;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// One aligned line per instruction, for people.
+    Text,
+    /// Tab separated `key=value` fields, one line per instruction, for tools.
+    Machine,
+}
+
+/// Registers the tracer compares before and after each instruction. `ip`
+/// is left out as it changes every time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot([u16; 9]);
+
+const SNAPSHOT_NAMES: [&str; 9] = ["a", "b", "c", "d", "sp", "cs", "ds", "ss", "es"];
+
+impl Snapshot {
+    pub fn of(cpu: &Cpu) -> Self {
+        Snapshot([
+            cpu.reg_read(Reg::A) as u16,
+            cpu.reg_read(Reg::B) as u16,
+            cpu.reg_read(Reg::C) as u16,
+            cpu.reg_read(Reg::D) as u16,
+            cpu.sp(),
+            cpu.seg_read(SegReg::CS),
+            cpu.seg_read(SegReg::DS),
+            cpu.seg_read(SegReg::SS),
+            cpu.seg_read(SegReg::ES),
+        ])
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegChange {
+    pub name: &'static str,
+    pub old: u16,
+    pub new: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub ip: u16,
+    pub instr: Instruction,
+    pub regs: Vec<RegChange>,
+    pub writes: Vec<MemWrite>,
+    pub flags: u16,
+}
+
+impl TraceEntry {
+    /// Builds the entry for `instr`, which started at `ip`, given the
+    /// registers before it ran and the cpu after.
+    pub fn new(
+        ip: u16,
+        instr: Instruction,
+        before: Snapshot,
+        cpu: &Cpu,
+        writes: Vec<MemWrite>,
+    ) -> Self {
+        let after = Snapshot::of(cpu);
+        let regs = SNAPSHOT_NAMES
+            .iter()
+            .zip(before.0.iter().zip(after.0.iter()))
+            .filter(|(_, (old, new))| old != new)
+            .map(|(&name, (&old, &new))| RegChange { name, old, new })
+            .collect();
+
+        TraceEntry {
+            ip,
+            instr,
+            regs,
+            writes,
+            flags: cpu.flags(),
+        }
+    }
+
+    pub fn format(&self, format: Format) -> String {
+        let mut line = String::new();
+        match format {
+            Format::Text => {
+                let _ = write!(line, "{:04x}  {:<24}", self.ip, self.instr.to_string());
+                for r in self.regs.iter() {
+                    let _ = write!(line, "  {}: {:#06x} -> {:#06x}", r.name, r.old, r.new);
+                }
+                for w in self.writes.iter() {
+                    let _ = write!(
+                        line,
+                        "  [{:#06x}]: {:#04x} -> {:#04x}",
+                        w.addr, w.old, w.new
+                    );
+                }
+                let _ = write!(line, "  flags: {:#06x}", self.flags);
+            }
+            Format::Machine => {
+                let regs = self
+                    .regs
+                    .iter()
+                    .map(|r| format!("{}:{:04x}:{:04x}", r.name, r.old, r.new))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let writes = self
+                    .writes
+                    .iter()
+                    .map(|w| format!("{:04x}:{:02x}:{:02x}", w.addr, w.old, w.new))
+                    .collect::<Vec<_>>()
+                    .join(",");
+
+                let _ = write!(
+                    line,
+                    "ip={:04x}\top={}\tinstr={}\tregs={}\tmem={}\tflags={:04x}",
+                    self.ip,
+                    self.instr.mnemonic(),
+                    self.instr,
+                    regs,
+                    writes,
+                    self.flags
+                );
+            }
+        }
+
+        line
+    }
+}
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: Format,
+    range: Option<Range<u16>>,
+    kinds: Option<Vec<String>>,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: Format) -> Self {
+        Tracer {
+            out,
+            format,
+            range: None,
+            kinds: None,
+            error: None,
+        }
+    }
+
+    /// Only records instructions starting in `range`.
+    pub fn with_range(mut self, range: Range<u16>) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    /// Only records instructions with one of these mnemonics.
+    pub fn with_kinds(mut self, kinds: &[&str]) -> Self {
+        self.kinds = Some(kinds.iter().map(|k| k.to_lowercase()).collect());
+        self
+    }
+
+    pub fn matches(&self, entry: &TraceEntry) -> bool {
+        let in_range = self.range.as_ref().is_none_or(|r| r.contains(&entry.ip));
+        let kind = entry.instr.mnemonic();
+        let of_kind = self
+            .kinds
+            .as_ref()
+            .is_none_or(|k| k.iter().any(|k| k == kind));
+
+        in_range && of_kind
+    }
+
+    /// Writes `entry` if it passes the filters. After the first write error
+    /// the tracer stops writing, the error is kept in `error`.
+    pub fn record(&mut self, entry: &TraceEntry) {
+        if self.error.is_some() || !self.matches(entry) {
+            return;
+        }
+
+        if let Err(e) = writeln!(self.out, "{}", entry.format(self.format)) {
+            self.error = Some(e);
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use super::*;
+    use crate::asm;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone())
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    fn traced(src: &str, tracer: impl FnOnce(Box<dyn Write>) -> Tracer) -> Output {
+        let bytes = asm::assemble(src).unwrap();
+        let mut mem = Mem::new(0x40);
+        mem.load(0, &bytes);
+
+        let out = Output::default();
+        let mut cpu = Cpu::default();
+        cpu.set_stack(0x30, 0x10);
+        cpu.attach_tracer(tracer(Box::new(out.clone())));
+        assert_eq!(cpu.run(&mut mem, 100), StopReason::Halt);
+
+        out
+    }
+
+    #[test]
+    fn text_format() {
+        let out = traced("ld 3 a\nld a [0x0020]\ncmp a a\nhlt", |w| {
+            Tracer::new(w, Format::Text)
+        });
+
+        assert_eq!(
+            out.lines(),
+            vec![
+                "0000  ld 3 a                    a: 0x0000 -> 0x0003  flags: 0x0000",
+                "0006  ld a [0x0020]             [0x0020]: 0x00 -> 0x00  [0x0021]: 0x00 -> 0x03  flags: 0x0000",
+                "000c  cmp a a                   flags: 0x0106",
+                "000f  hlt                       flags: 0x0106",
+            ]
+        );
+    }
+
+    #[test]
+    fn machine_format() {
+        let out = traced("push 0x102\npop b\nhlt", |w| {
+            Tracer::new(w, Format::Machine)
+        });
+
+        assert_eq!(
+            out.lines(),
+            vec![
+                "ip=0000\top=push\tinstr=push 258\tregs=sp:0030:002e\tmem=002e:00:01,002f:00:02\tflags=0000",
+                "ip=0004\top=pop\tinstr=pop b\tregs=b:0000:0102,sp:002e:0030\tmem=\tflags=0000",
+                "ip=0006\top=hlt\tinstr=hlt\tregs=\tmem=\tflags=0000",
+            ]
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let src = "ld 1 a\nsum a b\nsum a b\nld 0 c\nhlt";
+
+        let out = traced(src, |w| {
+            Tracer::new(w, Format::Machine).with_kinds(&["SUM"])
+        });
+        let ips = out
+            .lines()
+            .iter()
+            .map(|l| l[3..7].to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ips, vec!["0006", "0009"]);
+
+        let out = traced(src, |w| {
+            Tracer::new(w, Format::Machine).with_range(0x06..0x0c)
+        });
+        assert_eq!(out.lines().len(), 2);
+
+        let out = traced(src, |w| {
+            Tracer::new(w, Format::Machine)
+                .with_range(0x09..0x20)
+                .with_kinds(&["ld", "hlt"])
+        });
+        assert_eq!(out.lines().len(), 2);
+    }
+
+    #[test]
+    fn faulting_instruction_isnt_recorded() {
+        let bytes = asm::assemble("ld 1 a\nld a [0x0100]").unwrap();
+        let mut mem = Mem::new(0x20);
+        mem.load(0, &bytes);
+
+        let out = Output::default();
+        let mut cpu = Cpu::default();
+        cpu.attach_tracer(Tracer::new(Box::new(out.clone()), Format::Machine));
+
+        assert!(matches!(cpu.run(&mut mem, 10), StopReason::Fault(_)));
+        assert_eq!(out.lines().len(), 1);
+    }
+}